@@ -1,8 +1,8 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use ethers::providers::{Http, Provider};
 use ethers_throttle::ThrottledJsonRpcClient;
 use futures::stream::FuturesUnordered;
@@ -14,10 +14,16 @@ use telemetry_batteries::tracing::datadog::DatadogBattery;
 use telemetry_batteries::tracing::TracingShutdownHandle;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use world_tree::tree::config::ServiceConfig;
+use tracing_subscriber::Layer;
+use world_tree::tree::config::{ServiceConfig, EXAMPLE_CONFIG};
+use world_tree::tree::dry_run::index_dry_run;
+use world_tree::tree::inspect::{describe_proof, export_proofs, read_proof};
+#[cfg(feature = "metrics")]
+use world_tree::tree::metrics::MetricsRecorder;
+use world_tree::tree::replay::{read_events, replay_events};
 use world_tree::tree::service::InclusionProofService;
 use world_tree::tree::tree_manager::{BridgedTree, CanonicalTree, TreeManager};
-use world_tree::tree::WorldTree;
+use world_tree::tree::{Hash, WorldTree};
 
 /// This service syncs the state of the World Tree and spawns a server that can deliver inclusion proofs for a given identity.
 #[derive(Parser, Debug)]
@@ -27,6 +33,47 @@ struct Opts {
     /// Path to the configuration file
     #[clap(short, long)]
     config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replays recorded tree change events into a fresh tree, printing the root after each event
+    Replay {
+        /// Path to a file containing one JSON encoded tree change event per line
+        #[clap(short, long)]
+        events: PathBuf,
+        /// Depth of the tree to replay the events into
+        #[clap(short, long, default_value_t = 30)]
+        tree_depth: usize,
+    },
+    /// Prints the fully resolved configuration, including defaults, with secrets redacted
+    PrintConfig,
+    /// Writes a commented example configuration file with placeholder values
+    GenConfig {
+        /// Path to write the example configuration to
+        #[clap(short, long)]
+        path: PathBuf,
+    },
+    /// Decodes a JSON encoded inclusion proof and prints each of its branches
+    DecodeProof {
+        /// Path to a file containing the inclusion proof
+        #[clap(short, long)]
+        file: PathBuf,
+        /// Identity commitment to verify the proof against
+        #[clap(short, long)]
+        leaf: Option<Hash>,
+    },
+    /// Syncs the tree to the chain head and writes an inclusion proof for every identity commitment to a directory
+    ExportProofs {
+        /// Directory to write `proofs.jsonl` and `manifest.json` to
+        #[clap(short, long)]
+        out_dir: PathBuf,
+    },
+    /// Decodes the canonical tree events up to the chain head without building the tree, printing each root and
+    /// comparing it against the onchain root at its block
+    IndexDryRun,
 }
 
 #[tokio::main]
@@ -35,6 +82,31 @@ pub async fn main() -> eyre::Result<()> {
 
     let opts = Opts::parse();
 
+    if let Some(command) = opts.command {
+        return match command {
+            Command::Replay { events, tree_depth } => {
+                replay(&events, tree_depth)
+            }
+            Command::PrintConfig => print_config(opts.config.as_deref()),
+            Command::GenConfig { path } => {
+                fs::write(&path, EXAMPLE_CONFIG)?;
+                println!("Wrote example config to {}", path.display());
+
+                Ok(())
+            }
+            Command::DecodeProof { file, leaf } => {
+                let inclusion_proof = read_proof(&file)?;
+                print!("{}", describe_proof(&inclusion_proof, leaf));
+
+                Ok(())
+            }
+            Command::ExportProofs { out_dir } => {
+                export(opts.config.as_deref(), &out_dir).await
+            }
+            Command::IndexDryRun => dry_run(opts.config.as_deref()).await,
+        };
+    }
+
     let config = ServiceConfig::load(opts.config.as_deref())?;
 
     let _tracing_shutdown_handle = if let Some(telemetry) = &config.telemetry {
@@ -58,10 +130,18 @@ pub async fn main() -> eyre::Result<()> {
 
         tracing_shutdown_handle
     } else {
-        tracing_subscriber::registry()
-            .with(tracing_subscriber::fmt::layer().pretty().compact())
-            .with(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
+        let registry = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .pretty()
+                .compact()
+                .with_filter(tracing_subscriber::EnvFilter::from_default_env()),
+        );
+
+        // The console layer records the tokio runtime events regardless of `RUST_LOG`
+        #[cfg(feature = "tokio-console")]
+        let registry = registry.with(console_subscriber::spawn());
+
+        registry.init();
 
         TracingShutdownHandle
     };
@@ -70,7 +150,7 @@ pub async fn main() -> eyre::Result<()> {
 
     let world_tree = initialize_world_tree(&config).await?;
 
-    let handles = InclusionProofService::new(world_tree)
+    let handles = InclusionProofService::new(world_tree, config.server.clone())
         .serve(config.socket_address)
         .await?;
 
@@ -86,32 +166,14 @@ pub async fn main() -> eyre::Result<()> {
 async fn initialize_world_tree(
     config: &ServiceConfig,
 ) -> eyre::Result<Arc<WorldTree<Provider<ThrottledJsonRpcClient<Http>>>>> {
-    let canonical_provider_config = &config.canonical_tree.provider;
-
-    let http_provider =
-        Http::new(canonical_provider_config.rpc_endpoint.clone());
-    let throttled_provider = ThrottledJsonRpcClient::new(
-        http_provider,
-        canonical_provider_config.throttle,
-        None,
-    );
-    let canonical_middleware = Arc::new(Provider::new(throttled_provider));
-
-    let canonical_tree_config = &config.canonical_tree;
-    let canonical_tree_manager = TreeManager::<_, CanonicalTree>::new(
-        canonical_tree_config.address,
-        canonical_tree_config.window_size,
-        canonical_tree_config.creation_block,
-        canonical_middleware,
-    )
-    .await?;
+    let canonical_tree_manager =
+        initialize_canonical_tree_manager(config).await?;
 
     let mut bridged_tree_managers = vec![];
 
     for tree_config in config.bridged_trees.iter() {
         let bridged_provider_config = &tree_config.provider;
-        let http_provider =
-            Http::new(bridged_provider_config.rpc_endpoint.clone());
+        let http_provider = bridged_provider_config.http()?;
 
         let throttled_provider = ThrottledJsonRpcClient::new(
             http_provider,
@@ -123,6 +185,7 @@ async fn initialize_world_tree(
         let tree_manager = TreeManager::<_, BridgedTree>::new(
             tree_config.address,
             tree_config.window_size,
+            tree_config.max_concurrent_log_requests,
             tree_config.creation_block,
             bridged_middleware,
         )
@@ -136,10 +199,145 @@ async fn initialize_world_tree(
         fs::remove_file(&config.cache.cache_file)?;
     }
 
-    Ok(Arc::new(WorldTree::new(
-        config.tree_depth,
+    let tree_depth = canonical_tree_manager
+        .resolve_tree_depth(config.tree_depth)
+        .await?;
+    tracing::info!(tree_depth, "Resolved tree depth");
+
+    let world_tree = WorldTree::new(
+        tree_depth,
         canonical_tree_manager,
         bridged_tree_managers,
         &config.cache.cache_file,
-    )?))
+    )?;
+
+    if let Some(false_positive_rate) = config.leaf_filter_fp_rate {
+        world_tree
+            .identity_tree
+            .write()
+            .await
+            .enable_leaf_filter(false_positive_rate);
+    }
+
+    // Forward tree metrics to the statsd or Prometheus exporter when either is configured
+    #[cfg(feature = "metrics")]
+    if config.server.metrics_address.is_some()
+        || config
+            .telemetry
+            .as_ref()
+            .is_some_and(|telemetry| telemetry.metrics.is_some())
+    {
+        world_tree.identity_tree.write().await.metrics =
+            Arc::new(MetricsRecorder);
+    }
+
+    Ok(Arc::new(world_tree))
+}
+
+async fn initialize_canonical_tree_manager(
+    config: &ServiceConfig,
+) -> eyre::Result<
+    TreeManager<Provider<ThrottledJsonRpcClient<Http>>, CanonicalTree>,
+> {
+    let canonical_provider_config = &config.canonical_tree.provider;
+
+    let http_provider = canonical_provider_config.http()?;
+    let throttled_provider = ThrottledJsonRpcClient::new(
+        http_provider,
+        canonical_provider_config.throttle,
+        None,
+    );
+    let canonical_middleware = Arc::new(Provider::new(throttled_provider));
+
+    let canonical_tree_config = &config.canonical_tree;
+    let canonical_tree_manager = TreeManager::<_, CanonicalTree>::new(
+        canonical_tree_config.address,
+        canonical_tree_config.window_size,
+        canonical_tree_config.max_concurrent_log_requests,
+        canonical_tree_config.creation_block,
+        canonical_middleware,
+    )
+    .await?
+    .with_root_verification(canonical_tree_config.verify_roots);
+
+    Ok(canonical_tree_manager)
+}
+
+fn replay(events: &Path, tree_depth: usize) -> eyre::Result<()> {
+    let events = read_events(events)?;
+    let steps = replay_events(tree_depth, events)?;
+
+    for (idx, step) in steps.iter().enumerate() {
+        println!(
+            "Event {idx}: nonce = {}, root = {:?}",
+            step.expected_root.nonce, step.computed_root
+        );
+    }
+
+    if let Some((idx, step)) =
+        steps.iter().enumerate().find(|(_, step)| step.diverged())
+    {
+        eyre::bail!(
+            "Root diverged after event {idx}: expected {:?}, computed {:?}",
+            step.expected_root.hash,
+            step.computed_root
+        );
+    }
+
+    Ok(())
+}
+
+async fn export(
+    config_path: Option<&Path>,
+    out_dir: &Path,
+) -> eyre::Result<()> {
+    let config = ServiceConfig::load(config_path)?;
+
+    let world_tree = initialize_world_tree(&config).await?;
+    world_tree.sync_to_head().await?;
+
+    let manifest =
+        export_proofs(&*world_tree.identity_tree.read().await, out_dir)?;
+    println!(
+        "Exported {} proofs against root {:?} to {}",
+        manifest.num_proofs,
+        manifest.root,
+        out_dir.display()
+    );
+
+    Ok(())
+}
+
+async fn dry_run(config_path: Option<&Path>) -> eyre::Result<()> {
+    let config = ServiceConfig::load(config_path)?;
+
+    let canonical_tree_manager =
+        initialize_canonical_tree_manager(&config).await?;
+    let tree_depth = canonical_tree_manager
+        .resolve_tree_depth(config.tree_depth)
+        .await?;
+    let steps = index_dry_run(&canonical_tree_manager, tree_depth).await?;
+
+    for step in steps.iter() {
+        println!("{step}");
+    }
+
+    if let Some(step) = steps.iter().find(|step| step.diverged()) {
+        eyre::bail!(
+            "Root diverged at block {}: decoded {:?}, onchain {:?}",
+            step.block_number,
+            step.root.hash,
+            step.onchain_root.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+fn print_config(config_path: Option<&Path>) -> eyre::Result<()> {
+    let config = ServiceConfig::load(config_path)?;
+
+    println!("{}", toml::to_string_pretty(&config.redacted())?);
+
+    Ok(())
 }
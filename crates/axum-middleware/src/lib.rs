@@ -1 +1,2 @@
 pub mod logging;
+pub mod rate_limit;
@@ -0,0 +1,156 @@
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultKeyedRateLimiter, Quota};
+use tracing::warn;
+
+/// Number of requests handled between two sweeps of the per client buckets
+const RETAIN_RECENT_INTERVAL: u64 = 1024;
+
+/// Token bucket rate limiter keyed by the IP address of the client
+pub struct RateLimiter {
+    limiter: DefaultKeyedRateLimiter<IpAddr>,
+    clock: DefaultClock,
+    requests: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Initializes a new `RateLimiter` replenishing `requests_per_second` tokens per second
+    /// and allowing up to `burst` requests at once for each client
+    pub fn new(requests_per_second: NonZeroU32, burst: NonZeroU32) -> Self {
+        let quota = Quota::per_second(requests_per_second).allow_burst(burst);
+
+        Self {
+            limiter: governor::RateLimiter::keyed(quota),
+            clock: DefaultClock::default(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Drops the buckets of clients that have fully replenished their quota so that memory does not grow with
+    /// the number of distinct client addresses seen over the lifetime of the service
+    pub fn retain_recent(&self) {
+        self.limiter.retain_recent();
+        self.limiter.shrink_to_fit();
+    }
+
+    /// Returns the number of clients currently tracked by the limiter
+    pub fn len(&self) -> usize {
+        self.limiter.len()
+    }
+
+    /// Returns `true` if the limiter is not tracking any client
+    pub fn is_empty(&self) -> bool {
+        self.limiter.is_empty()
+    }
+}
+
+/// Rejects requests with `429 Too Many Requests` and a `Retry-After` header once a client exceeds its quota.
+/// Requires the server to be started with `into_make_service_with_connect_info::<SocketAddr>()`.
+pub async fn middleware<B>(
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = addr.ip();
+
+    // Sweep stale buckets every `RETAIN_RECENT_INTERVAL` requests
+    let requests = rate_limiter.requests.fetch_add(1, Ordering::Relaxed) + 1;
+    if requests % RETAIN_RECENT_INTERVAL == 0 {
+        rate_limiter.retain_recent();
+    }
+
+    match rate_limiter.limiter.check_key(&ip) {
+        Ok(()) => next.run(request).await,
+        Err(not_until) => {
+            let wait_time = not_until.wait_time_from(rate_limiter.clock.now());
+
+            // Round up so that clients never retry before a token is available
+            let retry_after =
+                wait_time.as_secs() + u64::from(wait_time.subsec_nanos() > 0);
+
+            warn!(?ip, retry_after, "Rate limit exceeded");
+
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use axum::Router;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limit_exceeded() {
+        let rate_limiter = Arc::new(RateLimiter::new(
+            NonZeroU32::new(1).unwrap(),
+            NonZeroU32::new(2).unwrap(),
+        ));
+
+        let router = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .route_layer(axum::middleware::from_fn_with_state(
+                rate_limiter,
+                middleware,
+            ));
+
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        // The burst allows the first two requests through
+        for _ in 0..2 {
+            let response = reqwest::get(&url).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .expect("Missing Retry-After header")
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(retry_after >= 1);
+    }
+
+    #[test]
+    fn test_retain_recent() {
+        let rate_limiter = RateLimiter::new(
+            NonZeroU32::new(1000).unwrap(),
+            NonZeroU32::new(1).unwrap(),
+        );
+
+        for i in 0..10 {
+            let ip = IpAddr::from([10, 0, 0, i]);
+            rate_limiter.limiter.check_key(&ip).unwrap();
+        }
+        assert_eq!(rate_limiter.len(), 10);
+
+        // Every bucket replenishes its single token within a millisecond
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        rate_limiter.retain_recent();
+        assert!(rate_limiter.is_empty());
+    }
+}
@@ -1,12 +1,19 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use ethers::providers::Middleware;
+use ethers::providers::{Middleware, MiddlewareError};
 use ethers::types::{BlockNumber, Filter, Log};
-use futures::stream::FuturesOrdered;
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 
+/// Number of consecutive rate limited log requests tolerated before `next` returns the error
+const MAX_RATE_LIMIT_RETRIES: u32 = 10;
+/// Delay before retrying once no requests are in flight, multiplied by the number of consecutive rate limited requests
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_millis(250);
+
 /// The `BlockScanner` utility tool enables allows parsing arbitrary onchain events
 #[derive(Debug)]
 pub struct BlockScanner<M: Middleware + 'static> {
@@ -14,68 +21,154 @@ pub struct BlockScanner<M: Middleware + 'static> {
     pub middleware: Arc<M>,
     /// The block from which to start parsing a given event
     pub next_block: AtomicU64,
+    /// The chain head observed by the last call to `next`
+    pub latest_block: AtomicU64,
+    /// The number of consecutive failed attempts to process the logs from `next_block`, reset once an attempt succeeds
+    pub consecutive_failures: AtomicU32,
     /// The maximum block range to parse
     window_size: u64,
+    /// The maximum number of concurrent log requests
+    max_concurrency: usize,
+    /// The current number of concurrent log requests, reduced when the provider rate limits requests
+    concurrency: AtomicUsize,
     /// Filter specifying the address and topics to match on when scanning
     filter: Filter,
-    chain_id: u64,
+    /// The chain id of the onchain data provider
+    pub chain_id: u64,
 }
 
 impl<M> BlockScanner<M>
 where
     M: Middleware + Send + Sync + Debug,
 {
-    /// Initializes a new `BlockScanner`
+    /// Initializes a new `BlockScanner` issuing up to `max_concurrency` log requests at once
     pub async fn new(
         middleware: Arc<M>,
         window_size: u64,
+        max_concurrency: usize,
         current_block: u64,
         filter: Filter,
     ) -> Result<Self, M::Error> {
         let chain_id = middleware.get_chainid().await?.as_u64();
+        let max_concurrency = max_concurrency.max(1);
+
         Ok(Self {
             middleware,
             next_block: AtomicU64::new(current_block),
+            latest_block: AtomicU64::new(current_block),
+            consecutive_failures: AtomicU32::new(0),
             window_size,
+            max_concurrency,
+            concurrency: AtomicUsize::new(max_concurrency),
             filter,
             chain_id,
         })
     }
 
     /// Retrieves events matching the specified address and topics from the last synced block to the latest block, stepping by `window_size`.
-    /// Note that the logs are unsorted and should be handled accordingly.
+    /// Windows are requested concurrently, halving the concurrency when the provider rate limits a request and raising it by one
+    /// once a full round of requests succeeds, up to `max_concurrency`. Responses are buffered so that windows are returned in block order.
+    /// Note that the logs within a window are returned as provided and should be handled accordingly.
     pub async fn next(&self) -> Result<Vec<Log>, M::Error> {
         let latest_block = self.middleware.get_block_number().await?.as_u64();
+        self.latest_block.store(latest_block, Ordering::SeqCst);
         let mut next_block = self.next_block.load(Ordering::SeqCst);
 
-        let mut tasks = FuturesOrdered::new();
+        let mut windows = VecDeque::new();
         while next_block < latest_block {
             let to_block = (next_block + self.window_size).min(latest_block);
+            windows.push_back((next_block, to_block));
+            next_block = to_block + 1;
+        }
 
-            tracing::debug!(chain_id = ?self.chain_id, from_block = ?next_block, ?to_block, "Scanning blocks");
+        let mut tasks = FuturesUnordered::new();
+        // Windows complete out of order, so buffer the logs keyed by the first block of each window
+        let mut window_logs = BTreeMap::new();
+        let mut consecutive_rate_limits = 0;
+        let mut successes = 0;
 
-            let filter = self
-                .filter
-                .clone()
-                .from_block(BlockNumber::Number(next_block.into()))
-                .to_block(BlockNumber::Number(to_block.into()));
+        while !windows.is_empty() || !tasks.is_empty() {
+            while tasks.len() < self.concurrency.load(Ordering::SeqCst) {
+                let Some((from_block, to_block)) = windows.pop_front() else {
+                    break;
+                };
 
-            let middleware = self.middleware.clone();
+                tracing::debug!(chain_id = ?self.chain_id, ?from_block, ?to_block, "Scanning blocks");
 
-            tasks.push_back(async move { middleware.get_logs(&filter).await });
+                let filter = self
+                    .filter
+                    .clone()
+                    .from_block(BlockNumber::Number(from_block.into()))
+                    .to_block(BlockNumber::Number(to_block.into()));
 
-            next_block = to_block + 1;
-        }
+                let middleware = self.middleware.clone();
+
+                tasks.push(async move {
+                    let result = middleware.get_logs(&filter).await;
+                    ((from_block, to_block), result)
+                });
+            }
+
+            let Some((window, result)) = tasks.next().await else {
+                break;
+            };
+
+            match result {
+                Ok(logs) => {
+                    consecutive_rate_limits = 0;
+                    successes += 1;
+
+                    let concurrency = self.concurrency.load(Ordering::SeqCst);
+                    if successes >= concurrency {
+                        successes = 0;
+                        self.concurrency.store(
+                            (concurrency + 1).min(self.max_concurrency),
+                            Ordering::SeqCst,
+                        );
+                    }
+
+                    window_logs.insert(window.0, logs);
+                }
+                Err(err)
+                    if is_rate_limited(&err)
+                        && consecutive_rate_limits < MAX_RATE_LIMIT_RETRIES =>
+                {
+                    consecutive_rate_limits += 1;
+                    successes = 0;
+
+                    // Halve the number of requests that were in flight when the provider started rejecting them
+                    let in_flight = tasks.len() + 1;
+                    let concurrency = self.concurrency.load(Ordering::SeqCst);
+                    self.concurrency.store(
+                        concurrency.min(in_flight / 2).max(1),
+                        Ordering::SeqCst,
+                    );
 
-        //Sort all of the results
-        let mut aggregated_logs = vec![];
+                    tracing::warn!(
+                        chain_id = ?self.chain_id,
+                        from_block = ?window.0,
+                        to_block = ?window.1,
+                        concurrency = self.concurrency.load(Ordering::SeqCst),
+                        "Log request rate limited"
+                    );
 
-        while let Some(result) = tasks.next().await {
-            let logs = result?;
+                    windows.push_front(window);
 
-            aggregated_logs.extend(logs);
+                    // Requests still in flight keep the scan moving, otherwise wait for the provider to recover
+                    if tasks.is_empty() {
+                        tokio::time::sleep(
+                            RATE_LIMIT_BACKOFF * consecutive_rate_limits,
+                        )
+                        .await;
+                    }
+                }
+                Err(err) => return Err(err),
+            }
         }
 
+        let aggregated_logs =
+            window_logs.into_values().flatten().collect::<Vec<_>>();
+
         self.next_block.store(next_block, Ordering::SeqCst);
 
         tracing::debug!(chain_id = ?self.chain_id, last_synced_block = ?next_block - 1, "Last synced block updated");
@@ -83,3 +176,133 @@ where
         Ok(aggregated_logs)
     }
 }
+
+/// Returns whether a provider error indicates that the request was rate limited
+fn is_rate_limited<E: MiddlewareError>(err: &E) -> bool {
+    // 429 mirrors the HTTP status, -32005 is returned by Infura and Alchemy when the request limit is exceeded
+    if err
+        .as_error_response()
+        .is_some_and(|response| matches!(response.code, 429 | -32005))
+    {
+        return true;
+    }
+
+    let message = err.to_string().to_lowercase();
+    message.contains("rate limit") || message.contains("too many requests")
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use ethers::providers::{MockProvider, Provider, ProviderError};
+    use ethers::types::{U256, U64};
+
+    use super::*;
+
+    /// Middleware that rejects log requests once more than `max_in_flight` are outstanding
+    #[derive(Debug)]
+    struct RateLimitedMiddleware {
+        inner: Provider<MockProvider>,
+        latest_block: u64,
+        max_in_flight: usize,
+        in_flight: AtomicUsize,
+        rate_limited: AtomicUsize,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    enum RateLimitedError {
+        #[error(transparent)]
+        Provider(#[from] ProviderError),
+        #[error("rate limit exceeded")]
+        RateLimited,
+    }
+
+    impl MiddlewareError for RateLimitedError {
+        type Inner = ProviderError;
+
+        fn from_err(src: ProviderError) -> Self {
+            Self::Provider(src)
+        }
+
+        fn as_inner(&self) -> Option<&Self::Inner> {
+            match self {
+                Self::Provider(err) => Some(err),
+                Self::RateLimited => None,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Middleware for RateLimitedMiddleware {
+        type Error = RateLimitedError;
+        type Provider = MockProvider;
+        type Inner = Provider<MockProvider>;
+
+        fn inner(&self) -> &Self::Inner {
+            &self.inner
+        }
+
+        async fn get_block_number(&self) -> Result<U64, Self::Error> {
+            Ok(self.latest_block.into())
+        }
+
+        async fn get_logs(
+            &self,
+            filter: &Filter,
+        ) -> Result<Vec<Log>, Self::Error> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            if in_flight > self.max_in_flight {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                self.rate_limited.fetch_add(1, Ordering::SeqCst);
+                return Err(RateLimitedError::RateLimited);
+            }
+
+            // Later windows respond sooner so that requests complete out of order
+            let from_block = filter.get_from_block().unwrap().as_u64();
+            let delay = 20 - (from_block / 10) % 10;
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            // Return a single log tagged with the first block of the window
+            Ok(vec![Log {
+                block_number: filter.get_from_block(),
+                ..Default::default()
+            }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_scan() -> eyre::Result<()> {
+        let (inner, mock) = Provider::mocked();
+        mock.push(U256::from(1))?;
+
+        let middleware = Arc::new(RateLimitedMiddleware {
+            inner,
+            latest_block: 1000,
+            max_in_flight: 3,
+            in_flight: AtomicUsize::new(0),
+            rate_limited: AtomicUsize::new(0),
+        });
+
+        let block_scanner =
+            BlockScanner::new(middleware.clone(), 9, 10, 0, Filter::new())
+                .await?;
+
+        let logs = block_scanner.next().await?;
+
+        // Every window is scanned exactly once and in order despite the rate limited requests
+        let from_blocks = logs
+            .iter()
+            .map(|log| log.block_number.unwrap().as_u64())
+            .collect::<Vec<_>>();
+        assert_eq!(from_blocks, (0..1000).step_by(10).collect::<Vec<_>>());
+        assert_eq!(block_scanner.next_block.load(Ordering::SeqCst), 1000);
+
+        // The scanner settles around the provider limit rather than retrying at full concurrency
+        assert!(
+            middleware.rate_limited.load(Ordering::SeqCst) < logs.len() / 2
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use ethers::providers::Middleware;
+use ethers::types::Log;
+
+use super::error::WorldTreeError;
+use super::identity_tree::{LeafUpdates, Root};
+use super::tree_manager::{
+    extract_identity_updates, CanonicalTree, TreeManager,
+};
+use super::Hash;
+use crate::abi::IWorldIDIdentityManager;
+
+/// A root produced by indexing the canonical tree events, alongside the root reported onchain at the end of its block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DryRunStep {
+    /// The block in which the root was emitted
+    pub block_number: u64,
+    /// The root decoded from the tree change
+    pub root: Root,
+    /// The latest root onchain at `block_number`, only set for the last root of each block since earlier roots in the
+    /// same block are overwritten before the block ends
+    pub onchain_root: Option<Hash>,
+}
+
+impl DryRunStep {
+    /// Returns true if the decoded root does not match the onchain root at its block
+    pub fn diverged(&self) -> bool {
+        self.onchain_root
+            .is_some_and(|onchain_root| onchain_root != self.root.hash)
+    }
+}
+
+impl fmt::Display for DryRunStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Block {}: nonce = {}, root = {:?}",
+            self.block_number, self.root.nonce, self.root.hash
+        )?;
+
+        match self.onchain_root {
+            Some(onchain_root) if onchain_root == self.root.hash => {
+                write!(f, ", matches onchain root")
+            }
+            Some(onchain_root) => {
+                write!(f, ", diverged from onchain root {onchain_root:?}")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Scans the canonical tree events from the next block of the tree manager to the chain head and decodes each tree
+/// change without building the tree, comparing the last root of each block against the onchain root at that block.
+/// Tree changes with leaf indices past the capacity of a tree of depth `tree_depth` are rejected.
+pub async fn index_dry_run<M: Middleware + 'static>(
+    tree_manager: &TreeManager<M, CanonicalTree>,
+    tree_depth: usize,
+) -> Result<Vec<DryRunStep>, WorldTreeError<M>> {
+    let middleware = tree_manager.block_scanner.middleware.clone();
+
+    let logs = tree_manager
+        .block_scanner
+        .next()
+        .await
+        .map_err(WorldTreeError::MiddlewareError)?;
+    let identity_updates =
+        extract_identity_updates(&logs, middleware.clone(), tree_depth).await?;
+
+    let identity_manager =
+        IWorldIDIdentityManager::new(tree_manager.address, middleware);
+
+    let mut onchain_roots = HashMap::new();
+    for block_number in logs.iter().filter_map(|log| log.block_number) {
+        let block_number = block_number.as_u64();
+        if onchain_roots.contains_key(&block_number) {
+            continue;
+        }
+
+        let onchain_root = identity_manager
+            .latest_root()
+            .block(block_number)
+            .call()
+            .await?;
+        onchain_roots.insert(block_number, Hash::from_limbs(onchain_root.0));
+    }
+
+    Ok(dry_run_steps(&logs, &identity_updates, &onchain_roots))
+}
+
+/// Pairs each decoded root with the block of the log that emitted it, in the order the roots were emitted
+pub fn dry_run_steps(
+    logs: &[Log],
+    identity_updates: &BTreeMap<Root, LeafUpdates>,
+    onchain_roots: &HashMap<u64, Hash>,
+) -> Vec<DryRunStep> {
+    let roots = identity_updates
+        .keys()
+        .map(|root| (root.hash, *root))
+        .collect::<HashMap<_, _>>();
+
+    let mut logs = logs
+        .iter()
+        .filter_map(|log| {
+            let block_number = log.block_number?.as_u64();
+            let log_index = log.log_index.unwrap_or_default();
+            let post_root = Hash::from_be_bytes(log.topics.get(3)?.0);
+
+            Some((block_number, log_index, post_root))
+        })
+        .collect::<Vec<_>>();
+    logs.sort_unstable_by_key(|(block_number, log_index, _)| {
+        (*block_number, *log_index)
+    });
+
+    let mut steps: Vec<DryRunStep> = vec![];
+    for (block_number, _, post_root) in logs {
+        let Some(root) = roots.get(&post_root) else {
+            continue;
+        };
+
+        // Only the last root of a block can be compared against the onchain root
+        if let Some(previous) = steps.last_mut() {
+            if previous.block_number == block_number {
+                previous.onchain_root = None;
+            }
+        }
+
+        steps.push(DryRunStep {
+            block_number,
+            root: *root,
+            onchain_root: onchain_roots.get(&block_number).copied(),
+        });
+    }
+
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::contract::EthEvent;
+    use ethers::types::{H256, U64};
+
+    use super::*;
+    use crate::abi::TreeChangedFilter;
+    use crate::tree::LeafIndex;
+
+    fn tree_changed_log(block_number: u64, log_index: u64, root: Hash) -> Log {
+        Log {
+            topics: vec![
+                TreeChangedFilter::signature(),
+                H256::zero(),
+                H256::zero(),
+                H256(root.to_be_bytes::<32>()),
+            ],
+            block_number: Some(U64::from(block_number)),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dry_run_steps() {
+        let roots = (1..=4)
+            .map(|nonce| Root {
+                hash: Hash::from(100 + nonce as u64),
+                nonce,
+            })
+            .collect::<Vec<_>>();
+
+        let identity_updates = roots
+            .iter()
+            .map(|root| {
+                let updates = HashMap::from([(
+                    LeafIndex(root.nonce as u32),
+                    Hash::from(root.nonce as u64),
+                )]);
+                (*root, LeafUpdates::Insert(updates))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        // The second and third roots are emitted in the same block, and the logs are recorded out of order
+        let logs = vec![
+            tree_changed_log(12, 0, roots[3].hash),
+            tree_changed_log(11, 1, roots[2].hash),
+            tree_changed_log(10, 0, roots[0].hash),
+            tree_changed_log(11, 0, roots[1].hash),
+        ];
+
+        let onchain_roots = HashMap::from([
+            (10, roots[0].hash),
+            (11, roots[2].hash),
+            (12, Hash::from(1)),
+        ]);
+
+        let steps = dry_run_steps(&logs, &identity_updates, &onchain_roots);
+        let printed = steps.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        assert_eq!(
+            printed,
+            vec![
+                format!(
+                    "Block 10: nonce = 1, root = {:?}, matches onchain root",
+                    roots[0].hash
+                ),
+                format!("Block 11: nonce = 2, root = {:?}", roots[1].hash),
+                format!(
+                    "Block 11: nonce = 3, root = {:?}, matches onchain root",
+                    roots[2].hash
+                ),
+                format!(
+                    "Block 12: nonce = 4, root = {:?}, diverged from onchain root {:?}",
+                    roots[3].hash,
+                    Hash::from(1)
+                ),
+            ]
+        );
+
+        let diverged = steps
+            .iter()
+            .filter(|step| step.diverged())
+            .map(|step| step.block_number)
+            .collect::<Vec<_>>();
+        assert_eq!(diverged, vec![12]);
+    }
+}
@@ -0,0 +1,200 @@
+use semaphore::merkle_tree::Branch;
+use semaphore::Field;
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::identity_tree::InclusionProof;
+
+/// Representation of the field elements in a proof response. Both representations deserialize back into a `Field`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum FieldEncoding {
+    /// `0x` prefixed hex, as serialized by `Field`
+    #[default]
+    Hex,
+    /// Decimal big integer strings
+    Decimal,
+}
+
+/// A field element serialized in the requested encoding
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedField {
+    pub value: Field,
+    pub encoding: FieldEncoding,
+}
+
+impl EncodedField {
+    pub fn new(value: Field, encoding: FieldEncoding) -> Self {
+        Self { value, encoding }
+    }
+}
+
+impl Serialize for EncodedField {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match self.encoding {
+            FieldEncoding::Hex => self.value.serialize(serializer),
+            FieldEncoding::Decimal => {
+                serializer.serialize_str(&self.value.to_string())
+            }
+        }
+    }
+}
+
+/// An inclusion proof serialized with its root, siblings and checksum in the requested encoding
+#[derive(Debug)]
+pub struct EncodedInclusionProof {
+    pub proof: InclusionProof,
+    pub encoding: FieldEncoding,
+}
+
+impl EncodedInclusionProof {
+    pub fn new(proof: InclusionProof, encoding: FieldEncoding) -> Self {
+        Self { proof, encoding }
+    }
+}
+
+/// Mirrors the layout of a serialized `InclusionProof` with each field element wrapped in its encoding
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InclusionProofRepr {
+    root: EncodedField,
+    proof: Vec<BranchRepr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<EncodedField>,
+}
+
+#[derive(Serialize)]
+enum BranchRepr {
+    Left(EncodedField),
+    Right(EncodedField),
+}
+
+impl Serialize for EncodedInclusionProof {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        // Hex is the native representation, so the proof is serialized as is
+        if self.encoding == FieldEncoding::Hex {
+            return self.proof.serialize(serializer);
+        }
+
+        let encode = |value| EncodedField::new(value, self.encoding);
+
+        InclusionProofRepr {
+            root: encode(self.proof.root),
+            proof: self
+                .proof
+                .proof
+                .0
+                .iter()
+                .map(|branch| match branch {
+                    Branch::Left(sibling) => BranchRepr::Left(encode(*sibling)),
+                    Branch::Right(sibling) => {
+                        BranchRepr::Right(encode(*sibling))
+                    }
+                })
+                .collect(),
+            checksum: self.proof.checksum.map(encode),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use semaphore::cascading_merkle_tree::CascadingMerkleTree;
+    use semaphore::poseidon_tree::PoseidonHash;
+
+    use super::*;
+    use crate::tree::Hash;
+
+    fn inclusion_proof() -> InclusionProof {
+        let leaves = (1..=4).map(Hash::from).collect::<Vec<_>>();
+        let tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                2,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        InclusionProof::new(tree.root(), tree.proof(2)).with_checksum()
+    }
+
+    #[test]
+    fn test_hex_encoding() -> eyre::Result<()> {
+        let proof = inclusion_proof();
+
+        // The default encoding matches the native serialization of the proof
+        let encoded = serde_json::to_value(EncodedInclusionProof::new(
+            inclusion_proof(),
+            FieldEncoding::default(),
+        ))?;
+        assert_eq!(encoded, serde_json::to_value(&proof)?);
+        assert!(encoded["root"].as_str().unwrap().starts_with("0x"));
+
+        let decoded: InclusionProof = serde_json::from_value(encoded)?;
+        assert_eq!(decoded, proof);
+        assert_eq!(decoded.checksum, proof.checksum);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimal_encoding() -> eyre::Result<()> {
+        let proof = inclusion_proof();
+
+        let encoded = serde_json::to_value(EncodedInclusionProof::new(
+            inclusion_proof(),
+            FieldEncoding::Decimal,
+        ))?;
+        assert_eq!(encoded["root"], proof.root.to_string());
+        assert_eq!(encoded["checksum"], proof.checksum.unwrap().to_string());
+
+        // Siblings keep their direction
+        let branches = encoded["proof"].as_array().unwrap();
+        assert_eq!(branches.len(), proof.proof.0.len());
+        for (encoded_branch, branch) in
+            branches.iter().zip(proof.proof.0.iter())
+        {
+            let (direction, sibling) = match branch {
+                Branch::Left(sibling) => ("Left", sibling),
+                Branch::Right(sibling) => ("Right", sibling),
+            };
+            assert_eq!(encoded_branch[direction], sibling.to_string());
+        }
+
+        let decoded: InclusionProof = serde_json::from_value(encoded)?;
+        assert_eq!(decoded, proof);
+        assert_eq!(decoded.checksum, proof.checksum);
+        assert!(decoded.verify(Hash::from(3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_encoding_query_param() -> eyre::Result<()> {
+        let decimal: FieldEncoding = serde_json::from_str("\"decimal\"")?;
+        assert_eq!(decimal, FieldEncoding::Decimal);
+
+        let field = Field::from(255);
+        assert_eq!(
+            serde_json::to_value(EncodedField::new(
+                field,
+                FieldEncoding::Decimal
+            ))?,
+            "255"
+        );
+        assert_eq!(
+            serde_json::to_value(EncodedField::new(field, FieldEncoding::Hex))?,
+            serde_json::to_value(field)?
+        );
+
+        Ok(())
+    }
+}
@@ -1,9 +1,15 @@
+use axum::extract::rejection::{JsonRejection, QueryRejection};
 use axum::response::IntoResponse;
+use axum::Json;
 use ethers::prelude::{AbiError, ContractError};
 use ethers::providers::Middleware;
+use ethers::types::{H160, H256};
 use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use super::Hash;
+
 #[derive(Error, Debug)]
 pub enum WorldTreeError<M>
 where
@@ -27,6 +33,30 @@ where
     TransactionNotFound,
     #[error("Calldata does not have a function selector")]
     MissingFunctionSelector,
+    #[error("No contract code deployed at {address:?} on chain {chain_id}")]
+    ContractCodeNotFound { address: H160, chain_id: u64 },
+    #[error(
+        "Insertion at leaf index {found} skips leaves from index {expected}"
+    )]
+    LeafIndexGap { expected: u32, found: u32 },
+    #[error("Root {root:?} at block {block_number} does not match the onchain root {onchain_root:?}")]
+    RootVerificationFailed {
+        block_number: u64,
+        root: Hash,
+        onchain_root: Hash,
+    },
+    #[error("Sync stalled at block {block} after {failures} consecutive failed attempts")]
+    SyncStalled { block: u64, failures: u32 },
+    #[error("Batch of {size} exceeds the maximum batch size of {max}")]
+    BatchTooLarge { size: usize, max: usize },
+    #[error("Malformed tree change in transaction {tx_hash:?}: {reason}")]
+    MalformedTreeChange { tx_hash: H256, reason: String },
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Offset {offset} exceeds the number of leaves {total}")]
+    OffsetOutOfRange { offset: usize, total: usize },
+    #[error("Configured tree depth {configured} does not match the onchain tree depth {onchain}")]
+    TreeDepthMismatch { configured: usize, onchain: usize },
     #[error(transparent)]
     IdentityTreeError(#[from] IdentityTreeError),
     #[error(transparent)]
@@ -39,32 +69,90 @@ where
     EthABIError(#[from] ethers::abi::Error),
     #[error(transparent)]
     HyperError(#[from] hyper::Error),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
 }
 
 #[derive(Error, Debug)]
 pub enum IdentityTreeError {
     #[error("Root not found")]
     RootNotFound,
+    #[error("Root has been pruned")]
+    RootPruned,
     #[error("Leaf already exists")]
     LeafAlreadyExists,
     #[error("Leaf does not exist in tree")]
     LeafNotFound,
     #[error("Proof is invalid - the tree is likely corrupted")]
     InvalidProofCorruptedTree,
+    #[error("Tree is full")]
+    TreeFull,
+    #[error("Inserted leaves can not be zero")]
+    ZeroLeafInsert,
+    #[error("Leaf index is out of range for the tree depth")]
+    LeafIndexOutOfRange,
+    #[error(
+        "Leaf index {found} does not match the next empty index {expected}"
+    )]
+    UnexpectedLeafIndex { expected: u32, found: u32 },
+    #[error("Failed to insert leaf {failed_at} of the batch after inserting {} leaves: {source}", .inserted.len())]
+    PartialInsert {
+        inserted: Vec<u32>,
+        failed_at: usize,
+        source: Box<IdentityTreeError>,
+    },
+    #[error("Transition between roots is not append only")]
+    NotAppendOnly,
+    #[error("No finalized root is available yet")]
+    NoFinalizedRoot,
+    #[error("Storage updates do not match the expected root")]
+    StorageUpdatesRootMismatch,
+    #[error("Storage update at node {node_idx} does not match the hash of its children")]
+    InvalidStorageUpdate { node_idx: u32 },
+    #[error("Tree depth mismatch: expected {expected}, found {found}")]
+    DepthMismatch { expected: usize, found: usize },
+    #[error("Tree self check failed: {0}")]
+    SelfCheckFailed(String),
     #[error(transparent)]
     MmapVecError(#[from] eyre::Report),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 }
 
+#[derive(Error, Debug)]
+pub enum DeltaDecodeError {
+    #[error("Unsupported delta format version {found}, expected {expected}")]
+    UnsupportedVersion { expected: u8, found: u8 },
+    #[error("Delta is {found} bytes, expected {expected}")]
+    LengthMismatch { expected: usize, found: usize },
+    #[error("Delta contains node {node_idx} more than once")]
+    DuplicateNode { node_idx: u32 },
+}
+
 impl IdentityTreeError {
     fn to_status_code(&self) -> StatusCode {
         match self {
             IdentityTreeError::RootNotFound
             | IdentityTreeError::LeafNotFound => StatusCode::NOT_FOUND,
+            IdentityTreeError::RootPruned => StatusCode::GONE,
+            IdentityTreeError::LeafIndexOutOfRange => StatusCode::BAD_REQUEST,
+            IdentityTreeError::NoFinalizedRoot => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            IdentityTreeError::RootNotFound => "root_not_found",
+            IdentityTreeError::LeafNotFound => "leaf_not_found",
+            IdentityTreeError::RootPruned => "root_pruned",
+            IdentityTreeError::LeafIndexOutOfRange => "leaf_index_out_of_range",
+            IdentityTreeError::NoFinalizedRoot => "no_finalized_root",
+            _ => "internal",
+        }
+    }
 }
 
 impl<M> WorldTreeError<M>
@@ -74,10 +162,61 @@ where
     fn to_status_code(&self) -> StatusCode {
         match self {
             WorldTreeError::TreeNotSynced => StatusCode::SERVICE_UNAVAILABLE,
+            WorldTreeError::BatchTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            WorldTreeError::InvalidRequest(_)
+            | WorldTreeError::OffsetOutOfRange { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            WorldTreeError::ChainIdNotFound => StatusCode::NOT_FOUND,
             WorldTreeError::IdentityTreeError(e) => e.to_status_code(),
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    /// Returns a stable identifier for the error that clients can match on
+    fn error_code(&self) -> &'static str {
+        match self {
+            WorldTreeError::TreeNotSynced => "tree_not_synced",
+            WorldTreeError::ChainIdNotFound => "chain_id_not_found",
+            WorldTreeError::BatchTooLarge { .. } => "batch_too_large",
+            WorldTreeError::InvalidRequest(_) => "invalid_request",
+            WorldTreeError::OffsetOutOfRange { .. } => "offset_out_of_range",
+            WorldTreeError::IdentityTreeError(e) => e.error_code(),
+            _ => "internal",
+        }
+    }
+}
+
+impl<M> From<JsonRejection> for WorldTreeError<M>
+where
+    M: Middleware + 'static,
+{
+    fn from(rejection: JsonRejection) -> Self {
+        WorldTreeError::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl<M> From<QueryRejection> for WorldTreeError<M>
+where
+    M: Middleware + 'static,
+{
+    fn from(rejection: QueryRejection) -> Self {
+        WorldTreeError::InvalidRequest(rejection.body_text())
+    }
+}
+
+/// JSON envelope returned by the API for all errors
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
 }
 
 impl<M> IntoResponse for WorldTreeError<M>
@@ -86,7 +225,129 @@ where
 {
     fn into_response(self) -> axum::response::Response {
         let status_code = self.to_status_code();
-        let response_body = self.to_string();
-        (status_code, response_body).into_response()
+        let response_body = ErrorResponse {
+            error: ErrorBody {
+                code: self.error_code().to_string(),
+                message: self.to_string(),
+            },
+        };
+
+        (status_code, Json(response_body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::providers::{MockProvider, Provider};
+
+    use super::*;
+
+    type M = Provider<MockProvider>;
+
+    async fn error_response(
+        error: WorldTreeError<M>,
+    ) -> eyre::Result<(StatusCode, ErrorResponse)> {
+        let response = error.into_response();
+        let status_code = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        Ok((status_code, serde_json::from_slice(&body)?))
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() -> eyre::Result<()> {
+        let cases = [
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::LeafNotFound,
+                ),
+                StatusCode::NOT_FOUND,
+                "leaf_not_found",
+            ),
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::RootNotFound,
+                ),
+                StatusCode::NOT_FOUND,
+                "root_not_found",
+            ),
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::RootPruned,
+                ),
+                StatusCode::GONE,
+                "root_pruned",
+            ),
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::NoFinalizedRoot,
+                ),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no_finalized_root",
+            ),
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::LeafIndexOutOfRange,
+                ),
+                StatusCode::BAD_REQUEST,
+                "leaf_index_out_of_range",
+            ),
+            (
+                WorldTreeError::InvalidRequest("bad hex".to_string()),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                WorldTreeError::OffsetOutOfRange {
+                    offset: 11,
+                    total: 10,
+                },
+                StatusCode::BAD_REQUEST,
+                "offset_out_of_range",
+            ),
+            (
+                WorldTreeError::BatchTooLarge {
+                    size: 101,
+                    max: 100,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "batch_too_large",
+            ),
+            (
+                WorldTreeError::ChainIdNotFound,
+                StatusCode::NOT_FOUND,
+                "chain_id_not_found",
+            ),
+            (
+                WorldTreeError::TreeNotSynced,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "tree_not_synced",
+            ),
+            (
+                WorldTreeError::IdentityTreeError(
+                    IdentityTreeError::InvalidProofCorruptedTree,
+                ),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+        ];
+
+        for (error, expected_status, expected_code) in cases {
+            let message = error.to_string();
+            let (status_code, response) = error_response(error).await?;
+
+            assert_eq!(status_code, expected_status);
+            assert_eq!(
+                response,
+                ErrorResponse {
+                    error: ErrorBody {
+                        code: expected_code.to_string(),
+                        message,
+                    },
+                }
+            );
+        }
+
+        Ok(())
     }
 }
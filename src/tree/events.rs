@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ethers::types::Log;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use super::identity_tree::{LeafUpdates, Root};
+use super::Hash;
+
+/// Number of events buffered for each subscriber, subscribers that fall further behind miss the oldest events
+pub const INDEXER_EVENTS_CAPACITY: usize = 1024;
+
+/// Progress of the canonical tree indexer, published for external monitoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum IndexerEvent {
+    /// The tree started backfilling the canonical tree from `from_block`
+    BackfillStarted { from_block: u64 },
+    /// The tree finished backfilling the canonical tree up to `block_number`, with `root` as the latest root
+    BackfillCompleted { block_number: u64, root: Hash },
+    /// A new root emitted at `block_number` was received from the canonical tree
+    BlockProcessed { block_number: u64, root: Hash },
+    /// Every block up to the chain head at `block_number` has been processed
+    CaughtUp { block_number: u64 },
+}
+
+/// Broadcasts `IndexerEvent`s to any number of subscribers, events emitted while there are no subscribers are dropped
+#[derive(Debug, Clone)]
+pub struct IndexerEvents(broadcast::Sender<IndexerEvent>);
+
+impl IndexerEvents {
+    pub fn new(capacity: usize) -> Self {
+        Self(broadcast::channel(capacity).0)
+    }
+
+    /// Returns a receiver for all events emitted after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<IndexerEvent> {
+        self.0.subscribe()
+    }
+
+    pub fn emit(&self, event: IndexerEvent) {
+        tracing::debug!(?event, "Indexer event");
+
+        // Sending only fails when there are no subscribers, which is not an error
+        let _ = self.0.send(event);
+    }
+}
+
+impl Default for IndexerEvents {
+    fn default() -> Self {
+        Self::new(INDEXER_EVENTS_CAPACITY)
+    }
+}
+
+/// Returns a `BlockProcessed` event for each root in `identity_updates`, in order, using the block of the log that emitted
+/// the root. Roots without a matching log, e.g. when recovered by a rescan, fall back to `last_block`.
+pub fn block_processed_events(
+    logs: &[Log],
+    identity_updates: &BTreeMap<Root, LeafUpdates>,
+    last_block: u64,
+) -> Vec<IndexerEvent> {
+    // The post root is the third topic of the `TreeChanged` event
+    let root_blocks = logs
+        .iter()
+        .filter_map(|log| {
+            let post_root = log.topics.get(3)?;
+            let block_number = log.block_number?.as_u64();
+
+            Some((Hash::from_be_bytes(post_root.0), block_number))
+        })
+        .collect::<HashMap<_, _>>();
+
+    identity_updates
+        .keys()
+        .map(|root| IndexerEvent::BlockProcessed {
+            block_number: root_blocks
+                .get(&root.hash)
+                .copied()
+                .unwrap_or(last_block),
+            root: root.hash,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::abi::AbiEncode;
+    use ethers::types::{Transaction, H256, U256, U64};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::abi::RegisterIdentitiesCall;
+    use crate::tree::identity_tree::IdentityTree;
+    use crate::tree::WorldTree;
+
+    fn tree_changed_log(block_number: u64, post_root: Hash) -> Log {
+        Log {
+            topics: vec![
+                H256::zero(),
+                H256::zero(),
+                H256::zero(),
+                H256(post_root.to_be_bytes()),
+            ],
+            block_number: Some(U64::from(block_number)),
+            ..Default::default()
+        }
+    }
+
+    fn root(nonce: usize) -> Root {
+        Root {
+            hash: Hash::from(nonce + 100),
+            nonce,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfill_events() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+
+        let mut expected_tree = IdentityTree::new(3);
+        expected_tree.insert(0, Hash::from(1))?;
+        expected_tree.insert(1, Hash::from(2))?;
+        let post_root = expected_tree.root();
+        let tx_hash = H256::from_low_u64_be(1);
+
+        // Mocked responses are returned in reverse order. The backfill scans blocks 0 to 10 and finds a single log, then
+        // fetches the `registerIdentities` transaction that emitted it.
+        let call = RegisterIdentitiesCall {
+            insertion_proof: [U256::zero(); 8],
+            pre_root: U256::zero(),
+            start_index: 0,
+            identity_commitments: vec![U256::from(1), U256::from(2)],
+            post_root: U256(post_root.into_limbs()),
+        };
+        mock.push(Transaction {
+            hash: tx_hash,
+            nonce: U256::from(1),
+            input: call.encode().into(),
+            ..Default::default()
+        })?;
+        mock.push(vec![Log {
+            transaction_hash: Some(tx_hash),
+            ..tree_changed_log(5, post_root)
+        }])?;
+        mock.push(U64::from(10))?;
+
+        // Events emitted before subscribing are not received
+        world_tree
+            .events
+            .emit(IndexerEvent::CaughtUp { block_number: 0 });
+
+        let mut rx = world_tree.subscribe_events();
+        world_tree.sync_to_head().await?;
+        assert_eq!(world_tree.identity_tree.read().await.root(), post_root);
+
+        let mut received = vec![];
+        loop {
+            match rx.try_recv() {
+                Ok(event) => received.push(event),
+                Err(TryRecvError::Empty) => break,
+                Err(err) => panic!("Unexpected error: {err:?}"),
+            }
+        }
+
+        assert_eq!(
+            received,
+            vec![
+                IndexerEvent::BackfillStarted { from_block: 0 },
+                IndexerEvent::BackfillCompleted {
+                    block_number: 10,
+                    root: post_root,
+                },
+                IndexerEvent::CaughtUp { block_number: 10 },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_processed_events() {
+        // Two roots are received from logs and a third is recovered by a rescan
+        let logs = vec![
+            tree_changed_log(10, root(1).hash),
+            tree_changed_log(12, root(2).hash),
+        ];
+        let identity_updates = (1..=3)
+            .map(|nonce| (root(nonce), LeafUpdates::Insert(HashMap::new())))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            block_processed_events(&logs, &identity_updates, 15),
+            vec![
+                IndexerEvent::BlockProcessed {
+                    block_number: 10,
+                    root: root(1).hash,
+                },
+                IndexerEvent::BlockProcessed {
+                    block_number: 12,
+                    root: root(2).hash,
+                },
+                IndexerEvent::BlockProcessed {
+                    block_number: 15,
+                    root: root(3).hash,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_indexer_event_serialization() -> eyre::Result<()> {
+        let event = IndexerEvent::BlockProcessed {
+            block_number: 10,
+            root: Hash::from(1),
+        };
+
+        let value = serde_json::to_value(event)?;
+        assert_eq!(value["type"], "blockProcessed");
+        assert_eq!(value["blockNumber"], 10);
+
+        Ok(())
+    }
+}
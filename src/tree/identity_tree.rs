@@ -1,16 +1,23 @@
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use futures::{Stream, StreamExt};
 use rayon::iter::{Either, IntoParallelIterator, ParallelIterator};
 use semaphore::cascading_merkle_tree::CascadingMerkleTree;
 use semaphore::generic_storage::{GenericStorage, MmapVec};
 use semaphore::merkle_tree::{Branch, Hasher};
 use semaphore::poseidon_tree::{PoseidonHash, Proof};
 use semaphore::Field;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::error::IdentityTreeError;
+use super::leaf_filter::LeafFilter;
+use super::metrics::{NoopMetrics, TreeMetrics};
 use super::{Hash, LeafIndex, NodeIndex};
 
 // Leaf index to hash, 0 indexed from the initial leaf
@@ -18,12 +25,100 @@ pub type Leaves = HashMap<LeafIndex, Hash>;
 // Node index to hash, 0 indexed from the root
 pub type StorageUpdates = HashMap<NodeIndex, Hash>;
 
+// Number of leaves appended to the tree at a time by `append_stream`
+const APPEND_STREAM_CHUNK_SIZE: usize = 1024;
+// Number of siblings resolved from the canonical tree for a single proof above which a warning is logged
+const PROOF_FALLBACK_WARN_THRESHOLD: usize = 20;
+// Number of transaction hashes retained by `record_root_tx`, after which the oldest are evicted
+pub const ROOT_TX_RETENTION: usize = 100_000;
+// Number of pruned root hashes retained to distinguish pruned roots from unknown roots, after which the oldest are evicted
+pub const PRUNED_ROOT_RETENTION: usize = 100_000;
+
 pub struct IdentityTree<S> {
     pub tree: CascadingMerkleTree<PoseidonHash, S>,
     pub tree_updates: BTreeMap<Root, StorageUpdates>,
+    // Cost of `append_updates` for each root in `tree_updates`, removed once the root is applied or pruned
+    pub update_stats: HashMap<Root, UpdateStats>,
     // Hashmap of root hash to nonce
     pub roots: HashMap<Hash, usize>,
+    // Hashmap of the hash of the transaction that produced a root to the root, for both pending and canonical roots
+    pub root_txs: HashMap<H256, Root>,
+    // Transaction hashes in `root_txs` in the order they were recorded, used to evict the oldest beyond `ROOT_TX_RETENTION`
+    pub root_tx_order: VecDeque<H256>,
     pub leaves: HashMap<Hash, u32>,
+    // Policy determining if `insert` reuses leaf indices freed by `remove`
+    pub leaf_index_policy: LeafIndexPolicy,
+    // Indices of removed leaves available for reuse, only tracked under `LeafIndexPolicy::ReuseDeleted`
+    pub free_indices: BTreeSet<u32>,
+    // Hashes of roots that have been applied to the canonical tree or pruned from `tree_updates`
+    pub pruned_roots: HashSet<Hash>,
+    // Hashes in `pruned_roots` in the order they were pruned, used to evict the oldest beyond `PRUNED_ROOT_RETENTION`
+    pub pruned_root_order: VecDeque<Hash>,
+    // Counts of where siblings were resolved from when constructing proofs at non canonical roots
+    pub sibling_resolutions: SiblingResolutions,
+    // Hooks invoked to record metrics about the tree, which are no-ops unless set via `with_metrics`
+    pub metrics: Arc<dyn TreeMetrics>,
+    // The root represented by the canonical tree, set when updates are applied to the canonical tree
+    pub canonical_root: Option<Root>,
+    // Bloom filter over the leaves hashmap used to reject absent leaves early, disabled unless set via `with_leaf_filter`
+    pub leaf_filter: Option<LeafFilter>,
+}
+
+/// Number of proof siblings resolved from `tree_updates` versus falling back to the canonical tree
+#[derive(Debug, Default)]
+pub struct SiblingResolutions {
+    pub updates: AtomicU64,
+    pub canonical_tree: AtomicU64,
+}
+
+/// Time taken by `append_updates` to construct the storage updates for a root, and the number of nodes it recomputed
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateStats {
+    pub elapsed_ms: u64,
+    pub num_recomputed_nodes: usize,
+}
+
+/// Leaves hashmap entries changed by `IdentityTree::reconcile`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Entries removed since the leaf is not at the index in the canonical tree or pending updates, sorted by index
+    pub removed: Vec<(u32, Hash)>,
+    /// Entries added for leaves in the canonical tree or pending updates missing from the hashmap, sorted by index
+    pub restored: Vec<(u32, Hash)>,
+}
+
+/// Index assigned to an inserted leaf and the root of the tree after the insertion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InsertReceipt {
+    pub index: u32,
+    pub root: Hash,
+}
+
+/// Status of a root hash relative to the state of an `IdentityTree`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootStatus {
+    /// The root of the canonical tree
+    Canonical,
+    /// A root with updates in `tree_updates` that have not been applied to the canonical tree
+    Pending,
+    /// A root that was previously tracked but has since been superseded by the canonical tree
+    Pruned,
+    /// A root that has never been seen by the tree
+    Unknown,
+}
+
+/// Determines where `IdentityTree::insert` places new leaves
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LeafIndexPolicy {
+    /// New leaves are always appended to the end of the tree
+    #[default]
+    AppendOnly,
+    /// New leaves fill the lowest index zeroed by `remove` before appending
+    ReuseDeleted,
 }
 
 impl IdentityTree<Vec<Hash>> {
@@ -33,9 +128,124 @@ impl IdentityTree<Vec<Hash>> {
         Self {
             tree,
             tree_updates: BTreeMap::new(),
+            update_stats: HashMap::new(),
             roots: HashMap::new(),
+            root_txs: HashMap::new(),
+            root_tx_order: VecDeque::new(),
             leaves: HashMap::new(),
+            leaf_index_policy: LeafIndexPolicy::default(),
+            free_indices: BTreeSet::new(),
+            pruned_roots: HashSet::new(),
+            pruned_root_order: VecDeque::new(),
+            sibling_resolutions: SiblingResolutions::default(),
+            metrics: Arc::new(NoopMetrics),
+            canonical_root: None,
+            leaf_filter: None,
+        }
+    }
+
+    /// Rebuilds a tree from the leaves of the canonical tree and the pending `tree_updates`,
+    /// e.g. as exported via `canonical_leaves` and `tree_updates`, without replaying chain events.
+    /// Note that deleted leaves at the end of the canonical tree are not represented in `leaves` and are not restored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the root node of any storage update does not match its root hash,
+    /// if the updates were built for a tree of a different depth, or if a leaf index is out of range for the tree depth.
+    pub fn from_updates(
+        tree_depth: usize,
+        updates: BTreeMap<Root, StorageUpdates>,
+        leaves: HashMap<Hash, u32>,
+    ) -> Result<Self, IdentityTreeError> {
+        let mut identity_tree = Self::new(tree_depth);
+
+        // Place the canonical leaves at their indices, filling any gaps with empty leaves
+        let num_leaves =
+            leaves.values().max().map_or(0, |idx| *idx as usize + 1);
+
+        if num_leaves > 1 << tree_depth {
+            return Err(IdentityTreeError::LeafIndexOutOfRange);
+        }
+
+        let mut canonical_leaves = vec![Hash::ZERO; num_leaves];
+        for (leaf, idx) in leaves.iter() {
+            canonical_leaves[*idx as usize] = *leaf;
+        }
+
+        identity_tree.tree.extend_from_slice(&canonical_leaves);
+        identity_tree.leaves = leaves;
+
+        // Ensure that each update is internally consistent with its root
+        for (root, update) in updates.iter() {
+            if update.get(&NodeIndex(0)) != Some(&root.hash) {
+                return Err(IdentityTreeError::StorageUpdatesRootMismatch);
+            }
+
+            // Every update contains the leaves it changed, so its deepest node reveals the depth of the tree it was built for
+            if let Some(max_idx) = update.keys().map(|idx| idx.0).max() {
+                let (found, _) = storage_idx_to_coords(max_idx as usize);
+                if found != tree_depth {
+                    return Err(IdentityTreeError::DepthMismatch {
+                        expected: tree_depth,
+                        found,
+                    });
+                }
+            }
+
+            identity_tree.roots.insert(root.hash, root.nonce);
+        }
+
+        // Since each update is flattened into the next, the latest update contains all pending leaf changes
+        if let Some(latest_update) = updates.values().last() {
+            let first_leaf_idx = leaf_to_storage_idx(0, tree_depth);
+
+            for (node_idx, value) in latest_update.iter() {
+                if node_idx.0 < first_leaf_idx {
+                    continue;
+                }
+
+                let leaf_idx = storage_to_leaf_idx(node_idx.0, tree_depth);
+                if leaf_idx as usize >= 1 << tree_depth {
+                    return Err(IdentityTreeError::LeafIndexOutOfRange);
+                }
+
+                if *value != Hash::ZERO {
+                    identity_tree.leaves.insert(*value, leaf_idx);
+                } else if (leaf_idx as usize) < identity_tree.tree.num_leaves()
+                {
+                    let leaf = identity_tree.tree.get_leaf(leaf_idx as usize);
+                    identity_tree.leaves.remove(&leaf);
+                }
+            }
         }
+
+        identity_tree.tree_updates = updates;
+
+        Ok(identity_tree)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl IdentityTree<Vec<Hash>> {
+    /// Builds a tree of `num_leaves` pseudo-random leaves deterministically generated from `seed`
+    pub fn random(tree_depth: usize, num_leaves: usize, seed: u64) -> Self {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+
+        let leaves = (0..num_leaves as u32)
+            .map(|idx| {
+                let mut limbs: [u64; 4] = rng.gen();
+                limbs[3] = 0; // nullify most significant limb to keep the values in the Field
+
+                (idx, Hash::from_limbs(limbs))
+            })
+            .collect::<Vec<_>>();
+
+        let mut identity_tree = Self::new(tree_depth);
+        identity_tree.extend_from_slice(&leaves);
+
+        identity_tree
     }
 }
 
@@ -105,7 +315,18 @@ impl IdentityTree<MmapVec<Hash>> {
             tree,
             leaves,
             tree_updates: BTreeMap::new(),
+            update_stats: HashMap::new(),
             roots: HashMap::new(),
+            root_txs: HashMap::new(),
+            root_tx_order: VecDeque::new(),
+            leaf_index_policy: LeafIndexPolicy::default(),
+            free_indices: BTreeSet::new(),
+            pruned_roots: HashSet::new(),
+            pruned_root_order: VecDeque::new(),
+            sibling_resolutions: SiblingResolutions::default(),
+            metrics: Arc::new(NoopMetrics),
+            canonical_root: None,
+            leaf_filter: None,
         })
     }
 }
@@ -114,23 +335,276 @@ impl<S> IdentityTree<S>
 where
     S: GenericStorage<Hash>,
 {
+    /// Returns the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.tree.depth()
+    }
+
+    /// Returns the root hash of the canonical tree
+    pub fn root(&self) -> Hash {
+        self.tree.root()
+    }
+
+    /// Sets the policy used by `insert` to place new leaves
+    /// When reusing deleted indices, any zeroed leaves already in the tree are tracked as free
+    pub fn with_leaf_index_policy(mut self, policy: LeafIndexPolicy) -> Self {
+        self.leaf_index_policy = policy;
+
+        self.free_indices = match policy {
+            LeafIndexPolicy::AppendOnly => BTreeSet::new(),
+            LeafIndexPolicy::ReuseDeleted => self
+                .tree
+                .leaves()
+                .enumerate()
+                .filter_map(|(idx, leaf)| {
+                    if leaf == Hash::ZERO {
+                        Some(idx as u32)
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        };
+
+        self
+    }
+
+    /// Sets the hooks used to record metrics about the tree
+    pub fn with_metrics(mut self, metrics: Arc<dyn TreeMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Enables a bloom filter over the leaves hashmap, checked before proof lookups so absent leaves are rejected early
+    /// The filter is rebuilt once the tree outgrows it or once removed leaves take up too much of it, see `LeafFilter::needs_rebuild`
+    pub fn with_leaf_filter(mut self, false_positive_rate: f64) -> Self {
+        self.enable_leaf_filter(false_positive_rate);
+        self
+    }
+
+    /// Enables the bloom filter over the leaves hashmap in place, see `with_leaf_filter`
+    pub fn enable_leaf_filter(&mut self, false_positive_rate: f64) {
+        self.leaf_filter = Some(LeafFilter::from_leaves(
+            self.leaves.keys(),
+            false_positive_rate,
+        ));
+    }
+
+    /// Rebuilds the leaf filter from the leaves hashmap if enabled, resizing it to the current number of leaves
+    pub fn rebuild_leaf_filter(&mut self) {
+        if let Some(filter) = &self.leaf_filter {
+            self.leaf_filter = Some(LeafFilter::from_leaves(
+                self.leaves.keys(),
+                filter.false_positive_rate(),
+            ));
+        }
+    }
+
+    // Rebuilds the leaf filter only if the leaves have drifted too far from what it was built over, since rebuilding
+    // iterates over every leaf while the tree is locked
+    fn maybe_rebuild_leaf_filter(&mut self) {
+        if self
+            .leaf_filter
+            .as_ref()
+            .is_some_and(|filter| filter.needs_rebuild(self.leaves.len()))
+        {
+            self.rebuild_leaf_filter();
+        }
+    }
+
+    // Adds a leaf to the leaves hashmap and the leaf filter
+    // Removed leaves are left in the filter until it is rebuilt, which only causes false positives
+    fn track_leaf(&mut self, leaf: Hash, leaf_idx: u32) {
+        self.leaves.insert(leaf, leaf_idx);
+
+        if let Some(filter) = &mut self.leaf_filter {
+            filter.insert(&leaf);
+        }
+    }
+
+    /// Returns a hash of the canonical root combined with the leaves hashmap sorted by index.
+    /// Two trees have the same fingerprint only if they share the same root and leaf index assignments.
+    pub fn fingerprint(&self) -> Hash {
+        let mut leaves = self
+            .leaves
+            .iter()
+            .map(|(leaf, idx)| (*idx, *leaf))
+            .collect::<Vec<_>>();
+
+        leaves.sort_unstable();
+
+        let mut bytes = Vec::with_capacity(32 + leaves.len() * 36);
+        bytes.extend_from_slice(&self.tree.root().to_be_bytes::<32>());
+
+        for (idx, leaf) in leaves {
+            bytes.extend_from_slice(&idx.to_be_bytes());
+            bytes.extend_from_slice(&leaf.to_be_bytes::<32>());
+        }
+
+        Hash::from_be_bytes(keccak256(bytes))
+    }
+
+    /// Returns the leaves of the canonical tree, excluding any pending updates
+    pub fn canonical_leaves(&self) -> HashMap<Hash, u32> {
+        self.tree
+            .leaves()
+            .enumerate()
+            .filter_map(|(idx, leaf)| {
+                if leaf != Hash::ZERO {
+                    Some((leaf, idx as u32))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` leaves of the canonical tree starting at index `offset`, ordered by index
+    /// Deleted leaves are included as zero so that pages remain stable
+    pub fn leaves_page(&self, offset: usize, limit: usize) -> Vec<(u32, Hash)> {
+        self.tree
+            .leaves()
+            .enumerate()
+            .skip(offset)
+            .take(limit)
+            .map(|(idx, leaf)| (idx as u32, leaf))
+            .collect()
+    }
+
     /// Inserts a new leaf into the tree and updates the leaves hashmap
-    /// Returns an error if the leaf already exists
+    /// Under `LeafIndexPolicy::ReuseDeleted`, the leaf is placed at the lowest free index if one exists
+    /// Returns a receipt with the index assigned to the leaf and the resulting root, or an error if the leaf already
+    /// exists, the tree is full or `index` is not the next empty index
     pub fn insert(
         &mut self,
         index: u32,
         leaf: Hash,
-    ) -> Result<(), IdentityTreeError> {
+    ) -> Result<InsertReceipt, IdentityTreeError> {
         // Check if the leaf already exists
         if self.leaves.contains_key(&leaf) {
             return Err(IdentityTreeError::LeafAlreadyExists);
         }
-        self.leaves.insert(leaf, index);
 
-        // We can expect here because the `reallocate` implementation for Vec<H::Hash> as DynamicTreeStorage does not fail
-        self.tree.push(leaf).expect("Failed to insert into tree");
+        // Fill the lowest deleted index if available
+        if let Some(free_idx) = self.free_indices.pop_first() {
+            self.track_leaf(leaf, free_idx);
+            self.tree.set_leaf(free_idx as usize, leaf);
 
-        Ok(())
+            return Ok(InsertReceipt {
+                index: free_idx,
+                root: self.tree.root(),
+            });
+        }
+
+        // Once the tree holds 2^depth leaves there is no room left to push to
+        if self.tree.num_leaves() >= 1 << self.tree.depth() {
+            return Err(IdentityTreeError::TreeFull);
+        }
+
+        // Leaves are pushed to the next empty index, so any other index would be tracked at the wrong slot
+        let next_idx = self.tree.num_leaves() as u32;
+        if index != next_idx {
+            return Err(IdentityTreeError::UnexpectedLeafIndex {
+                expected: next_idx,
+                found: index,
+            });
+        }
+
+        self.tree.push(leaf)?;
+        self.track_leaf(leaf, index);
+        self.metrics.set_leaf_count(self.tree.num_leaves());
+
+        Ok(InsertReceipt {
+            index,
+            root: self.tree.root(),
+        })
+    }
+
+    /// Inserts each leaf in order, stopping at the first leaf that fails to insert
+    /// Returns the index assigned to each leaf on success.
+    ///
+    /// This is not transactional, the leaves inserted before a failure remain in the tree. Use `append_updates` to
+    /// apply a batch of leaves atomically.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PartialInsert` with the indices assigned to the successfully inserted prefix and the position of the
+    /// leaf that failed, so that callers can resume from that position
+    pub fn insert_many(
+        &mut self,
+        leaves: &[(u32, Hash)],
+    ) -> Result<Vec<u32>, IdentityTreeError> {
+        let mut inserted = Vec::with_capacity(leaves.len());
+
+        for (position, (index, leaf)) in leaves.iter().enumerate() {
+            match self.insert(*index, *leaf) {
+                Ok(receipt) => inserted.push(receipt.index),
+                Err(source) => {
+                    return Err(IdentityTreeError::PartialInsert {
+                        inserted,
+                        failed_at: position,
+                        source: Box::new(source),
+                    });
+                }
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    /// Sets the leaf at `index` to `value`, tolerant of leaves that have already been applied
+    /// If the index already holds the value this is a no-op, if it holds a different value the leaf is updated in place,
+    /// and if the index is the next empty index the leaf is appended to the tree
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value is zero, if the value exists at a different index, or if the index is past the
+    /// next empty index
+    pub fn insert_or_update(
+        &mut self,
+        index: u32,
+        value: Hash,
+    ) -> Result<(), IdentityTreeError> {
+        // Zero is reserved for deleted leaves
+        if value == Hash::ZERO {
+            return Err(IdentityTreeError::ZeroLeafInsert);
+        }
+
+        if let Some(existing_idx) = self.leaves.get(&value) {
+            return if *existing_idx == index {
+                Ok(())
+            } else {
+                Err(IdentityTreeError::LeafAlreadyExists)
+            };
+        }
+
+        let num_leaves = self.tree.num_leaves();
+        match (index as usize).cmp(&num_leaves) {
+            std::cmp::Ordering::Less => {
+                let previous = self.tree.get_leaf(index as usize);
+                self.leaves.remove(&previous);
+
+                self.tree.set_leaf(index as usize, value);
+                self.track_leaf(value, index);
+                self.free_indices.remove(&index);
+
+                Ok(())
+            }
+            std::cmp::Ordering::Equal => {
+                if num_leaves >= 1 << self.tree.depth() {
+                    return Err(IdentityTreeError::TreeFull);
+                }
+
+                self.tree.push(value)?;
+                self.track_leaf(value, index);
+                self.metrics.set_leaf_count(self.tree.num_leaves());
+
+                Ok(())
+            }
+            std::cmp::Ordering::Greater => {
+                Err(IdentityTreeError::LeafIndexOutOfRange)
+            }
+        }
     }
 
     /// Extends the tree with new leaves and updates the leaves hashmap
@@ -139,34 +613,178 @@ where
         let leaves = leaves
             .iter()
             .map(|(idx, hash)| {
-                self.leaves.insert(*hash, *idx);
+                self.track_leaf(*hash, *idx);
                 *hash
             })
             .collect::<Vec<_>>();
 
         // Insert the new leaves into the tree
         self.tree.extend_from_slice(&leaves);
+        self.metrics.set_leaf_count(self.tree.num_leaves());
+    }
+
+    /// Extends the tree with leaves consumed from a stream, placing them in chunks and yielding to the runtime
+    /// between chunks so that backfilling a large number of leaves does not starve other tasks
+    /// Each leaf is placed at its stream index, updating leaves already in the tree and filling any gap past the end
+    /// of the tree with zero leaves
+    ///
+    /// Returns the root of the tree once the stream is exhausted, or an error if an index is past the capacity of the tree
+    pub async fn append_stream(
+        &mut self,
+        leaves: impl Stream<Item = (u32, Hash)>,
+    ) -> Result<Hash, IdentityTreeError> {
+        let mut chunks =
+            std::pin::pin!(leaves.chunks(APPEND_STREAM_CHUNK_SIZE));
+
+        while let Some(chunk) = chunks.next().await {
+            if chunk.iter().any(|(leaf_idx, _)| {
+                *leaf_idx as usize >= 1 << self.tree.depth()
+            }) {
+                return Err(IdentityTreeError::TreeFull);
+            }
+
+            // Leaves past the end of the tree are buffered by their offset so that the chunk is appended at once
+            let num_leaves = self.tree.num_leaves();
+            let mut appended = vec![];
+            for (leaf_idx, leaf) in chunk {
+                let offset = (leaf_idx as usize).checked_sub(num_leaves);
+                let previous = match offset {
+                    Some(offset) => {
+                        if offset >= appended.len() {
+                            appended.resize(offset + 1, Hash::ZERO);
+                        }
+                        std::mem::replace(&mut appended[offset], leaf)
+                    }
+                    None => {
+                        let previous = self.tree.get_leaf(leaf_idx as usize);
+                        self.tree.set_leaf(leaf_idx as usize, leaf);
+                        previous
+                    }
+                };
+
+                if self.leaves.get(&previous) == Some(&leaf_idx) {
+                    self.leaves.remove(&previous);
+                }
+                self.track_leaf(leaf, leaf_idx);
+                self.free_indices.remove(&leaf_idx);
+            }
+
+            if self.leaf_index_policy == LeafIndexPolicy::ReuseDeleted {
+                for (offset, leaf) in appended.iter().enumerate() {
+                    if *leaf == Hash::ZERO {
+                        self.free_indices.insert((num_leaves + offset) as u32);
+                    }
+                }
+            }
+
+            self.tree.extend_from_slice(&appended);
+            self.metrics.set_leaf_count(self.tree.num_leaves());
+
+            tokio::task::yield_now().await;
+        }
+
+        Ok(self.tree.root())
     }
 
     /// Removes a leaf from the tree and updates the leaves hashmap
-    pub fn remove(&mut self, index: usize) {
+    /// Returns the removed leaf, or `None` if the index was already empty
+    ///
+    /// The leaf slot in the tree storage is overwritten with zero. Note that the memory previously backing the key in
+    /// the `leaves` hashmap, any pending `tree_updates` containing the leaf and the intermediate nodes derived from it
+    /// are not scrubbed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the index is not below the number of leaves in the tree
+    pub fn remove(
+        &mut self,
+        index: usize,
+    ) -> Result<Option<Hash>, IdentityTreeError> {
+        if index >= self.tree.num_leaves() {
+            return Err(IdentityTreeError::LeafIndexOutOfRange);
+        }
+
         let leaf = self.tree.get_leaf(index);
         self.leaves.remove(&leaf);
         self.tree.set_leaf(index, Hash::ZERO);
+
+        if self.leaf_index_policy == LeafIndexPolicy::ReuseDeleted {
+            self.free_indices.insert(index as u32);
+        }
+
+        Ok((leaf != Hash::ZERO).then_some(leaf))
     }
 
     // Appends new leaf updates to the `leaves` hashmap and adds newly calculated storage nodes to `tree_updates`
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            root = ?root.hash,
+            num_leaves = tracing::field::Empty,
+            num_recomputed_nodes = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        )
+    )]
     pub fn append_updates(
         &mut self,
         root: Root,
         leaf_updates: LeafUpdates,
     ) -> Result<(), IdentityTreeError> {
+        let start_time = Instant::now();
+        let span = tracing::Span::current();
+
+        let leaves = match &leaf_updates {
+            LeafUpdates::Insert(leaves) | LeafUpdates::Delete(leaves) => leaves,
+        };
+        span.record("num_leaves", leaves.len());
+
+        // Leaf indices past the capacity of the tree can only come from a malformed update
+        if let Some(leaf_idx) = leaves
+            .keys()
+            .find(|leaf_idx| leaf_idx.0 as usize >= 1 << self.tree.depth())
+        {
+            tracing::warn!(?root.hash, ?leaf_idx, "Rejecting out of range leaf index");
+            return Err(IdentityTreeError::LeafIndexOutOfRange);
+        }
+
+        // Zero is reserved for deleted leaves, so a zero valued insert can only come from a malformed update
+        if let LeafUpdates::Insert(leaves) = &leaf_updates {
+            if let Some((leaf_idx, _)) =
+                leaves.iter().find(|(_, leaf)| **leaf == Hash::ZERO)
+            {
+                tracing::warn!(?root.hash, ?leaf_idx, "Rejecting zero leaf insert");
+                return Err(IdentityTreeError::ZeroLeafInsert);
+            }
+        }
+
         self.update_leaves(&leaf_updates);
 
-        let updates = self.construct_storage_updates(leaf_updates, None)?;
+        // Pending inserts claim their indices so that `insert` does not hand them out before the update is applied
+        if let LeafUpdates::Insert(leaves) = &leaf_updates {
+            for leaf_idx in leaves.keys() {
+                let leaf_idx: u32 = leaf_idx.into();
+                self.free_indices.remove(&leaf_idx);
+            }
+        }
+
+        let (updates, num_recomputed_nodes) =
+            self.construct_storage_updates(leaf_updates, None)?;
+        span.record("num_recomputed_nodes", num_recomputed_nodes);
+        self.metrics.record_append(num_recomputed_nodes);
         self.tree_updates.insert(root, updates);
         self.roots.insert(root.hash, root.nonce);
 
+        let elapsed_ms = start_time.elapsed().as_millis() as u64;
+        span.record("elapsed_ms", elapsed_ms);
+        self.update_stats.insert(
+            root,
+            UpdateStats {
+                elapsed_ms,
+                num_recomputed_nodes,
+            },
+        );
+
         Ok(())
     }
 
@@ -174,12 +792,18 @@ where
         match &leaf_updates {
             LeafUpdates::Insert(updates) => {
                 for (idx, val) in updates.iter() {
-                    self.leaves.insert(*val, idx.into());
+                    self.track_leaf(*val, idx.into());
                 }
             }
             LeafUpdates::Delete(updates) => {
-                for (_, val) in updates.iter() {
-                    self.leaves.remove(val);
+                // Deletions carry zero values, so remove whichever leaf currently occupies the index
+                for leaf_idx in updates.keys() {
+                    let leaf_idx: u32 = leaf_idx.into();
+                    let leaf = self.latest_leaf(leaf_idx);
+
+                    if self.leaves.get(&leaf) == Some(&leaf_idx) {
+                        self.leaves.remove(&leaf);
+                    }
                 }
             }
         }
@@ -199,7 +823,7 @@ where
     ///
     /// # Returns
     ///
-    /// `StorageUpdates` which is a hashmap of node indices to their updated values.
+    /// `StorageUpdates` which is a hashmap of node indices to their updated values, and the number of nodes recomputed from the leaf updates.
     ///
     /// # Errors
     ///
@@ -208,7 +832,7 @@ where
         &self,
         leaf_updates: LeafUpdates,
         root: Option<&Root>,
-    ) -> Result<StorageUpdates, IdentityTreeError> {
+    ) -> Result<(StorageUpdates, usize), IdentityTreeError> {
         // Get the previous update to flatten existing storage nodes into the newly updated nodes
         // If a specific root is specified, get the update at that root
         let prev_update = if let Some(root) = root {
@@ -288,25 +912,44 @@ where
             };
         }
 
+        let num_recomputed_nodes = updates.len();
+
         // Flatten any remaining updates from the previous update
         for (node_idx, hash) in prev_update {
             updates.entry(node_idx).or_insert(hash);
         }
 
-        Ok(updates)
+        Ok((updates, num_recomputed_nodes))
     }
 
     // Applies updates up to the specified root, inclusive
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            root = ?root.hash,
+            num_leaves = tracing::field::Empty,
+            num_nodes = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        )
+    )]
     pub fn apply_updates_to_root(&mut self, root: &Root) {
+        let start_time = Instant::now();
+        let span = tracing::Span::current();
+
         // Get the update at the specified root and apply to the tree
         if let Some(update) = self.tree_updates.remove(root) {
             self.roots.remove(&root.hash);
+            self.update_stats.remove(root);
+            self.record_pruned_root(root.hash);
+            span.record("num_nodes", update.len());
 
             // Filter out updates that are not leaves
+            let first_leaf_idx = leaf_to_storage_idx(0, self.tree.depth());
             let mut leaf_updates = update
                 .into_iter()
                 .filter_map(|(idx, value)| {
-                    if *idx >= 1 << self.tree.depth() {
+                    if *idx >= first_leaf_idx {
                         let leaf_idx =
                             storage_to_leaf_idx(*idx, self.tree.depth());
                         Some((leaf_idx, value))
@@ -317,25 +960,43 @@ where
                 .collect::<Vec<_>>();
 
             leaf_updates.sort_by_key(|(idx, _)| *idx);
+            span.record("num_leaves", leaf_updates.len());
 
-            // Partition the leaf updates into insertions and deletions
-            let (insertions, deletions): (Vec<Hash>, Vec<usize>) = leaf_updates
-                .into_par_iter()
-                .partition_map(|(leaf_idx, value)| {
-                    if value != Hash::ZERO {
-                        Either::Left(value)
+            // Deletions only free their indices once they reach the canonical tree, since `insert` writes to it directly
+            if self.leaf_index_policy == LeafIndexPolicy::ReuseDeleted {
+                for (leaf_idx, value) in leaf_updates.iter() {
+                    if *value == Hash::ZERO {
+                        self.free_indices.insert(*leaf_idx as u32);
                     } else {
-                        Either::Right(leaf_idx as usize)
+                        self.free_indices.remove(&(*leaf_idx as u32));
                     }
-                });
+                }
+            }
+
+            // Partition the leaf updates into leaves appended past the end of the tree and leaves updated in place
+            // A leaf that was inserted and then deleted while pending is appended as zero to keep the indices contiguous
+            let num_leaves = self.tree.num_leaves();
+            let (insertions, updates): (Vec<Hash>, Vec<(usize, Hash)>) =
+                leaf_updates.into_par_iter().partition_map(
+                    |(leaf_idx, value)| {
+                        if leaf_idx as usize >= num_leaves {
+                            Either::Left(value)
+                        } else {
+                            Either::Right((leaf_idx as usize, value))
+                        }
+                    },
+                );
 
             // Insert/delete leaves in the canonical tree
             // Note that the leaves are inserted/removed from the leaves hashmap when the updates are first applied to tree_updates
             self.tree.extend_from_slice(&insertions);
 
-            for leaf_idx in deletions {
-                self.tree.set_leaf(leaf_idx, Hash::ZERO);
+            for (leaf_idx, value) in updates {
+                self.tree.set_leaf(leaf_idx, value);
             }
+
+            self.metrics.set_leaf_count(self.tree.num_leaves());
+            self.canonical_root = Some(*root);
         }
 
         // Split off tree updates at the new root
@@ -343,633 +1004,3420 @@ where
         let current_tree_updates = self.tree_updates.split_off(root);
 
         // Clean up any roots that are no longer needed
-        for root in self.tree_updates.keys() {
+        let pruned =
+            std::mem::replace(&mut self.tree_updates, current_tree_updates);
+        for root in pruned.keys() {
             self.roots.remove(&root.hash);
+            self.update_stats.remove(root);
+            self.record_pruned_root(root.hash);
         }
 
-        self.tree_updates = current_tree_updates;
-    }
+        self.maybe_rebuild_leaf_filter();
 
-    /// Construct an inclusion proof for a given leaf
-    /// If a root is provided, the proof is constructed from the specified root
-    /// Otherwise, the proof is constructed from the current canonical tree
-    pub fn inclusion_proof(
-        &self,
-        leaf: Hash,
-        root: Option<&Root>,
-    ) -> Result<Option<InclusionProof>, IdentityTreeError> {
-        let leaf_idx = match self.leaves.get(&leaf) {
-            Some(idx) => idx,
-            None => return Err(IdentityTreeError::LeafNotFound),
-        };
+        span.record("elapsed_ms", start_time.elapsed().as_millis() as u64);
+    }
 
-        let inclusion_proof = if let Some(root) = root {
-            if root.hash == self.tree.root() {
-                let proof = self.tree.proof(*leaf_idx as usize);
-                InclusionProof::new(self.tree.root(), proof)
-            } else {
-                let proof = self.construct_proof_from_root(*leaf_idx, root)?;
-                InclusionProof::new(root.hash, proof)
-            }
-        } else {
-            if *leaf_idx as usize > self.tree.num_leaves() {
-                return Ok(None);
-            }
+    /// Applies all pending updates up to and including the latest root in `tree_updates` to the canonical tree
+    /// Returns the new canonical root, or `None` if there were no pending updates
+    pub fn apply_all_pending(&mut self) -> Option<Root> {
+        // Since each update is flattened into the next, applying the latest root applies every pending update
+        let latest_root = *self.tree_updates.keys().last()?;
+        self.apply_updates_to_root(&latest_root);
 
-            let proof = self.tree.proof(*leaf_idx as usize);
-            InclusionProof::new(self.tree.root(), proof)
-        };
+        Some(latest_root)
+    }
 
-        if !inclusion_proof.verify(leaf) {
-            return Err(IdentityTreeError::InvalidProofCorruptedTree);
-        }
+    /// Returns the `append_updates` stats of each pending root, ordered by nonce
+    /// Roots loaded into `tree_updates` without `append_updates`, e.g. when syncing on startup, have no stats
+    pub fn pending_update_stats(&self) -> Vec<(Root, UpdateStats)> {
+        self.tree_updates
+            .keys()
+            .filter_map(|root| Some((*root, *self.update_stats.get(root)?)))
+            .collect()
+    }
 
-        Ok(Some(inclusion_proof))
+    /// Returns the number of pending roots that have not been applied to the canonical tree yet
+    pub fn canonical_lag(&self) -> usize {
+        self.tree_updates.len()
     }
 
-    /// Construct an inclusion proof for a given leaf at a specified root
-    pub fn construct_proof_from_root(
-        &self,
-        leaf_idx: u32,
-        root: &Root,
-    ) -> Result<Proof, IdentityTreeError> {
-        // Get the updates at the specified root
-        let updates = self
-            .tree_updates
-            .get(root)
-            .ok_or(IdentityTreeError::RootNotFound)?;
+    /// Returns an estimate of the memory used by the pending `tree_updates`, in bytes
+    pub fn memory_usage(&self) -> usize {
+        let node_size =
+            std::mem::size_of::<NodeIndex>() + std::mem::size_of::<Hash>();
 
-        // Convert the leaf index to a storage index for easier indexing
-        let mut node_idx = leaf_to_storage_idx(leaf_idx, self.tree.depth());
+        self.tree_updates
+            .values()
+            .map(|updates| {
+                std::mem::size_of::<Root>() + updates.len() * node_size
+            })
+            .sum()
+    }
 
-        let mut proof: Vec<Branch<Hash>> = vec![];
+    /// Returns the root represented by the canonical tree, if known
+    pub fn canonical_root(&self) -> Option<Root> {
+        self.canonical_root
+    }
 
-        // Traverse the tree from the leaf to the root, constructing the proof along the way with precedence for the updated node values
-        while node_idx > 0 {
-            let sibling_idx = if node_idx % 2 == 0 {
-                node_idx - 1
-            } else {
-                node_idx + 1
-            };
+    /// Returns the number of leaves in the tree at the latest pending root, including deleted leaves
+    pub fn latest_num_leaves(&self) -> usize {
+        self.tree_updates
+            .keys()
+            .last()
+            .and_then(|root| self.num_leaves_at(root).ok())
+            .unwrap_or_else(|| self.tree.num_leaves())
+    }
 
-            // Check if the sibling is in the updates, otherwise get the node from the tree
-            let sibling = updates
-                .get(&sibling_idx.into())
+    /// Returns the value of a leaf in the latest pending update if present, otherwise in the canonical tree
+    fn latest_leaf(&self, leaf_idx: u32) -> Hash {
+        // Note that each update is flattened, so the latest update contains all pending leaves
+        self.tree_updates
+            .values()
+            .last()
+            .and_then(|updates| {
+                updates
+                    .get(
+                        &leaf_to_storage_idx(leaf_idx, self.tree.depth())
+                            .into(),
+                    )
+                    .copied()
+            })
+            .unwrap_or_else(|| {
+                if (leaf_idx as usize) < self.tree.num_leaves() {
+                    self.tree.get_leaf(leaf_idx as usize)
+                } else {
+                    Hash::ZERO
+                }
+            })
+    }
+
+    /// Verifies that every entry in the leaves hashmap matches the leaf at its index in the canonical tree or
+    /// pending updates, and that no two leaves share an index. If `recompute_root` is set, the root of the canonical
+    /// tree is also recomputed from its leaves, which is expensive for large trees.
+    pub fn self_check(
+        &self,
+        recompute_root: bool,
+    ) -> Result<(), IdentityTreeError> {
+        let mut indices = HashSet::with_capacity(self.leaves.len());
+
+        for (leaf, leaf_idx) in self.leaves.iter() {
+            if !indices.insert(*leaf_idx) {
+                return Err(IdentityTreeError::SelfCheckFailed(format!(
+                    "Multiple leaves map to index {leaf_idx}"
+                )));
+            }
+
+            let expected = self.latest_leaf(*leaf_idx);
+            if expected != *leaf {
+                return Err(IdentityTreeError::SelfCheckFailed(format!(
+                    "Leaf {leaf:?} maps to index {leaf_idx} which holds {expected:?}"
+                )));
+            }
+        }
+
+        if recompute_root {
+            let leaves = self.tree.leaves().collect::<Vec<_>>();
+            let recomputed: CascadingMerkleTree<PoseidonHash> =
+                CascadingMerkleTree::new_with_leaves(
+                    vec![],
+                    self.tree.depth(),
+                    &Hash::ZERO,
+                    &leaves,
+                );
+
+            if recomputed.root() != self.tree.root() {
+                return Err(IdentityTreeError::SelfCheckFailed(format!(
+                    "Recomputed root {:?} does not match the tree root {:?}",
+                    recomputed.root(),
+                    self.tree.root()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recomputes every internal node in the storage updates of a pending root from its children and checks that the
+    /// stored hashes match. Children are read from the same updates, falling back to the canonical tree, since each update
+    /// contains every node changed since the canonical tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidStorageUpdate` with the deepest mismatching node, so that a corrupted node is reported rather than
+    /// its parent.
+    pub fn validate_updates(
+        &self,
+        root: &Root,
+    ) -> Result<(), IdentityTreeError> {
+        let updates = self
+            .tree_updates
+            .get(root)
+            .ok_or(IdentityTreeError::RootNotFound)?;
+
+        if updates.get(&NodeIndex(0)) != Some(&root.hash) {
+            return Err(IdentityTreeError::StorageUpdatesRootMismatch);
+        }
+
+        let get_node = |node_idx: u32| {
+            updates
+                .get(&NodeIndex(node_idx))
                 .copied()
-                .or_else(|| {
+                .unwrap_or_else(|| {
                     let (depth, offset) =
-                        storage_idx_to_coords(sibling_idx as usize);
-                    Some(self.tree.get_node(depth, offset))
+                        storage_idx_to_coords(node_idx as usize);
+                    self.tree.get_node(depth, offset)
                 })
-                .expect("Could not find node in tree");
+        };
 
-            // Add the sibling to the proof and adjust the node index
-            proof.push(if node_idx % 2 == 0 {
-                Branch::Right(sibling)
-            } else {
-                Branch::Left(sibling)
-            });
+        // Nodes deeper in the tree always have a higher storage index
+        let first_leaf_idx = leaf_to_storage_idx(0, self.tree.depth());
+        let mut internal_nodes = updates
+            .iter()
+            .filter(|(node_idx, _)| node_idx.0 < first_leaf_idx)
+            .map(|(node_idx, hash)| (node_idx.0, *hash))
+            .collect::<Vec<_>>();
+        internal_nodes.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
 
-            node_idx = (node_idx - 1) / 2;
+        for (node_idx, hash) in internal_nodes {
+            let expected = PoseidonHash::hash_node(
+                &get_node(node_idx * 2 + 1),
+                &get_node(node_idx * 2 + 2),
+            );
+
+            if hash != expected {
+                return Err(IdentityTreeError::InvalidStorageUpdate {
+                    node_idx,
+                });
+            }
         }
 
-        Ok(semaphore::merkle_tree::Proof(proof))
+        Ok(())
     }
 
-    // Computes the updated root hash from a list of new leaves
-    pub fn compute_root(
-        &self,
-        leaves: &[Hash],
-        root: Option<&Root>,
-    ) -> Result<Hash, IdentityTreeError> {
-        let next_leaf_index = self.tree.num_leaves();
+    /// Rebuilds the internal nodes in the storage updates of a pending root from the updated leaves, replacing the stored
+    /// updates once the rebuilt nodes hash to the root. Use this to repair updates that fail `validate_updates`.
+    ///
+    /// Sibling nodes that are not part of the updates are read from the canonical tree, so the leaves in the updates are
+    /// trusted and only the internal nodes are recomputed.
+    pub fn recompute_updates(&mut self, root: &Root) -> eyre::Result<()> {
+        let updates = self
+            .tree_updates
+            .get(root)
+            .ok_or(IdentityTreeError::RootNotFound)?;
 
-        let leaf_updates = leaves
+        let depth = self.tree.depth();
+        let first_leaf_idx = leaf_to_storage_idx(0, depth);
+
+        let mut recomputed: StorageUpdates = updates
             .iter()
-            .enumerate()
-            .map(|(idx, value)| {
-                (LeafIndex((next_leaf_index + idx) as u32), *value)
+            .filter(|(node_idx, _)| node_idx.0 >= first_leaf_idx)
+            .map(|(node_idx, hash)| (*node_idx, *hash))
+            .collect();
+
+        let get_node = |updates: &StorageUpdates, node_idx: u32| {
+            updates
+                .get(&NodeIndex(node_idx))
+                .copied()
+                .unwrap_or_else(|| {
+                    let (depth, offset) =
+                        storage_idx_to_coords(node_idx as usize);
+                    self.tree.get_node(depth, offset)
+                })
+        };
+
+        // Hash each level of the updated nodes into their parents, up to the root
+        let mut level = recomputed
+            .keys()
+            .map(|node_idx| node_idx.0)
+            .collect::<BTreeSet<_>>();
+        for _ in 0..depth {
+            let parents = level
+                .iter()
+                .map(|node_idx| (node_idx - 1) / 2)
+                .collect::<BTreeSet<_>>();
+
+            for parent_idx in parents.iter() {
+                let hash = PoseidonHash::hash_node(
+                    &get_node(&recomputed, parent_idx * 2 + 1),
+                    &get_node(&recomputed, parent_idx * 2 + 2),
+                );
+                recomputed.insert(NodeIndex(*parent_idx), hash);
+            }
+
+            level = parents;
+        }
+
+        let recomputed_root = recomputed.get(&NodeIndex(0)).copied();
+        if recomputed_root != Some(root.hash) {
+            eyre::bail!(
+                "Recomputed root {recomputed_root:?} does not match {:?}, the updated leaves are corrupted",
+                root.hash
+            );
+        }
+
+        let repaired_nodes = recomputed
+            .iter()
+            .filter(|(node_idx, hash)| updates.get(node_idx) != Some(hash))
+            .count()
+            + updates
+                .keys()
+                .filter(|node_idx| !recomputed.contains_key(node_idx))
+                .count();
+
+        tracing::warn!(?root, repaired_nodes, "Recomputed storage updates");
+
+        self.tree_updates.insert(*root, recomputed);
+
+        Ok(())
+    }
+
+    /// Repairs the leaves hashmap after it has desynchronized from the canonical tree and `tree_updates`,
+    /// e.g. after an unclean shutdown between `append_updates` and `apply_updates_to_root`.
+    /// The value at each index is taken from the latest pending update if present, otherwise from the canonical tree.
+    pub fn reconcile(&mut self) -> ReconcileReport {
+        let depth = self.tree.depth();
+        let num_leaves = self.tree.num_leaves();
+        let first_leaf_idx = leaf_to_storage_idx(0, depth);
+
+        // Note that each update is flattened, so the latest update contains all pending leaves
+        let pending = self.tree_updates.values().last();
+        let expected_leaf = |leaf_idx: u32| self.latest_leaf(leaf_idx);
+
+        let mut removed = self
+            .leaves
+            .iter()
+            .filter(|(leaf, leaf_idx)| expected_leaf(**leaf_idx) != **leaf)
+            .map(|(leaf, leaf_idx)| (*leaf_idx, *leaf))
+            .collect::<Vec<_>>();
+        removed.sort_unstable();
+
+        let pending_indices = pending
+            .into_iter()
+            .flat_map(|updates| updates.keys())
+            .filter(|node_idx| ***node_idx >= first_leaf_idx)
+            .map(|node_idx| storage_to_leaf_idx(**node_idx, depth));
+
+        let restored = (0..num_leaves as u32)
+            .chain(pending_indices)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|leaf_idx| {
+                let leaf = expected_leaf(leaf_idx);
+
+                (leaf != Hash::ZERO
+                    && self.leaves.get(&leaf) != Some(&leaf_idx))
+                .then_some((leaf_idx, leaf))
             })
-            .collect::<HashMap<LeafIndex, Hash>>();
+            .collect::<Vec<_>>();
 
-        let mut storage_updates = self.construct_storage_updates(
-            LeafUpdates::Insert(leaf_updates),
-            root,
-        )?;
+        for (leaf_idx, leaf) in removed.iter() {
+            tracing::warn!(leaf_idx, ?leaf, "Removing desynchronized leaf");
+            self.leaves.remove(leaf);
+        }
 
-        let updated_root = storage_updates
-            .remove(&NodeIndex(0))
-            .ok_or(IdentityTreeError::RootNotFound)?;
+        for (leaf_idx, leaf) in restored.iter() {
+            tracing::warn!(leaf_idx, ?leaf, "Restoring missing leaf");
+            self.track_leaf(*leaf, *leaf_idx);
+        }
 
-        Ok(updated_root)
+        ReconcileReport { removed, restored }
     }
-}
 
-/// Flattens leaf updates into a single vector of leaf indices and hashes with precedence given to the latest updates
-pub fn flatten_leaf_updates(
-    leaf_updates: BTreeMap<Root, LeafUpdates>,
-) -> Vec<(LeafIndex, Hash)> {
-    let mut flattened_updates = HashMap::new();
+    /// Records the hash of the transaction that produced a root, retaining the `ROOT_TX_RETENTION` most recently recorded
+    /// transactions so that roots remain resolvable after they are applied to the canonical tree
+    pub fn record_root_tx(&mut self, tx_hash: H256, root: Root) {
+        if self.root_txs.insert(tx_hash, root).is_none() {
+            self.root_tx_order.push_back(tx_hash);
+        }
 
-    // Iterate in reverse over the sub-tree to ensure the latest updates are applied first
-    for (_, leaves) in leaf_updates.into_iter().rev() {
-        let updates: Leaves = leaves.into();
+        while self.root_tx_order.len() > ROOT_TX_RETENTION {
+            if let Some(evicted) = self.root_tx_order.pop_front() {
+                self.root_txs.remove(&evicted);
+            }
+        }
+    }
 
-        for (index, hash) in updates.into_iter() {
-            flattened_updates.entry(index).or_insert(hash);
+    /// Marks a root as pruned, retaining the `PRUNED_ROOT_RETENTION` most recently pruned roots
+    fn record_pruned_root(&mut self, hash: Hash) {
+        if self.pruned_roots.insert(hash) {
+            self.pruned_root_order.push_back(hash);
+        }
+
+        while self.pruned_root_order.len() > PRUNED_ROOT_RETENTION {
+            if let Some(evicted) = self.pruned_root_order.pop_front() {
+                self.pruned_roots.remove(&evicted);
+            }
         }
     }
 
-    let mut updates = flattened_updates.into_iter().collect::<Vec<_>>();
-    updates.sort_by_key(|(idx, _)| *idx);
+    /// Returns the root produced by the transaction with hash `tx_hash`, if it is among the retained transactions
+    pub fn root_by_tx(&self, tx_hash: H256) -> Option<Root> {
+        self.root_txs.get(&tx_hash).copied()
+    }
 
-    updates
-}
+    /// Returns the full `Root` for a root hash that is canonical or pending in `tree_updates`
+    /// This allows clients holding only a proof to recover the nonce of the root it was generated against
+    pub fn root_info(&self, root_hash: &Hash) -> Option<Root> {
+        if let Some(root) = self
+            .canonical_root
+            .filter(|canonical_root| canonical_root.hash == *root_hash)
+        {
+            return Some(root);
+        }
 
-pub enum LeafUpdates {
-    Insert(Leaves),
-    Delete(Leaves),
-}
+        self.roots
+            .get(root_hash)
+            .map(|nonce| Root::new(*root_hash, *nonce))
+    }
 
-impl From<LeafUpdates> for Leaves {
-    fn from(val: LeafUpdates) -> Self {
-        match val {
-            LeafUpdates::Insert(leaves) => leaves,
-            LeafUpdates::Delete(leaves) => leaves,
+    /// Returns whether a root hash is the canonical root, pending in `tree_updates`, pruned or unknown
+    pub fn root_status(&self, hash: &Hash) -> RootStatus {
+        if *hash == self.tree.root() {
+            RootStatus::Canonical
+        } else if self.tree_updates.keys().any(|root| root.hash == *hash) {
+            RootStatus::Pending
+        } else if self.pruned_roots.contains(hash) {
+            RootStatus::Pruned
+        } else {
+            RootStatus::Unknown
         }
     }
-}
 
-pub fn leaf_to_storage_idx(leaf_idx: u32, tree_depth: usize) -> u32 {
-    let leaf_0 = (1 << tree_depth) - 1;
-    leaf_0 + leaf_idx
-}
+    /// Returns `true` if the leaf filter rules out the leaf, so that absent leaves skip the leaves hashmap
+    /// A possible hit, or a disabled filter, falls through to the hashmap
+    fn filter_excludes(&self, leaf: &Hash) -> bool {
+        self.leaf_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.might_contain(leaf))
+    }
 
-pub fn storage_to_leaf_idx(storage_idx: u32, tree_depth: usize) -> u32 {
-    let leaf_0 = (1 << tree_depth) - 1;
-    storage_idx - leaf_0
-}
+    /// Construct an inclusion proof for a given leaf against the canonical root, ignoring any pending updates
+    /// Returns `None` if the leaf has not been applied to the canonical tree yet
+    pub fn finalized_inclusion_proof(
+        &self,
+        leaf: Hash,
+    ) -> Result<Option<InclusionProof>, IdentityTreeError> {
+        if self.canonical_root.is_none() {
+            return Err(IdentityTreeError::NoFinalizedRoot);
+        }
 
-pub fn storage_idx_to_coords(index: usize) -> (usize, usize) {
-    let depth = (index + 1).ilog2();
-    let offset = index - (2usize.pow(depth) - 1);
-    (depth as usize, offset)
-}
+        if self.filter_excludes(&leaf) {
+            return Err(IdentityTreeError::LeafNotFound);
+        }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
-pub struct Root {
-    pub hash: Hash,
-    //NOTE: note that this assumes that there is only one wallet that sequences transactions
-    // we should update to a syncing mechanism that can account for multiple sequencers
-    pub nonce: usize,
-}
+        // A leaf updated in place by a pending root is tracked at its index before the canonical tree holds it
+        let leaf_idx = *self
+            .leaves
+            .get(&leaf)
+            .ok_or(IdentityTreeError::LeafNotFound)?
+            as usize;
+        if leaf_idx < self.tree.num_leaves()
+            && self.tree.get_leaf(leaf_idx) != leaf
+        {
+            return Ok(None);
+        }
+
+        // Proofs against the canonical tree are verified and recorded the same way as any other proof
+        self.inclusion_proof(leaf, None)
+    }
+
+    /// Construct inclusion proofs against the canonical root for multiple leaves, preserving the order of the leaves
+    /// Leaves that are not in the canonical tree have no proof
+    pub fn finalized_inclusion_proofs(
+        &self,
+        leaves: &[Hash],
+    ) -> Result<Vec<Option<InclusionProof>>, IdentityTreeError> {
+        leaves
+            .iter()
+            .map(|leaf| match self.finalized_inclusion_proof(*leaf) {
+                Err(IdentityTreeError::LeafNotFound) => Ok(None),
+                result => result,
+            })
+            .collect()
+    }
+
+    /// Construct inclusion proofs for multiple leaves, preserving the order of the leaves
+    /// Leaves that are not in the tree have no proof
+    pub fn inclusion_proofs(
+        &self,
+        leaves: &[Hash],
+        root: Option<&Root>,
+    ) -> Result<Vec<Option<InclusionProof>>, IdentityTreeError> {
+        leaves
+            .iter()
+            .map(|leaf| match self.inclusion_proof(*leaf, root) {
+                Err(IdentityTreeError::LeafNotFound) => Ok(None),
+                result => result,
+            })
+            .collect()
+    }
+
+    /// Returns the leaf at the given index, or `None` if the index is empty or out of range
+    /// If a root is provided, the leaf is read at the specified root, otherwise from the current canonical tree
+    pub fn get_leaf(
+        &self,
+        leaf_idx: u32,
+        root: Option<&Root>,
+    ) -> Result<Option<Hash>, IdentityTreeError> {
+        let depth = self.tree.depth();
+        if leaf_idx as usize >= 1 << depth {
+            return Ok(None);
+        }
+
+        let updated_leaf = match root {
+            Some(root) if root.hash != self.tree.root() => self
+                .updates_at_root(root)?
+                .get(&leaf_to_storage_idx(leaf_idx, depth).into())
+                .copied(),
+            _ => None,
+        };
+
+        let leaf = updated_leaf.unwrap_or_else(|| {
+            if (leaf_idx as usize) < self.tree.num_leaves() {
+                self.tree.get_leaf(leaf_idx as usize)
+            } else {
+                Hash::ZERO
+            }
+        });
+
+        Ok((leaf != Hash::ZERO).then_some(leaf))
+    }
+
+    /// Construct an inclusion proof for a given leaf
+    /// If a root is provided, the proof is constructed from the specified root
+    /// Otherwise, the proof is constructed from the current canonical tree
+    pub fn inclusion_proof(
+        &self,
+        leaf: Hash,
+        root: Option<&Root>,
+    ) -> Result<Option<InclusionProof>, IdentityTreeError> {
+        let start_time = Instant::now();
+
+        if self.filter_excludes(&leaf) {
+            return Err(IdentityTreeError::LeafNotFound);
+        }
+
+        let leaf_idx = match self.leaves.get(&leaf) {
+            Some(idx) => idx,
+            None => return Err(IdentityTreeError::LeafNotFound),
+        };
+
+        let inclusion_proof = if let Some(root) = root {
+            if root.hash == self.tree.root() {
+                let proof = self.tree.proof(*leaf_idx as usize);
+                InclusionProof::new(self.tree.root(), proof)
+            } else {
+                let proof = self.construct_proof_from_root(*leaf_idx, root)?;
+                InclusionProof::new(root.hash, proof)
+            }
+        } else {
+            if *leaf_idx as usize >= self.tree.num_leaves() {
+                return Ok(None);
+            }
+
+            let proof = self.tree.proof(*leaf_idx as usize);
+            InclusionProof::new(self.tree.root(), proof)
+        };
+
+        if !inclusion_proof.verify(leaf) {
+            return Err(IdentityTreeError::InvalidProofCorruptedTree);
+        }
+
+        self.metrics.record_proof(start_time.elapsed());
+
+        Ok(Some(inclusion_proof))
+    }
+
+    /// Returns the storage updates at the specified root
+    fn updates_at_root(
+        &self,
+        root: &Root,
+    ) -> Result<&StorageUpdates, IdentityTreeError> {
+        self.tree_updates.get(root).ok_or_else(|| {
+            if self.pruned_roots.contains(&root.hash) {
+                IdentityTreeError::RootPruned
+            } else {
+                IdentityTreeError::RootNotFound
+            }
+        })
+    }
+
+    /// Returns the number of leaves in the tree at the specified root, including deleted leaves
+    fn num_leaves_at(&self, root: &Root) -> Result<usize, IdentityTreeError> {
+        if root.hash == self.tree.root() {
+            return Ok(self.tree.num_leaves());
+        }
+
+        let depth = self.tree.depth();
+        let first_leaf_idx = leaf_to_storage_idx(0, depth);
+
+        // Leaves are appended contiguously, so the highest updated leaf index marks the end of the tree
+        let num_leaves = self
+            .updates_at_root(root)?
+            .keys()
+            .filter(|node_idx| ***node_idx >= first_leaf_idx)
+            .map(|node_idx| storage_to_leaf_idx(**node_idx, depth) as usize + 1)
+            .max()
+            .unwrap_or_default();
+
+        Ok(num_leaves.max(self.tree.num_leaves()))
+    }
+
+    /// Construct a proof that `new` only appends leaves to `old`, leaving every leaf that exists at `old` unchanged
+    /// The proof is the path of the first empty leaf at `old`, resolved at `new`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotAppendOnly` if `new` precedes `old`, or if any leaf present at `old` is updated or deleted at `new`
+    pub fn consistency_proof(
+        &self,
+        old: &Root,
+        new: &Root,
+    ) -> Result<ConsistencyProof, IdentityTreeError> {
+        if old.nonce > new.nonce {
+            return Err(IdentityTreeError::NotAppendOnly);
+        }
+
+        let depth = self.tree.depth();
+        let old_num_leaves = self.num_leaves_at(old)? as u32;
+
+        // Reject transitions that modify leaves which already existed at the old root
+        if new.hash != self.tree.root() {
+            let first_leaf_idx = leaf_to_storage_idx(0, depth);
+
+            for node_idx in self.updates_at_root(new)?.keys() {
+                if **node_idx < first_leaf_idx {
+                    continue;
+                }
+
+                let leaf_idx = storage_to_leaf_idx(**node_idx, depth);
+                if leaf_idx < old_num_leaves
+                    && self.get_leaf(leaf_idx, Some(old))?
+                        != self.get_leaf(leaf_idx, Some(new))?
+                {
+                    return Err(IdentityTreeError::NotAppendOnly);
+                }
+            }
+        }
+
+        // A full tree can not be appended to, so the roots must be the same
+        let (leaf, siblings) = if old_num_leaves as usize >= 1 << depth {
+            (Hash::ZERO, vec![])
+        } else {
+            let leaf = self
+                .get_leaf(old_num_leaves, Some(new))?
+                .unwrap_or(Hash::ZERO);
+            let siblings = self
+                .paths(old_num_leaves, old_num_leaves + 1, Some(new))?
+                .pop()
+                .unwrap_or_default();
+
+            (leaf, siblings)
+        };
+
+        Ok(ConsistencyProof {
+            old_root: old.hash,
+            new_root: new.hash,
+            depth,
+            old_num_leaves,
+            leaf,
+            siblings,
+        })
+    }
+
+    /// Construct a single proof for multiple leaves, deduplicating the sibling nodes shared between their paths
+    /// If a root is provided, the proof is constructed from the specified root
+    /// Otherwise, the proof is constructed from the current canonical tree, returning `None` if any leaf is still pending
+    pub fn multi_proof(
+        &self,
+        leaves: &[Hash],
+        root: Option<&Root>,
+    ) -> Result<Option<MultiProof>, IdentityTreeError> {
+        let depth = self.tree.depth();
+
+        let (root_hash, updates) = match root {
+            Some(root) if root.hash != self.tree.root() => {
+                (root.hash, Some(self.updates_at_root(root)?))
+            }
+            _ => (self.tree.root(), None),
+        };
+
+        let leaf_indices = leaves
+            .iter()
+            .map(|leaf| {
+                self.leaves
+                    .get(leaf)
+                    .copied()
+                    .ok_or(IdentityTreeError::LeafNotFound)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Leaves past the end of the canonical tree only exist in pending updates
+        if updates.is_none()
+            && leaf_indices
+                .iter()
+                .any(|leaf_idx| *leaf_idx as usize >= self.tree.num_leaves())
+        {
+            return Ok(None);
+        }
+
+        // Get the node from the updates if present, otherwise from the canonical tree
+        let get_node = |node_idx: u32| {
+            updates
+                .and_then(|updates| updates.get(&node_idx.into()).copied())
+                .unwrap_or_else(|| {
+                    let (depth, offset) =
+                        storage_idx_to_coords(node_idx as usize);
+                    self.tree.get_node(depth, offset)
+                })
+        };
+
+        // Traverse the tree level by level from the leaves to the root, only including siblings that cannot be computed from the proven leaves
+        let mut level = leaf_indices
+            .iter()
+            .map(|leaf_idx| leaf_to_storage_idx(*leaf_idx, depth))
+            .collect::<BTreeSet<_>>();
+        let mut nodes = BTreeMap::new();
+
+        for _ in 0..depth {
+            for node_idx in level.iter() {
+                let sibling_idx = if node_idx % 2 == 0 {
+                    node_idx - 1
+                } else {
+                    node_idx + 1
+                };
+
+                if !level.contains(&sibling_idx) {
+                    nodes.insert(sibling_idx, get_node(sibling_idx));
+                }
+            }
+
+            level = level.iter().map(|node_idx| (node_idx - 1) / 2).collect();
+        }
+
+        let multi_proof = MultiProof {
+            root: root_hash,
+            depth,
+            leaf_indices,
+            nodes,
+        };
+
+        if !multi_proof.verify(leaves) {
+            return Err(IdentityTreeError::InvalidProofCorruptedTree);
+        }
+
+        Ok(Some(multi_proof))
+    }
+
+    /// Returns the sibling hashes ordered from the leaf to the root for each leaf index in `from..to`
+    /// If a root is provided, the siblings are resolved at the specified root, otherwise from the canonical tree
+    pub fn paths(
+        &self,
+        from: u32,
+        to: u32,
+        root: Option<&Root>,
+    ) -> Result<Vec<Vec<Hash>>, IdentityTreeError> {
+        let depth = self.tree.depth();
+        if from > to || to as usize > 1 << depth {
+            return Err(IdentityTreeError::LeafIndexOutOfRange);
+        }
+
+        // Resolve the updates once for the whole range
+        let updates = match root {
+            Some(root) if root.hash != self.tree.root() => {
+                Some(self.updates_at_root(root)?)
+            }
+            _ => None,
+        };
+
+        let get_node = |node_idx: u32| {
+            updates
+                .and_then(|updates| updates.get(&node_idx.into()).copied())
+                .unwrap_or_else(|| {
+                    let (depth, offset) =
+                        storage_idx_to_coords(node_idx as usize);
+                    self.tree.get_node(depth, offset)
+                })
+        };
+
+        let paths = (from..to)
+            .map(|leaf_idx| {
+                let mut node_idx = leaf_to_storage_idx(leaf_idx, depth);
+                let mut siblings = Vec::with_capacity(depth);
+
+                while node_idx > 0 {
+                    let sibling_idx = if node_idx % 2 == 0 {
+                        node_idx - 1
+                    } else {
+                        node_idx + 1
+                    };
+
+                    siblings.push(get_node(sibling_idx));
+                    node_idx = (node_idx - 1) / 2;
+                }
+
+                siblings
+            })
+            .collect();
+
+        Ok(paths)
+    }
+
+    /// Construct an inclusion proof for a given leaf at a specified root
+    pub fn construct_proof_from_root(
+        &self,
+        leaf_idx: u32,
+        root: &Root,
+    ) -> Result<Proof, IdentityTreeError> {
+        // Get the updates at the specified root
+        let updates = self.updates_at_root(root)?;
+
+        // Convert the leaf index to a storage index for easier indexing
+        let mut node_idx = leaf_to_storage_idx(leaf_idx, self.tree.depth());
+
+        let mut proof: Vec<Branch<Hash>> = vec![];
+        let mut num_fallbacks = 0;
+
+        // Traverse the tree from the leaf to the root, constructing the proof along the way with precedence for the updated node values
+        while node_idx > 0 {
+            let sibling_idx = if node_idx % 2 == 0 {
+                node_idx - 1
+            } else {
+                node_idx + 1
+            };
+
+            // Check if the sibling is in the updates, otherwise get the node from the tree
+            let sibling = match updates.get(&sibling_idx.into()) {
+                Some(sibling) => *sibling,
+                None => {
+                    num_fallbacks += 1;
+
+                    let (depth, offset) =
+                        storage_idx_to_coords(sibling_idx as usize);
+                    self.tree.get_node(depth, offset)
+                }
+            };
+
+            // Add the sibling to the proof and adjust the node index
+            proof.push(if node_idx % 2 == 0 {
+                Branch::Right(sibling)
+            } else {
+                Branch::Left(sibling)
+            });
+
+            node_idx = (node_idx - 1) / 2;
+        }
+
+        self.sibling_resolutions
+            .updates
+            .fetch_add((proof.len() - num_fallbacks) as u64, Ordering::Relaxed);
+        self.sibling_resolutions
+            .canonical_tree
+            .fetch_add(num_fallbacks as u64, Ordering::Relaxed);
+
+        if num_fallbacks > PROOF_FALLBACK_WARN_THRESHOLD {
+            tracing::warn!(
+                ?root.hash,
+                leaf_idx,
+                num_fallbacks,
+                "Proof siblings resolved from the canonical tree exceed threshold"
+            );
+        }
+
+        Ok(semaphore::merkle_tree::Proof(proof))
+    }
+
+    // Computes the updated root hash from a list of new leaves
+    // The leaves are appended after the last leaf at the specified root, or at the latest root if none is specified
+    pub fn compute_root(
+        &self,
+        leaves: &[Hash],
+        root: Option<&Root>,
+    ) -> Result<Hash, IdentityTreeError> {
+        let update = match root {
+            Some(root) => Some(self.updates_at_root(root)?),
+            None => self.tree_updates.values().last(),
+        };
+
+        let depth = self.tree.depth();
+        let first_leaf_idx = leaf_to_storage_idx(0, depth);
+        let next_leaf_index = update
+            .into_iter()
+            .flat_map(|update| update.keys())
+            .filter(|node_idx| ***node_idx >= first_leaf_idx)
+            .map(|node_idx| storage_to_leaf_idx(**node_idx, depth) as usize)
+            .max()
+            .map_or(0, |leaf_idx| leaf_idx + 1)
+            .max(self.tree.num_leaves());
+
+        let leaf_updates = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                (LeafIndex((next_leaf_index + idx) as u32), *value)
+            })
+            .collect::<HashMap<LeafIndex, Hash>>();
+
+        let (mut storage_updates, _) = self.construct_storage_updates(
+            LeafUpdates::Insert(leaf_updates),
+            root,
+        )?;
+
+        let updated_root = storage_updates
+            .remove(&NodeIndex(0))
+            .ok_or(IdentityTreeError::RootNotFound)?;
+
+        Ok(updated_root)
+    }
+}
+
+/// Flattens leaf updates into a single vector of leaf indices and hashes with precedence given to the latest updates
+pub fn flatten_leaf_updates(
+    leaf_updates: BTreeMap<Root, LeafUpdates>,
+) -> Vec<(LeafIndex, Hash)> {
+    let mut flattened_updates = HashMap::new();
+
+    // Iterate in reverse over the sub-tree to ensure the latest updates are applied first
+    for (_, leaves) in leaf_updates.into_iter().rev() {
+        let updates: Leaves = leaves.into();
+
+        for (index, hash) in updates.into_iter() {
+            flattened_updates.entry(index).or_insert(hash);
+        }
+    }
+
+    let mut updates = flattened_updates.into_iter().collect::<Vec<_>>();
+    updates.sort_by_key(|(idx, _)| *idx);
+
+    updates
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum LeafUpdates {
+    Insert(Leaves),
+    Delete(Leaves),
+}
+
+impl From<LeafUpdates> for Leaves {
+    fn from(val: LeafUpdates) -> Self {
+        match val {
+            LeafUpdates::Insert(leaves) => leaves,
+            LeafUpdates::Delete(leaves) => leaves,
+        }
+    }
+}
+
+pub fn leaf_to_storage_idx(leaf_idx: u32, tree_depth: usize) -> u32 {
+    let leaf_0 = (1 << tree_depth) - 1;
+    leaf_0 + leaf_idx
+}
+
+pub fn storage_to_leaf_idx(storage_idx: u32, tree_depth: usize) -> u32 {
+    let leaf_0 = (1 << tree_depth) - 1;
+    storage_idx - leaf_0
+}
+
+pub fn storage_idx_to_coords(index: usize) -> (usize, usize) {
+    let depth = (index + 1).ilog2();
+    let offset = index - (2usize.pow(depth) - 1);
+    (depth as usize, offset)
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Root {
+    pub hash: Hash,
+    //NOTE: note that this assumes that there is only one wallet that sequences transactions
+    // we should update to a syncing mechanism that can account for multiple sequencers
+    pub nonce: usize,
+}
+
+impl Root {
+    pub fn new(hash: Hash, nonce: usize) -> Root {
+        Self { hash, nonce }
+    }
+}
+
+impl From<(Hash, usize)> for Root {
+    fn from((hash, nonce): (Hash, usize)) -> Self {
+        Self::new(hash, nonce)
+    }
+}
+
+impl Ord for Root {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.nonce.cmp(&other.nonce)
+    }
+}
+
+impl PartialOrd for Root {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InclusionProof {
+    pub root: Field,
+    pub proof: Proof,
+    /// Hash over the root and ordered siblings, only included when requested by the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Hash>,
+}
+
+impl InclusionProof {
+    pub fn new(root: Field, proof: Proof) -> InclusionProof {
+        Self {
+            root,
+            proof,
+            checksum: None,
+        }
+    }
+
+    /// Returns the index of the proven leaf, derived from the direction of each branch
+    pub fn leaf_index(&self) -> u32 {
+        self.proof
+            .0
+            .iter()
+            .enumerate()
+            .fold(0, |leaf_idx, (level, branch)| match branch {
+                Branch::Left(_) => leaf_idx,
+                Branch::Right(_) => leaf_idx | 1 << level,
+            })
+    }
+
+    /// Attaches a checksum so that clients can detect a corrupted or truncated response before verifying the proof
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = Some(self.compute_checksum());
+        self
+    }
+
+    /// Returns a hash of the root combined with each sibling and its direction, in path order
+    pub fn compute_checksum(&self) -> Hash {
+        let mut bytes = Vec::with_capacity(32 + self.proof.0.len() * 33);
+        bytes.extend_from_slice(&self.root.to_be_bytes::<32>());
+
+        for branch in self.proof.0.iter() {
+            let (direction, sibling) = match branch {
+                Branch::Left(sibling) => (0u8, sibling),
+                Branch::Right(sibling) => (1u8, sibling),
+            };
+
+            bytes.push(direction);
+            bytes.extend_from_slice(&sibling.to_be_bytes::<32>());
+        }
+
+        Hash::from_be_bytes(keccak256(bytes))
+    }
+
+    /// Returns `true` if the proof carries a checksum that matches its root and siblings.
+    /// A missing or mismatched checksum indicates a corrupted response rather than an invalid proof.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum == Some(self.compute_checksum())
+    }
+
+    pub fn verify(&self, leaf: Field) -> bool {
+        let mut hash = leaf;
+
+        for branch in self.proof.0.iter() {
+            match branch {
+                Branch::Left(sibling) => {
+                    hash = PoseidonHash::hash_node(&hash, sibling);
+                }
+                Branch::Right(sibling) => {
+                    hash = PoseidonHash::hash_node(sibling, &hash);
+                }
+            }
+        }
+
+        hash == self.root
+    }
+}
+
+// Proofs are compared by their root and ordered branches, since the checksum is derived from both
+impl PartialEq for InclusionProof {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.proof.0 == other.proof.0
+    }
+}
+
+impl Eq for InclusionProof {}
+
+impl std::hash::Hash for InclusionProof {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::hash::Hash::hash(&self.root, state);
+
+        for branch in self.proof.0.iter() {
+            let direction = match branch {
+                Branch::Left(sibling) => (0u8, sibling),
+                Branch::Right(sibling) => (1u8, sibling),
+            };
+
+            std::hash::Hash::hash(&direction, state);
+        }
+    }
+}
+
+/// Proof of inclusion for multiple leaves under the same root
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultiProof {
+    pub root: Field,
+    pub depth: usize,
+    /// Index of each proven leaf, in the order the leaves were requested
+    pub leaf_indices: Vec<u32>,
+    /// Sibling nodes that cannot be computed from the proven leaves, keyed by storage index
+    pub nodes: BTreeMap<u32, Hash>,
+}
+
+impl MultiProof {
+    /// Verifies that `leaves` are at `leaf_indices` in the tree with the proven root
+    pub fn verify(&self, leaves: &[Hash]) -> bool {
+        if leaves.len() != self.leaf_indices.len() {
+            return false;
+        }
+
+        let mut level = BTreeMap::new();
+        for (leaf_idx, leaf) in self.leaf_indices.iter().zip(leaves) {
+            let node_idx = leaf_to_storage_idx(*leaf_idx, self.depth);
+
+            // The same index can not be proven for two different leaves
+            if *level.entry(node_idx).or_insert(*leaf) != *leaf {
+                return false;
+            }
+        }
+
+        for _ in 0..self.depth {
+            let mut parents = BTreeMap::new();
+
+            for (node_idx, hash) in level.iter() {
+                let (sibling_idx, is_left) = if node_idx % 2 == 0 {
+                    (node_idx - 1, false)
+                } else {
+                    (node_idx + 1, true)
+                };
+
+                let Some(sibling) =
+                    level.get(&sibling_idx).or(self.nodes.get(&sibling_idx))
+                else {
+                    return false;
+                };
+
+                let parent = if is_left {
+                    PoseidonHash::hash_node(hash, sibling)
+                } else {
+                    PoseidonHash::hash_node(sibling, hash)
+                };
+
+                parents.insert((node_idx - 1) / 2, parent);
+            }
+
+            level = parents;
+        }
+
+        level.get(&0) == Some(&self.root)
+    }
+}
+
+/// Proof that a newer root only appends leaves to an older root
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyProof {
+    pub old_root: Hash,
+    pub new_root: Hash,
+    pub depth: usize,
+    /// Number of leaves at the old root, which is the index of the first leaf appended by the new root
+    pub old_num_leaves: u32,
+    /// Value of the leaf at `old_num_leaves` at the new root
+    pub leaf: Hash,
+    /// Siblings of the path from `old_num_leaves` to the root at the new root, ordered from the leaf to the root
+    pub siblings: Vec<Hash>,
+}
+
+impl ConsistencyProof {
+    /// Verifies that the new root extends the old root
+    /// Siblings to the left of the path cover the leaves of the old root and must hash to both roots, while siblings
+    /// to the right of the path must be empty at the old root
+    pub fn verify(&self) -> bool {
+        if self.old_num_leaves as usize >= 1 << self.depth {
+            return self.siblings.is_empty() && self.old_root == self.new_root;
+        }
+
+        if self.siblings.len() != self.depth {
+            return false;
+        }
+
+        let mut node_idx = self.old_num_leaves;
+        let mut empty = Hash::ZERO;
+        let mut old_hash = Hash::ZERO;
+        let mut new_hash = self.leaf;
+
+        for sibling in self.siblings.iter() {
+            if node_idx % 2 == 0 {
+                old_hash = PoseidonHash::hash_node(&old_hash, &empty);
+                new_hash = PoseidonHash::hash_node(&new_hash, sibling);
+            } else {
+                old_hash = PoseidonHash::hash_node(sibling, &old_hash);
+                new_hash = PoseidonHash::hash_node(sibling, &new_hash);
+            }
+
+            empty = PoseidonHash::hash_node(&empty, &empty);
+            node_idx /= 2;
+        }
+
+        old_hash == self.old_root && new_hash == self.new_root
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, Instant};
+
+    use eyre::{eyre, ContextCompat};
+    use rand::{Rng, SeedableRng};
+    use semaphore::cascading_merkle_tree::CascadingMerkleTree;
+    use semaphore::merkle_tree::Branch;
+    use semaphore::poseidon_tree::PoseidonHash;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    use super::{
+        leaf_to_storage_idx, IdentityTree, InclusionProof, LeafIndexPolicy,
+        LeafUpdates, ReconcileReport, Root, RootStatus,
+        APPEND_STREAM_CHUNK_SIZE,
+    };
+    use crate::tree::error::IdentityTreeError;
+    use crate::tree::identity_tree::{
+        storage_idx_to_coords, storage_to_leaf_idx,
+    };
+    use crate::tree::metrics::TreeMetrics;
+    use crate::tree::{Hash, LeafIndex, NodeIndex};
+
+    const TREE_DEPTH: usize = 2;
+    const NUM_LEAVES: usize = 1 << TREE_DEPTH;
+
+    fn infinite_leaves() -> impl Iterator<Item = Hash> {
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(42);
+
+        std::iter::from_fn(move || Some(random_field_elem(&mut rng)))
+    }
+
+    fn random_field_elem(rng: &mut impl Rng) -> Hash {
+        let mut limbs: [u64; 4] = rng.gen();
+        limbs[3] = 0; // nullify most significant limb to keep the values in the Field
+
+        Hash::from_limbs(limbs)
+    }
+
+    fn generate_all_leaves() -> Vec<Hash> {
+        infinite_leaves().take(NUM_LEAVES).collect()
+    }
+
+    #[test]
+    fn test_ord_root() {
+        let root_1 = Root {
+            hash: Hash::from(1),
+            nonce: 1,
+        };
+
+        let root_2 = Root {
+            hash: Hash::from(2),
+            nonce: 2,
+        };
+
+        let root_3 = Root {
+            hash: Hash::from(3),
+            nonce: 1,
+        };
+
+        assert!(root_1 < root_2);
+        assert!(root_2 > root_3);
+    }
+
+    #[test]
+    fn test_root_constructors() {
+        let root = Root {
+            hash: Hash::from(1),
+            nonce: 2,
+        };
+
+        assert_eq!(Root::new(Hash::from(1), 2), root);
+        assert_eq!(Root::from((Hash::from(1), 2)), root);
+    }
+
+    #[test]
+    fn test_leaf_to_storage_idx() {
+        for i in 0..1 << TREE_DEPTH {
+            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
+            let expected_storage_idx = (1 << TREE_DEPTH) + i - 1;
+            assert_eq!(storage_idx, expected_storage_idx);
+        }
+    }
+
+    #[test]
+    fn test_storage_to_leaf_idx() {
+        for i in 0..1 << TREE_DEPTH {
+            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
+            let leaf_idx = storage_to_leaf_idx(storage_idx, TREE_DEPTH);
+            assert_eq!(leaf_idx, i);
+        }
+    }
+
+    #[test]
+    fn test_storage_idx_to_coords() {
+        for i in 0..1 << TREE_DEPTH {
+            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
+
+            let (depth, offset) = storage_idx_to_coords(storage_idx as usize);
+
+            let expected_depth = (storage_idx + 1).ilog2();
+            let expected_offset = storage_idx - (2_u32.pow(expected_depth) - 1);
+
+            assert_eq!(depth, expected_depth as usize);
+            assert_eq!(offset, expected_offset as usize);
+        }
+    }
+
+    #[test]
+    fn test_depth_and_root() {
+        let identity_tree = IdentityTree::random(TREE_DEPTH, NUM_LEAVES, 42);
+
+        assert_eq!(identity_tree.depth(), TREE_DEPTH);
+        assert_eq!(identity_tree.root(), identity_tree.tree.root());
+    }
+
+    #[test]
+    fn test_random() {
+        let tree = IdentityTree::random(TREE_DEPTH, NUM_LEAVES, 42);
+        let same_seed = IdentityTree::random(TREE_DEPTH, NUM_LEAVES, 42);
+        let other_seed = IdentityTree::random(TREE_DEPTH, NUM_LEAVES, 43);
+
+        assert_eq!(tree.tree.num_leaves(), NUM_LEAVES);
+        assert_eq!(tree.leaves.len(), NUM_LEAVES);
+        assert_eq!(tree.tree.root(), same_seed.tree.root());
+        assert_ne!(tree.tree.root(), other_seed.tree.root());
+    }
+
+    #[test]
+    fn test_insert() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Generate new leaves and insert into the tree
+        let leaves = generate_all_leaves();
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree
+                .insert(idx as u32, *leaf)
+                .expect("Could not insert leaf");
+        }
+
+        // Initialize an expected tree with the same leaves
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        // Ensure the tree roots are equal
+        assert_eq!(identity_tree.tree.root(), expected_tree.root());
+
+        // Assert that each of the leaves are in the leaves hashmap
+        for (leaf_idx, leaf) in leaves.iter().enumerate() {
+            assert_eq!(
+                identity_tree.leaves.get(leaf),
+                Some(&(leaf_idx as u32))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_tree_full() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Indices other than the next empty index are rejected rather than tracked at the wrong slot
+        let mut leaves = infinite_leaves();
+        let leaf = leaves.next().unwrap();
+        let result = identity_tree.insert(1, leaf);
+        assert!(matches!(
+            result,
+            Err(IdentityTreeError::UnexpectedLeafIndex {
+                expected: 0,
+                found: 1
+            })
+        ));
+        assert!(!identity_tree.leaves.contains_key(&leaf));
+        assert_eq!(identity_tree.tree.num_leaves(), 0);
+
+        // Fill the tree to capacity
+        for idx in 0..NUM_LEAVES {
+            let receipt =
+                identity_tree.insert(idx as u32, leaves.next().unwrap())?;
+            assert_eq!(receipt.index, idx as u32);
+        }
+
+        // The next insert must error rather than panic
+        let leaf = leaves.next().unwrap();
+        let result = identity_tree.insert(NUM_LEAVES as u32, leaf);
+
+        assert!(matches!(result, Err(IdentityTreeError::TreeFull)));
+        assert!(!identity_tree.leaves.contains_key(&leaf));
+        assert_eq!(identity_tree.tree.num_leaves(), NUM_LEAVES);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_stream() -> eyre::Result<()> {
+        const DEPTH: usize = 12;
+        // Stream a number of leaves that does not evenly divide into chunks
+        const NUM_STREAMED_LEAVES: usize = 5 * APPEND_STREAM_CHUNK_SIZE / 2;
+
+        let leaves = infinite_leaves()
+            .take(NUM_STREAMED_LEAVES)
+            .enumerate()
+            .map(|(idx, leaf)| (idx as u32, leaf))
+            .collect::<Vec<_>>();
+
+        let mut identity_tree = IdentityTree::new(DEPTH);
+        let root = identity_tree
+            .append_stream(futures::stream::iter(leaves.clone()))
+            .await?;
+
+        let mut expected_tree = IdentityTree::new(DEPTH);
+        expected_tree.extend_from_slice(&leaves);
+
+        assert_eq!(root, expected_tree.tree.root());
+        assert_eq!(identity_tree.leaves, expected_tree.leaves);
+
+        // Streaming past the capacity of the tree fails
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let result = identity_tree
+            .append_stream(futures::stream::iter(leaves.clone()))
+            .await;
+        assert!(matches!(result, Err(IdentityTreeError::TreeFull)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_stream_indices() -> eyre::Result<()> {
+        let leaves = generate_all_leaves();
+
+        // Leaves are streamed out of order, with index 2 left empty
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let root = identity_tree
+            .append_stream(futures::stream::iter([
+                (1, leaves[1]),
+                (0, leaves[0]),
+                (3, leaves[3]),
+            ]))
+            .await?;
+
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &[leaves[0], leaves[1], Hash::ZERO, leaves[3]],
+            );
+        assert_eq!(root, expected_tree.root());
+        assert_eq!(identity_tree.leaves.get(&leaves[3]), Some(&3));
+
+        // A leaf streamed at an index already in the tree replaces it
+        let root = identity_tree
+            .append_stream(futures::stream::iter([(1, leaves[2])]))
+            .await?;
+
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &[leaves[0], leaves[2], Hash::ZERO, leaves[3]],
+            );
+        assert_eq!(root, expected_tree.root());
+        assert_eq!(identity_tree.leaves.get(&leaves[1]), None);
+        assert_eq!(identity_tree.leaves.get(&leaves[2]), Some(&1));
+
+        Ok(())
+    }
+
+    /// Streams 100k leaves and compares the root against extending the tree with the same leaves at once.
+    /// Run with `cargo test append_stream_large -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_append_stream_large() -> eyre::Result<()> {
+        const DEPTH: usize = 17;
+        const NUM_STREAMED_LEAVES: usize = 100_000;
+
+        let leaves = infinite_leaves()
+            .take(NUM_STREAMED_LEAVES)
+            .enumerate()
+            .map(|(idx, leaf)| (idx as u32, leaf))
+            .collect::<Vec<_>>();
+
+        let mut identity_tree = IdentityTree::new(DEPTH);
+        let root = identity_tree
+            .append_stream(futures::stream::iter(leaves.clone()))
+            .await?;
+
+        let mut expected_tree = IdentityTree::new(DEPTH);
+        expected_tree.extend_from_slice(&leaves);
+
+        assert_eq!(root, expected_tree.tree.root());
+        assert_eq!(identity_tree.leaves, expected_tree.leaves);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_or_update() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        // Fresh inserts are appended to the tree
+        identity_tree.insert_or_update(0, leaves[0])?;
+        identity_tree.insert_or_update(1, leaves[1])?;
+        assert_eq!(identity_tree.tree.num_leaves(), 2);
+
+        // Re-inserting the same pair is a no-op
+        let root = identity_tree.tree.root();
+        identity_tree.insert_or_update(1, leaves[1])?;
+        assert_eq!(identity_tree.tree.num_leaves(), 2);
+        assert_eq!(identity_tree.tree.root(), root);
+        assert_eq!(identity_tree.leaves.len(), 2);
+
+        // A different value at an existing index is updated in place
+        identity_tree.insert_or_update(1, leaves[2])?;
+        assert_eq!(identity_tree.tree.num_leaves(), 2);
+        assert_eq!(identity_tree.tree.get_leaf(1), leaves[2]);
+        assert_eq!(identity_tree.leaves.get(&leaves[1]), None);
+        assert_eq!(identity_tree.leaves.get(&leaves[2]), Some(&1));
+
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &[leaves[0], leaves[2]],
+            );
+        assert_eq!(identity_tree.tree.root(), expected_tree.root());
+
+        // The same value can not be set at a different index
+        let result = identity_tree.insert_or_update(2, leaves[0]);
+        assert!(matches!(result, Err(IdentityTreeError::LeafAlreadyExists)));
+
+        // Zero is reserved for deleted leaves
+        let result = identity_tree.insert_or_update(1, Hash::ZERO);
+        assert!(matches!(result, Err(IdentityTreeError::ZeroLeafInsert)));
+        assert_eq!(identity_tree.tree.get_leaf(1), leaves[2]);
+
+        // Leaves can not be set past the next empty index
+        let result = identity_tree.insert_or_update(3, leaves[3]);
+        assert!(matches!(
+            result,
+            Err(IdentityTreeError::LeafIndexOutOfRange)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Generate new leaves and insert into the tree
+        let leaves = generate_all_leaves();
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree
+                .insert(idx as u32, *leaf)
+                .expect("Could not insert leaf");
+        }
+
+        // Remove each leaf from the tree
+        for i in 0..1 << TREE_DEPTH {
+            identity_tree.remove(i as usize)?;
+        }
+
+        // Initialize an expected tree with all leaves set to 0x00
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &vec![Hash::default(); leaves.len()],
+            );
+
+        // Ensure the tree roots are equal
+        assert_eq!(identity_tree.tree.root(), expected_tree.root());
+
+        // Assert that each of the leaves are not in the leaves hashmap
+        for leaf in 0..1 << TREE_DEPTH {
+            let leaf_hash = Hash::from(leaf);
+            assert_eq!(identity_tree.leaves.get(&leaf_hash), None);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_bounds() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+
+        // Removing an in range leaf returns its previous value
+        assert_eq!(identity_tree.remove(1)?, Some(leaves[1]));
+        assert_eq!(identity_tree.remove(1)?, None);
+
+        // Indices past the end of the tree are rejected without mutating the tree
+        let root = identity_tree.tree.root();
+        for index in [2, NUM_LEAVES, usize::MAX] {
+            let result = identity_tree.remove(index);
+            assert!(matches!(
+                result,
+                Err(IdentityTreeError::LeafIndexOutOfRange)
+            ));
+        }
+        assert_eq!(identity_tree.tree.root(), root);
+        assert_eq!(identity_tree.leaves.get(&leaves[0]), Some(&0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_wipes_leaf_slot() -> eyre::Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let cache_path = cache_dir.path().join("tree_cache");
+
+        let mut identity_tree =
+            IdentityTree::new_with_cache(TREE_DEPTH, &cache_path)?;
+
+        let leaves = generate_all_leaves();
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        identity_tree.remove(1)?;
+
+        assert_eq!(identity_tree.tree.get_leaf(1), Hash::ZERO);
+
+        // The removed leaf no longer appears anywhere in the raw tree storage
+        let storage = std::fs::read(&cache_path)?;
+        let removed_leaf = leaves[1].to_le_bytes::<32>();
+        assert!(!storage
+            .windows(removed_leaf.len())
+            .any(|window| window == removed_leaf));
+
+        // While the remaining leaves are still present
+        let remaining_leaf = leaves[0].to_le_bytes::<32>();
+        assert!(storage
+            .windows(remaining_leaf.len())
+            .any(|window| window == remaining_leaf));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_receipt() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        for (idx, leaf) in infinite_leaves().take(NUM_LEAVES).enumerate() {
+            let receipt = identity_tree.insert(idx as u32, leaf)?;
+
+            assert_eq!(identity_tree.leaves.get(&leaf), Some(&receipt.index));
+            assert_eq!(
+                identity_tree.get_leaf(receipt.index, None)?,
+                Some(leaf)
+            );
+            assert_eq!(receipt.root, identity_tree.root());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        let inserted =
+            identity_tree.insert_many(&[(0, leaves[0]), (1, leaves[1])])?;
+        assert_eq!(inserted, vec![0, 1]);
+
+        // The tree fills up partway through the batch
+        let extra_leaf = infinite_leaves().nth(NUM_LEAVES).unwrap();
+        let result = identity_tree.insert_many(&[
+            (2, leaves[2]),
+            (3, leaves[3]),
+            (4, extra_leaf),
+        ]);
+
+        let Err(IdentityTreeError::PartialInsert {
+            inserted,
+            failed_at,
+            source,
+        }) = result
+        else {
+            panic!("Expected a partial insert, got {result:?}");
+        };
+        assert_eq!(inserted, vec![2, 3]);
+        assert_eq!(failed_at, 2);
+        assert!(matches!(*source, IdentityTreeError::TreeFull));
+
+        // The successful prefix remains in the tree
+        assert_eq!(identity_tree.tree.num_leaves(), NUM_LEAVES);
+        assert_eq!(identity_tree.leaves.get(&leaves[3]), Some(&3));
+        assert_eq!(identity_tree.leaves.get(&extra_leaf), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_deleted_index() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH)
+            .with_leaf_index_policy(LeafIndexPolicy::ReuseDeleted);
+
+        // Fill the tree and remove the leaf at index 2
+        let mut leaves = infinite_leaves();
+        for idx in 0..NUM_LEAVES {
+            identity_tree.insert(idx as u32, leaves.next().unwrap())?;
+        }
+
+        identity_tree.remove(2)?;
+
+        // The new leaf should land in the freed slot rather than being appended
+        let new_leaf = leaves.next().unwrap();
+        let receipt = identity_tree.insert(NUM_LEAVES as u32, new_leaf)?;
+        assert_eq!(receipt.index, 2);
+        assert_eq!(receipt.root, identity_tree.root());
+
+        assert_eq!(identity_tree.tree.get_leaf(2), new_leaf);
+        assert_eq!(identity_tree.leaves.get(&new_leaf), Some(&2));
+        assert_eq!(identity_tree.tree.num_leaves(), NUM_LEAVES);
+        assert!(identity_tree.free_indices.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_pending_deleted_index() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH)
+            .with_leaf_index_policy(LeafIndexPolicy::ReuseDeleted);
+
+        let mut leaves = infinite_leaves();
+        for idx in 0..NUM_LEAVES {
+            identity_tree.insert(idx as u32, leaves.next().unwrap())?;
+        }
+
+        let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+        );
+        tree.extend_from_slice(
+            &identity_tree.tree.leaves().collect::<Vec<_>>(),
+        );
+
+        // A pending deletion does not free the index until it is applied
+        tree.set_leaf(3, Hash::ZERO);
+        let delete_root = Root::new(tree.root(), 1);
+        identity_tree.append_updates(
+            delete_root,
+            LeafUpdates::Delete(HashMap::from([(LeafIndex(3), Hash::ZERO)])),
+        )?;
+        assert!(identity_tree.free_indices.is_empty());
+
+        identity_tree.apply_updates_to_root(&delete_root);
+        assert_eq!(identity_tree.free_indices, BTreeSet::from([3]));
+
+        let receipt =
+            identity_tree.insert(NUM_LEAVES as u32, leaves.next().unwrap())?;
+        assert_eq!(receipt.index, 3);
+        assert!(identity_tree.free_indices.is_empty());
+
+        // A pending insert claims a freed index
+        identity_tree.remove(1)?;
+        assert_eq!(identity_tree.free_indices, BTreeSet::from([1]));
+
+        let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+        );
+        tree.extend_from_slice(
+            &identity_tree.tree.leaves().collect::<Vec<_>>(),
+        );
+        let new_leaf = leaves.next().unwrap();
+        tree.set_leaf(1, new_leaf);
+        identity_tree.append_updates(
+            Root::new(tree.root(), 2),
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(1), new_leaf)])),
+        )?;
+        assert!(identity_tree.free_indices.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() -> eyre::Result<()> {
+        let leaves = generate_all_leaves();
+
+        let mut tree_a = IdentityTree::new(TREE_DEPTH);
+        let mut tree_b = IdentityTree::new(TREE_DEPTH);
+        for (idx, leaf) in leaves[..NUM_LEAVES - 1].iter().enumerate() {
+            tree_a.insert(idx as u32, *leaf)?;
+            tree_b.insert(idx as u32, *leaf)?;
+        }
+
+        assert_eq!(tree_a.fingerprint(), tree_b.fingerprint());
+
+        // Diverge by a single leaf
+        tree_b.insert((NUM_LEAVES - 1) as u32, leaves[NUM_LEAVES - 1])?;
+        assert_ne!(tree_a.fingerprint(), tree_b.fingerprint());
+
+        // Equal roots with a different leaf index assignment must also diverge
+        let mut tree_c = IdentityTree::new(TREE_DEPTH);
+        for (idx, leaf) in leaves[..NUM_LEAVES - 1].iter().enumerate() {
+            tree_c.insert(idx as u32, *leaf)?;
+        }
+        tree_c.leaves.insert(leaves[0], 1);
+        tree_c.leaves.insert(leaves[1], 0);
+
+        assert_eq!(tree_a.tree.root(), tree_c.tree.root());
+        assert_ne!(tree_a.fingerprint(), tree_c.fingerprint());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_updates() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Generate the first half of the leaves and insert into the tree
+        let leaves = generate_all_leaves();
+        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        // Generate the updated tree with all of the leaves
+        let updated_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        // Append the new leaves to the tree
+        let new_root = Root {
+            hash: updated_tree.root(),
+            nonce: 1,
+        };
+
+        // Collect the second half of the leaves
+        let offset = NUM_LEAVES / 2;
+        let leaf_updates = leaves[(NUM_LEAVES / 2)..NUM_LEAVES]
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| (((idx + offset) as u32).into(), *value))
+            .collect::<HashMap<LeafIndex, Hash>>();
+
+        // Cache the expected root as the tree root should not change from the appended updates
+        let expected_root = identity_tree.tree.root();
+
+        identity_tree
+            .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
+
+        // Ensure that the root is correct and the updates are stored
+        assert_eq!(identity_tree.tree.root(), expected_root);
+        assert_eq!(identity_tree.tree_updates.len(), 1);
+
+        //TODO: assert expected updates
+
+        identity_tree.apply_updates_to_root(&new_root);
+
+        assert_eq!(identity_tree.tree.root(), updated_tree.root());
+        assert_eq!(identity_tree.tree_updates.len(), 0);
+
+        Ok(())
+    }
+
+    /// Records the fields of all spans created or updated while the layer is active, keyed by `<span name>.<field>`
+    #[derive(Clone, Default)]
+    struct SpanFieldRecorder(Arc<Mutex<HashMap<String, String>>>);
+
+    struct SpanFieldVisitor<'a> {
+        span_name: &'static str,
+        fields: &'a mut HashMap<String, String>,
+    }
+
+    impl tracing::field::Visit for SpanFieldVisitor<'_> {
+        fn record_debug(
+            &mut self,
+            field: &tracing::field::Field,
+            value: &dyn std::fmt::Debug,
+        ) {
+            self.fields.insert(
+                format!("{}.{}", self.span_name, field.name()),
+                format!("{value:?}"),
+            );
+        }
+    }
+
+    impl<S> Layer<S> for SpanFieldRecorder
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: Context<'_, S>,
+        ) {
+            let mut fields = self.0.lock().unwrap();
+            attrs.record(&mut SpanFieldVisitor {
+                span_name: attrs.metadata().name(),
+                fields: &mut fields,
+            });
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: Context<'_, S>,
+        ) {
+            let Some(span) = ctx.span(id) else {
+                return;
+            };
+
+            let mut fields = self.0.lock().unwrap();
+            values.record(&mut SpanFieldVisitor {
+                span_name: span.name(),
+                fields: &mut fields,
+            });
+        }
+    }
+
+    #[test]
+    fn test_append_zero_insert() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+
+        let root = Root {
+            hash: Hash::from(1),
+            nonce: 1,
+        };
+        let result = identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), leaves[1]),
+                (LeafIndex(0), Hash::ZERO),
+            ])),
+        );
+        assert!(matches!(result, Err(IdentityTreeError::ZeroLeafInsert)));
+
+        // The tree is left untouched
+        assert!(identity_tree.tree_updates.is_empty());
+        assert_eq!(identity_tree.leaves.get(&leaves[0]), Some(&0));
+        assert_eq!(identity_tree.leaves.get(&leaves[1]), None);
+
+        // Deletes are still applied as zero valued updates
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Delete(HashMap::from([(LeafIndex(0), Hash::ZERO)])),
+        )?;
+        assert_eq!(identity_tree.tree_updates.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_out_of_range() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        let result = identity_tree.append_updates(
+            Root {
+                hash: Hash::from(1),
+                nonce: 1,
+            },
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(0), leaves[0]),
+                (LeafIndex(1 << TREE_DEPTH), leaves[1]),
+            ])),
+        );
+        assert!(matches!(
+            result,
+            Err(IdentityTreeError::LeafIndexOutOfRange)
+        ));
+
+        // The tree is left untouched
+        assert!(identity_tree.tree_updates.is_empty());
+        assert!(identity_tree.leaves.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_spans() -> eyre::Result<()> {
+        let recorder = SpanFieldRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+
+        let leaves = generate_all_leaves();
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        let new_root = Root {
+            hash: expected_tree.root(),
+            nonce: 1,
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+            let leaf_updates = leaves
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| (LeafIndex(idx as u32), *value))
+                .collect::<HashMap<LeafIndex, Hash>>();
+
+            identity_tree
+                .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
+            identity_tree.apply_updates_to_root(&new_root);
+
+            eyre::Ok(())
+        })?;
+
+        let fields = recorder.0.lock().unwrap();
+        let expected_root = format!("{:?}", new_root.hash);
+
+        assert_eq!(fields["append_updates.root"], expected_root);
+        assert_eq!(fields["append_updates.num_leaves"], NUM_LEAVES.to_string());
+        // All leaves, intermediate nodes and the root are recomputed when starting from an empty tree
+        assert_eq!(
+            fields["append_updates.num_recomputed_nodes"],
+            ((1 << (TREE_DEPTH + 1)) - 1).to_string()
+        );
+        assert!(fields.contains_key("append_updates.elapsed_ms"));
+
+        assert_eq!(fields["apply_updates_to_root.root"], expected_root);
+        assert_eq!(
+            fields["apply_updates_to_root.num_leaves"],
+            NUM_LEAVES.to_string()
+        );
+        assert!(fields.contains_key("apply_updates_to_root.num_nodes"));
+        assert!(fields.contains_key("apply_updates_to_root.elapsed_ms"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_updates_to_root() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Generate the first half of the leaves and insert into the tree
+        let leaves = generate_all_leaves();
+
+        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        // Generate the updated tree with all of the leaves
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        let expected_root = expected_tree.root();
+
+        // Append the new leaves to the tree
+        let new_root = Root {
+            hash: expected_root,
+            nonce: 1,
+        };
+
+        // Collect the second half of the leaves
+        let leaf_updates = leaves[(NUM_LEAVES / 2)..]
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                (LeafIndex((NUM_LEAVES / 2 + idx) as u32), *value)
+            })
+            .collect::<HashMap<LeafIndex, Hash>>();
+
+        identity_tree
+            .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
+        assert_eq!(identity_tree.canonical_root(), None);
+
+        // Apply updates to the tree
+        identity_tree.apply_updates_to_root(&new_root);
+
+        assert_eq!(identity_tree.tree.root(), expected_root);
+        assert_eq!(identity_tree.tree_updates.len(), 0);
+        assert_eq!(identity_tree.canonical_root(), Some(new_root));
+
+        for (leaf_idx, leaf) in leaves.iter().enumerate() {
+            let proof = identity_tree
+                .inclusion_proof(*leaf, None)?
+                .ok_or(eyre!("Proof not found"))?;
+
+            assert_eq!(proof.root, expected_root);
+            assert_eq!(proof.proof, expected_tree.proof(leaf_idx));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_all_pending() -> eyre::Result<()> {
+        let leaves = generate_all_leaves();
+
+        // Builds a tree with half of the leaves in the canonical tree, followed by pending inserts and a delete
+        let build_tree =
+            || -> eyre::Result<(IdentityTree<Vec<Hash>>, Vec<Root>)> {
+                let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+                for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate()
+                {
+                    identity_tree.insert(idx as u32, *leaf)?;
+                }
+
+                let mut roots = vec![];
+                for idx in NUM_LEAVES / 2..NUM_LEAVES {
+                    let root = Root {
+                        hash: Hash::from(100 + idx),
+                        nonce: idx,
+                    };
+                    identity_tree.append_updates(
+                        root,
+                        LeafUpdates::Insert(HashMap::from([(
+                            LeafIndex(idx as u32),
+                            leaves[idx],
+                        )])),
+                    )?;
+                    roots.push(root);
+                }
+
+                let root = Root {
+                    hash: Hash::from(200),
+                    nonce: NUM_LEAVES,
+                };
+                identity_tree.append_updates(
+                    root,
+                    LeafUpdates::Delete(HashMap::from([(
+                        LeafIndex(0),
+                        Hash::ZERO,
+                    )])),
+                )?;
+                roots.push(root);
+
+                Ok((identity_tree, roots))
+            };
+
+        let (mut expected_tree, roots) = build_tree()?;
+        for root in roots.iter() {
+            expected_tree.apply_updates_to_root(root);
+        }
+
+        let (mut identity_tree, _) = build_tree()?;
+        assert_eq!(identity_tree.apply_all_pending(), roots.last().copied());
+
+        assert_eq!(identity_tree.tree.root(), expected_tree.tree.root());
+        assert_eq!(identity_tree.canonical_root, expected_tree.canonical_root);
+        assert_eq!(identity_tree.leaves, expected_tree.leaves);
+        assert_eq!(identity_tree.roots, expected_tree.roots);
+        assert_eq!(identity_tree.pruned_roots, expected_tree.pruned_roots);
+        assert!(identity_tree.tree_updates.is_empty());
+
+        // Nothing is left to apply
+        assert_eq!(identity_tree.apply_all_pending(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_num_leaves() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+        assert_eq!(identity_tree.latest_num_leaves(), 0);
+
+        identity_tree.insert(0, leaves[0])?;
+        assert_eq!(identity_tree.latest_num_leaves(), 1);
+
+        // Pending insertions count towards the leaves, pending deletions do not remove them
+        let insert_root = Root {
+            hash: Hash::from(1),
+            nonce: 1,
+        };
+        identity_tree.append_updates(
+            insert_root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), leaves[1]),
+                (LeafIndex(2), leaves[2]),
+            ])),
+        )?;
+        assert_eq!(identity_tree.latest_num_leaves(), 3);
+
+        identity_tree.append_updates(
+            Root {
+                hash: Hash::from(2),
+                nonce: 2,
+            },
+            LeafUpdates::Delete(HashMap::from([(LeafIndex(2), Hash::ZERO)])),
+        )?;
+        assert_eq!(identity_tree.latest_num_leaves(), 3);
+
+        identity_tree.apply_updates_to_root(&insert_root);
+        assert_eq!(identity_tree.latest_num_leaves(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_status() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        let initial_root = identity_tree.tree.root();
+
+        // Append one update per remaining leaf, each with the root of the tree after the insertion
+        let mut roots = vec![];
+        for idx in NUM_LEAVES / 2..NUM_LEAVES {
+            let tree: CascadingMerkleTree<PoseidonHash> =
+                CascadingMerkleTree::new_with_leaves(
+                    vec![],
+                    TREE_DEPTH,
+                    &Hash::ZERO,
+                    &leaves[..=idx],
+                );
+
+            let root = Root {
+                hash: tree.root(),
+                nonce: idx,
+            };
+
+            let leaf_updates =
+                HashMap::from([(LeafIndex(idx as u32), leaves[idx])]);
+            identity_tree
+                .append_updates(root, LeafUpdates::Insert(leaf_updates))?;
+
+            roots.push(root);
+        }
+
+        let unknown_root = Hash::from(1);
+
+        assert_eq!(
+            identity_tree.root_status(&initial_root),
+            RootStatus::Canonical
+        );
+        for root in roots.iter() {
+            assert_eq!(
+                identity_tree.root_status(&root.hash),
+                RootStatus::Pending
+            );
+        }
+        assert_eq!(
+            identity_tree.root_status(&unknown_root),
+            RootStatus::Unknown
+        );
+
+        // Applying the latest root prunes all prior roots
+        let latest_root = *roots.last().unwrap();
+        identity_tree.apply_updates_to_root(&latest_root);
+
+        assert_eq!(
+            identity_tree.root_status(&latest_root.hash),
+            RootStatus::Canonical
+        );
+        assert_eq!(
+            identity_tree.root_status(&roots[0].hash),
+            RootStatus::Pruned
+        );
+        assert_eq!(
+            identity_tree.root_status(&unknown_root),
+            RootStatus::Unknown
+        );
+
+        // Proofs at a pruned root are distinguishable from proofs at an unknown root
+        let result = identity_tree.inclusion_proof(leaves[0], Some(&roots[0]));
+        assert!(matches!(result, Err(IdentityTreeError::RootPruned)));
+
+        let result = identity_tree.inclusion_proof(
+            leaves[0],
+            Some(&Root {
+                hash: unknown_root,
+                nonce: NUM_LEAVES,
+            }),
+        );
+        assert!(matches!(result, Err(IdentityTreeError::RootNotFound)));
+
+        // Only the most recently pruned roots are retained
+        for idx in 0..PRUNED_ROOT_RETENTION as u64 {
+            identity_tree.record_pruned_root(Hash::from(1_000_000 + idx));
+        }
+        assert_eq!(
+            identity_tree.root_status(&roots[0].hash),
+            RootStatus::Unknown
+        );
+        assert_eq!(identity_tree.pruned_roots.len(), PRUNED_ROOT_RETENTION);
+        assert_eq!(
+            identity_tree.pruned_root_order.len(),
+            PRUNED_ROOT_RETENTION
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_info() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+
+        let mut roots = vec![];
+        for idx in 1..4 {
+            let tree: CascadingMerkleTree<PoseidonHash> =
+                CascadingMerkleTree::new_with_leaves(
+                    vec![],
+                    TREE_DEPTH,
+                    &Hash::ZERO,
+                    &leaves[..=idx],
+                );
+
+            let root = Root {
+                hash: tree.root(),
+                nonce: idx,
+            };
+
+            let leaf_updates =
+                HashMap::from([(LeafIndex(idx as u32), leaves[idx])]);
+            identity_tree
+                .append_updates(root, LeafUpdates::Insert(leaf_updates))?;
+
+            roots.push(root);
+        }
+
+        // The root recovered from a proof is the root it was generated against
+        for root in roots.iter() {
+            let proof = identity_tree
+                .inclusion_proof(leaves[1], Some(root))?
+                .expect("Could not get proof");
+            assert_eq!(identity_tree.root_info(&proof.root), Some(*root));
+        }
+
+        // The canonical root is still known after its updates are applied
+        identity_tree.apply_updates_to_root(&roots[1]);
+        assert_eq!(identity_tree.root_info(&roots[1].hash), Some(roots[1]));
+        assert_eq!(identity_tree.root_info(&roots[2].hash), Some(roots[2]));
+        assert_eq!(identity_tree.root_info(&roots[0].hash), None);
+        assert_eq!(identity_tree.root_info(&Hash::from(1)), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_root() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Generate the first half of the leaves and insert into the tree
+        let leaves = generate_all_leaves();
+
+        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        // Generate the updated tree with all of the leaves
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        // Collect the second half of the leaves
+        let leaf_updates = leaves[(NUM_LEAVES / 2)..]
+            .iter()
+            .cloned()
+            .collect::<Vec<Hash>>();
+
+        let updated_root = identity_tree.compute_root(&leaf_updates, None)?;
+        let expected_root = expected_tree.root();
+
+        assert_eq!(updated_root, expected_root);
+
+        // Leaves are appended after the pending leaves at the specified root
+        let tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves[0..NUM_LEAVES / 2 + 1],
+            );
+        let pending_root = Root::new(tree.root(), 1);
+        identity_tree.append_updates(
+            pending_root,
+            LeafUpdates::Insert(HashMap::from([(
+                LeafIndex((NUM_LEAVES / 2) as u32),
+                leaves[NUM_LEAVES / 2],
+            )])),
+        )?;
+
+        let leaf_updates = &leaves[(NUM_LEAVES / 2 + 1)..];
+        assert_eq!(
+            identity_tree.compute_root(leaf_updates, Some(&pending_root))?,
+            expected_root
+        );
+        assert_eq!(
+            identity_tree.compute_root(leaf_updates, None)?,
+            expected_root
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_updates() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        // Insert the first half of the leaves into the canonical tree
+        let leaves = generate_all_leaves();
+        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        // Append the second half of the leaves as pending updates
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+
+        let new_root = Root {
+            hash: expected_tree.root(),
+            nonce: 1,
+        };
+
+        let leaf_updates = leaves[(NUM_LEAVES / 2)..]
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                (LeafIndex((NUM_LEAVES / 2 + idx) as u32), *value)
+            })
+            .collect::<HashMap<LeafIndex, Hash>>();
+
+        identity_tree
+            .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
+
+        // Export the tree state and rebuild a new tree from it
+        let restored_tree = IdentityTree::from_updates(
+            TREE_DEPTH,
+            identity_tree.tree_updates.clone(),
+            identity_tree.canonical_leaves(),
+        )?;
+
+        assert_eq!(restored_tree.tree.root(), identity_tree.tree.root());
+        assert_eq!(restored_tree.tree_updates, identity_tree.tree_updates);
+        assert_eq!(restored_tree.leaves, identity_tree.leaves);
+        assert_eq!(restored_tree.roots, identity_tree.roots);
+
+        for leaf in leaves.iter() {
+            let proof = restored_tree
+                .inclusion_proof(*leaf, Some(&new_root))?
+                .context("Missing proof")?;
+
+            assert_eq!(proof.root, new_root.hash);
+        }
+
+        // Updates that do not match their root must be rejected
+        let mut corrupted_updates = identity_tree.tree_updates.clone();
+        for update in corrupted_updates.values_mut() {
+            update.insert(NodeIndex(0), Hash::ZERO);
+        }
+
+        let result = IdentityTree::from_updates(
+            TREE_DEPTH,
+            corrupted_updates,
+            identity_tree.canonical_leaves(),
+        );
+
+        assert!(matches!(
+            result,
+            Err(IdentityTreeError::StorageUpdatesRootMismatch)
+        ));
+
+        // Updates exported from a tree of a different depth must be rejected
+        for depth in [TREE_DEPTH - 1, TREE_DEPTH + 1] {
+            let result = IdentityTree::from_updates(
+                depth,
+                identity_tree.tree_updates.clone(),
+                HashMap::new(),
+            );
+
+            match result {
+                Err(IdentityTreeError::DepthMismatch { expected, found }) => {
+                    assert_eq!(expected, depth);
+                    assert_eq!(found, TREE_DEPTH);
+                }
+                Err(e) => panic!("Unexpected error: {e:?}"),
+                Ok(_) => panic!("Expected a depth mismatch"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten_leaf_updates() {}
+
+    #[test]
+    fn test_finalized_inclusion_proof() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves: Vec<_> = infinite_leaves().take(2).collect();
+
+        identity_tree.insert(0, leaves[0])?;
+        assert!(matches!(
+            identity_tree.finalized_inclusion_proof(leaves[0]),
+            Err(IdentityTreeError::NoFinalizedRoot)
+        ));
+
+        let canonical_root = Root::new(identity_tree.root(), 0);
+        identity_tree.canonical_root = Some(canonical_root);
+
+        // Append a newer pending root
+        let pending_root = {
+            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+            );
+            tree.extend_from_slice(&leaves);
+
+            Root::new(tree.root(), 1)
+        };
+        identity_tree.append_updates(
+            pending_root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(1), leaves[1])])),
+        )?;
+
+        let inclusion_proof = identity_tree
+            .finalized_inclusion_proof(leaves[0])?
+            .context("Missing proof")?;
+        assert_eq!(inclusion_proof.root, canonical_root.hash);
+        assert_ne!(inclusion_proof.root, pending_root.hash);
+        assert!(inclusion_proof.verify(leaves[0]));
+
+        // Leaves that are only pending have no finalized proof
+        assert!(identity_tree
+            .finalized_inclusion_proof(leaves[1])?
+            .is_none());
+
+        let inclusion_proofs = identity_tree.finalized_inclusion_proofs(&[
+            leaves[1],
+            leaves[0],
+            Hash::from(1),
+        ])?;
+        assert!(inclusion_proofs[0].is_none());
+        assert_eq!(
+            inclusion_proofs[1].as_ref().map(|proof| proof.root),
+            Some(canonical_root.hash)
+        );
+        assert!(inclusion_proofs[2].is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+
+        let pending_root = {
+            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+            );
+            tree.extend_from_slice(&leaves[0..3]);
+
+            Root::new(tree.root(), 1)
+        };
+        identity_tree.append_updates(
+            pending_root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(2), leaves[2])])),
+        )?;
+
+        let siblings = |proof: InclusionProof| {
+            proof
+                .proof
+                .0
+                .into_iter()
+                .map(|branch| match branch {
+                    Branch::Left(sibling) | Branch::Right(sibling) => sibling,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Paths at the canonical root
+        let paths = identity_tree.paths(0, 2, None)?;
+        assert_eq!(paths.len(), 2);
+        for (leaf_idx, path) in paths.into_iter().enumerate() {
+            let proof = identity_tree
+                .inclusion_proof(leaves[leaf_idx], None)?
+                .context("Missing proof")?;
+            assert_eq!(path, siblings(proof));
+        }
+
+        // Paths at a pending root
+        let paths = identity_tree.paths(0, 3, Some(&pending_root))?;
+        assert_eq!(paths.len(), 3);
+        for (leaf_idx, path) in paths.into_iter().enumerate() {
+            let proof = identity_tree
+                .inclusion_proof(leaves[leaf_idx], Some(&pending_root))?
+                .context("Missing proof")?;
+            assert_eq!(path, siblings(proof));
+        }
+
+        assert!(identity_tree.paths(1, 1, None)?.is_empty());
+        assert!(matches!(
+            identity_tree.paths(2, 1, None),
+            Err(IdentityTreeError::LeafIndexOutOfRange)
+        ));
+        assert!(matches!(
+            identity_tree.paths(0, NUM_LEAVES as u32 + 1, None),
+            Err(IdentityTreeError::LeafIndexOutOfRange)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_consistency_proof() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+        let canonical_root = Root::new(identity_tree.root(), 0);
+
+        let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+        );
+        tree.extend_from_slice(&leaves[0..2]);
+
+        // Append leaves 2 and 3 under separate roots
+        let mut roots = vec![canonical_root];
+        for leaf_idx in 2..NUM_LEAVES {
+            tree.push(leaves[leaf_idx])?;
+
+            let root = Root::new(tree.root(), leaf_idx - 1);
+            identity_tree.append_updates(
+                root,
+                LeafUpdates::Insert(HashMap::from([(
+                    LeafIndex(leaf_idx as u32),
+                    leaves[leaf_idx],
+                )])),
+            )?;
+            roots.push(root);
+        }
+
+        for (old_idx, old) in roots.iter().enumerate() {
+            for new in roots[old_idx..].iter() {
+                let proof = identity_tree.consistency_proof(old, new)?;
+                assert!(proof.verify());
+            }
+        }
+
+        // The proof is bound to the roots it was constructed for
+        let mut proof =
+            identity_tree.consistency_proof(&roots[0], &roots[1])?;
+        proof.old_root = roots[2].hash;
+        assert!(!proof.verify());
+
+        assert!(matches!(
+            identity_tree.consistency_proof(&roots[1], &roots[0]),
+            Err(IdentityTreeError::NotAppendOnly)
+        ));
+
+        // Deleting a leaf that exists at the old root breaks consistency
+        tree.set_leaf(0, Hash::ZERO);
+        let delete_root = Root::new(tree.root(), NUM_LEAVES);
+        identity_tree.append_updates(
+            delete_root,
+            LeafUpdates::Delete(HashMap::from([(LeafIndex(0), Hash::ZERO)])),
+        )?;
+
+        assert!(matches!(
+            identity_tree.consistency_proof(&roots[1], &delete_root),
+            Err(IdentityTreeError::NotAppendOnly)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inclusion_proof_checksum() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves: Vec<_> = infinite_leaves().take(4).collect();
+
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        let inclusion_proof = identity_tree
+            .inclusion_proof(leaves[2], None)?
+            .context("Missing proof")?;
+        assert!(inclusion_proof.checksum.is_none());
+        assert!(!inclusion_proof.verify_checksum());
+
+        let inclusion_proof = inclusion_proof.with_checksum();
+        assert!(inclusion_proof.verify_checksum());
+        assert!(inclusion_proof.verify(leaves[2]));
+
+        // A truncated sibling path fails the checksum
+        let mut truncated = identity_tree
+            .inclusion_proof(leaves[2], None)?
+            .context("Missing proof")?
+            .with_checksum();
+        truncated.proof.0.pop();
+        assert!(!truncated.verify_checksum());
+
+        // A tampered root fails the checksum
+        let mut tampered = identity_tree
+            .inclusion_proof(leaves[2], None)?
+            .context("Missing proof")?
+            .with_checksum();
+        tampered.root = Hash::from(1);
+        assert!(!tampered.verify_checksum());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inclusion_proof_eq() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves: Vec<_> = infinite_leaves().take(4).collect();
+
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        let proof = identity_tree
+            .inclusion_proof(leaves[2], None)?
+            .context("Missing proof")?;
+
+        // Proofs constructed independently for the same leaf are equal, regardless of the checksum
+        let same_proof =
+            InclusionProof::new(proof.root, identity_tree.tree.proof(2))
+                .with_checksum();
+        assert_eq!(proof, same_proof);
+
+        let mut hashes = HashSet::new();
+        hashes.insert(proof);
+        assert!(!hashes.insert(same_proof));
+
+        // Proofs for a different leaf or against a different root are not
+        let other_leaf = identity_tree
+            .inclusion_proof(leaves[3], None)?
+            .context("Missing proof")?;
+        assert!(!hashes.contains(&other_leaf));
+
+        let mut other_root = identity_tree
+            .inclusion_proof(leaves[2], None)?
+            .context("Missing proof")?;
+        other_root.root = Hash::from(1);
+        assert!(!hashes.contains(&other_root));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inclusion_proof() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+
+        let leaves: Vec<_> = infinite_leaves().take(4).collect();
+
+        // We insert only the first leaf
+        identity_tree.insert(0, leaves[0])?;
+
+        // Simulate and create updates
+        let (root_012, updates) = {
+            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+            );
+
+            tree.push(leaves[0])?;
+            tree.push(leaves[1])?;
+            tree.push(leaves[2])?;
+
+            let updates = LeafUpdates::Insert(
+                vec![(1.into(), leaves[1]), (2.into(), leaves[2])]
+                    .into_iter()
+                    .collect::<HashMap<LeafIndex, Hash>>(),
+            );
+
+            let root = Root {
+                hash: tree.root(),
+                nonce: 1,
+            };
+
+            (root, updates)
+        };
+
+        identity_tree.append_updates(root_012, updates)?;
+
+        // Simulate and create updates
+        let (root_0123, updates) = {
+            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+            );
+
+            tree.push(leaves[0])?;
+            tree.push(leaves[1])?;
+            tree.push(leaves[2])?;
+            tree.push(leaves[3])?;
+
+            let updates = LeafUpdates::Insert(
+                vec![(3.into(), leaves[3])]
+                    .into_iter()
+                    .collect::<HashMap<LeafIndex, Hash>>(),
+            );
+
+            let root = Root {
+                hash: tree.root(),
+                nonce: 2,
+            };
+
+            (root, updates)
+        };
+
+        identity_tree.append_updates(root_0123, updates)?;
+
+        let proof = identity_tree
+            .inclusion_proof(leaves[3], Some(&root_0123))?
+            .context("Missing proof")?;
+
+        assert_eq!(
+            proof.proof.0[0],
+            Branch::Right(leaves[2]),
+            "The first sibling of leaf 3 must be leaf 2"
+        );
 
-impl Ord for Root {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.nonce.cmp(&other.nonce)
-    }
-}
+        let proof = identity_tree
+            .inclusion_proof(leaves[2], Some(&root_012))?
+            .context("Missing proof")?;
 
-impl PartialOrd for Root {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
+        assert_eq!(
+            proof.proof.0[0],
+            Branch::Left(Hash::ZERO),
+            "The first sibling of leaf 2 must be zero hash"
+        );
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct InclusionProof {
-    pub root: Field,
-    pub proof: Proof,
-}
+        let proof = identity_tree.inclusion_proof(leaves[2], None)?;
 
-impl InclusionProof {
-    pub fn new(root: Field, proof: Proof) -> InclusionProof {
-        Self { root, proof }
+        assert!(
+            proof.is_none(),
+            "The canonical tree does not contain this update yet"
+        );
+
+        Ok(())
     }
 
-    pub fn verify(&self, leaf: Field) -> bool {
-        let mut hash = leaf;
+    #[test]
+    fn test_multi_proof() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-        for branch in self.proof.0.iter() {
-            match branch {
-                Branch::Left(sibling) => {
-                    hash = PoseidonHash::hash_node(&hash, sibling);
-                }
-                Branch::Right(sibling) => {
-                    hash = PoseidonHash::hash_node(sibling, &hash);
-                }
-            }
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
         }
 
-        hash == self.root
-    }
-}
+        for proven_leaves in [&leaves[0..2], &leaves[1..3], &leaves[..]] {
+            let multi_proof = identity_tree
+                .multi_proof(proven_leaves, None)?
+                .context("Multi proof not found")?;
+
+            assert_eq!(multi_proof.root, identity_tree.tree.root());
+            assert!(multi_proof.verify(proven_leaves));
+
+            // Shared siblings are only included once
+            let individual_proofs_len = proven_leaves
+                .iter()
+                .map(|leaf| {
+                    identity_tree
+                        .inclusion_proof(*leaf, None)?
+                        .map(|proof| proof.proof.0.len())
+                        .ok_or(eyre!("Proof not found"))
+                })
+                .sum::<eyre::Result<usize>>()?;
+            assert!(multi_proof.nodes.len() < individual_proofs_len);
+        }
 
-#[cfg(test)]
-mod test {
-    use std::collections::HashMap;
+        // Siblings of adjacent leaves can be computed from the leaves themselves
+        let multi_proof = identity_tree
+            .multi_proof(&leaves[0..2], None)?
+            .context("Multi proof not found")?;
+        assert_eq!(multi_proof.nodes.len(), 1);
 
-    use eyre::{eyre, ContextCompat};
-    use rand::{Rng, SeedableRng};
-    use semaphore::cascading_merkle_tree::CascadingMerkleTree;
-    use semaphore::merkle_tree::Branch;
-    use semaphore::poseidon_tree::PoseidonHash;
+        let multi_proof = identity_tree
+            .multi_proof(&leaves, None)?
+            .context("Multi proof not found")?;
+        assert!(multi_proof.nodes.is_empty());
 
-    use super::{leaf_to_storage_idx, IdentityTree, LeafUpdates, Root};
-    use crate::tree::error::IdentityTreeError;
-    use crate::tree::identity_tree::{
-        storage_idx_to_coords, storage_to_leaf_idx,
-    };
-    use crate::tree::{Hash, LeafIndex};
+        // Verification fails for the wrong leaves
+        let multi_proof = identity_tree
+            .multi_proof(&leaves[0..2], None)?
+            .context("Multi proof not found")?;
+        assert!(!multi_proof.verify(&[leaves[1], leaves[0]]));
+        assert!(!multi_proof.verify(&leaves[0..1]));
 
-    const TREE_DEPTH: usize = 2;
-    const NUM_LEAVES: usize = 1 << TREE_DEPTH;
+        Ok(())
+    }
 
-    fn infinite_leaves() -> impl Iterator<Item = Hash> {
-        let mut rng = rand::rngs::SmallRng::seed_from_u64(42);
+    #[test]
+    fn test_multi_proof_pending_leaf() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-        std::iter::from_fn(move || Some(random_field_elem(&mut rng)))
-    }
+        identity_tree.insert(0, leaves[0])?;
 
-    fn random_field_elem(rng: &mut impl Rng) -> Hash {
-        let mut limbs: [u64; 4] = rng.gen();
-        limbs[3] = 0; // nullify most significant limb to keep the values in the Field
+        let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+        );
+        tree.extend_from_slice(&leaves[0..2]);
+        let root = Root::new(tree.root(), 1);
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(1), leaves[1])])),
+        )?;
 
-        Hash::from_limbs(limbs)
-    }
+        // The pending leaf is not yet in the canonical tree
+        assert!(identity_tree.multi_proof(&leaves[0..2], None)?.is_none());
 
-    fn generate_all_leaves() -> Vec<Hash> {
-        infinite_leaves().take(NUM_LEAVES).collect()
+        let multi_proof = identity_tree
+            .multi_proof(&leaves[0..2], Some(&root))?
+            .context("Multi proof not found")?;
+        assert_eq!(multi_proof.root, root.hash);
+        assert!(multi_proof.verify(&leaves[0..2]));
+
+        Ok(())
     }
 
     #[test]
-    fn test_ord_root() {
-        let root_1 = Root {
-            hash: Hash::from(1),
-            nonce: 1,
-        };
+    fn test_sibling_resolutions() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-        let root_2 = Root {
-            hash: Hash::from(2),
-            nonce: 2,
-        };
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
 
-        let root_3 = Root {
-            hash: Hash::from(3),
+        // Only the right half of the tree changes, so the sibling of the updated subtree falls back to the canonical tree
+        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &leaves,
+            );
+        let root = Root {
+            hash: expected_tree.root(),
             nonce: 1,
         };
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(2), leaves[2]),
+                (LeafIndex(3), leaves[3]),
+            ])),
+        )?;
 
-        assert!(root_1 < root_2);
-        assert!(root_2 > root_3);
+        identity_tree
+            .inclusion_proof(leaves[2], Some(&root))?
+            .context("Missing proof")?;
+
+        let resolutions = &identity_tree.sibling_resolutions;
+        assert_eq!(resolutions.updates.load(Ordering::Relaxed), 1);
+        assert_eq!(resolutions.canonical_tree.load(Ordering::Relaxed), 1);
+
+        Ok(())
     }
 
     #[test]
-    fn test_leaf_to_storage_idx() {
-        for i in 0..1 << TREE_DEPTH {
-            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
-            let expected_storage_idx = (1 << TREE_DEPTH) + i - 1;
-            assert_eq!(storage_idx, expected_storage_idx);
+    fn test_leaves_page() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        for (idx, leaf) in leaves.iter().take(10).enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
         }
-    }
+        identity_tree.remove(4)?;
 
-    #[test]
-    fn test_storage_to_leaf_idx() {
-        for i in 0..1 << TREE_DEPTH {
-            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
-            let leaf_idx = storage_to_leaf_idx(storage_idx, TREE_DEPTH);
-            assert_eq!(leaf_idx, i);
+        let mut paged = vec![];
+        let mut offset = 0;
+        loop {
+            let page = identity_tree.leaves_page(offset, 3);
+            if page.is_empty() {
+                break;
+            }
+
+            assert!(page.len() <= 3);
+            offset += page.len();
+            paged.extend(page);
         }
+
+        let expected = (0..10)
+            .map(|idx| {
+                let leaf = if idx == 4 { Hash::ZERO } else { leaves[idx] };
+                (idx as u32, leaf)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(paged, expected);
+
+        assert!(identity_tree.leaves_page(10, 3).is_empty());
+
+        Ok(())
     }
 
     #[test]
-    fn test_storage_idx_to_coords() {
-        for i in 0..1 << TREE_DEPTH {
-            let storage_idx = leaf_to_storage_idx(i, TREE_DEPTH);
+    fn test_self_check() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-            let (depth, offset) = storage_idx_to_coords(storage_idx as usize);
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+        identity_tree.append_updates(
+            Root {
+                hash: Hash::from(1),
+                nonce: 1,
+            },
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(2), leaves[2])])),
+        )?;
 
-            let expected_depth = (storage_idx + 1).ilog2();
-            let expected_offset = storage_idx - (2_u32.pow(expected_depth) - 1);
+        identity_tree.self_check(true)?;
 
-            assert_eq!(depth, expected_depth as usize);
-            assert_eq!(offset, expected_offset as usize);
-        }
+        // A leaf mapped to an index holding a different leaf
+        identity_tree.leaves.insert(leaves[3], 0);
+        assert!(matches!(
+            identity_tree.self_check(false),
+            Err(IdentityTreeError::SelfCheckFailed(_))
+        ));
+
+        // A leaf mapped to an empty index
+        identity_tree.leaves.insert(leaves[3], 3);
+        assert!(matches!(
+            identity_tree.self_check(false),
+            Err(IdentityTreeError::SelfCheckFailed(_))
+        ));
+
+        identity_tree.leaves.remove(&leaves[3]);
+        identity_tree.self_check(true)?;
+
+        Ok(())
     }
 
     #[test]
-    fn test_insert() -> eyre::Result<()> {
+    fn test_validate_updates() -> eyre::Result<()> {
         let mut identity_tree = IdentityTree::new(TREE_DEPTH);
-
-        // Generate new leaves and insert into the tree
         let leaves = generate_all_leaves();
-        for (idx, leaf) in leaves.iter().enumerate() {
-            identity_tree
-                .insert(idx as u32, *leaf)
-                .expect("Could not insert leaf");
+
+        identity_tree.insert(0, leaves[0])?;
+
+        let mut roots = vec![];
+        for idx in 1..3 {
+            let tree: CascadingMerkleTree<PoseidonHash> =
+                CascadingMerkleTree::new_with_leaves(
+                    vec![],
+                    TREE_DEPTH,
+                    &Hash::ZERO,
+                    &leaves[..=idx],
+                );
+
+            let root = Root {
+                hash: tree.root(),
+                nonce: idx,
+            };
+
+            identity_tree.append_updates(
+                root,
+                LeafUpdates::Insert(HashMap::from([(
+                    LeafIndex(idx as u32),
+                    leaves[idx],
+                )])),
+            )?;
+            roots.push(root);
         }
 
-        // Initialize an expected tree with the same leaves
-        let expected_tree: CascadingMerkleTree<PoseidonHash> =
-            CascadingMerkleTree::new_with_leaves(
-                vec![],
-                TREE_DEPTH,
-                &Hash::ZERO,
-                &leaves,
-            );
+        for root in roots.iter() {
+            identity_tree.validate_updates(root)?;
+        }
 
-        // Ensure the tree roots are equal
-        assert_eq!(identity_tree.tree.root(), expected_tree.root());
+        // A corrupted internal node is reported at its own index rather than at its parent
+        let latest_root = roots[1];
+        let corrupted_idx = (leaf_to_storage_idx(2, TREE_DEPTH) - 1) / 2;
+        identity_tree
+            .tree_updates
+            .get_mut(&latest_root)
+            .unwrap()
+            .insert(NodeIndex(corrupted_idx), Hash::from(1));
 
-        // Assert that each of the leaves are in the leaves hashmap
-        for (leaf_idx, leaf) in leaves.iter().enumerate() {
-            assert_eq!(
-                identity_tree.leaves.get(leaf),
-                Some(&(leaf_idx as u32))
-            );
+        match identity_tree.validate_updates(&latest_root) {
+            Err(IdentityTreeError::InvalidStorageUpdate { node_idx }) => {
+                assert_eq!(node_idx, corrupted_idx);
+            }
+            result => panic!("Unexpected result: {result:?}"),
         }
 
+        // Other roots are unaffected
+        identity_tree.validate_updates(&roots[0])?;
+
         Ok(())
     }
 
     #[test]
-    fn test_remove() -> eyre::Result<()> {
+    fn test_recompute_updates() -> eyre::Result<()> {
         let mut identity_tree = IdentityTree::new(TREE_DEPTH);
-
-        // Generate new leaves and insert into the tree
         let leaves = generate_all_leaves();
-        for (idx, leaf) in leaves.iter().enumerate() {
-            identity_tree
-                .insert(idx as u32, *leaf)
-                .expect("Could not insert leaf");
-        }
 
-        // Remove each leaf from the tree
-        for i in 0..1 << TREE_DEPTH {
-            identity_tree.remove(i as usize);
-        }
+        identity_tree.insert(0, leaves[0])?;
 
-        // Initialize an expected tree with all leaves set to 0x00
-        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+        let tree: CascadingMerkleTree<PoseidonHash> =
             CascadingMerkleTree::new_with_leaves(
                 vec![],
                 TREE_DEPTH,
                 &Hash::ZERO,
-                &vec![Hash::default(); leaves.len()],
+                &leaves[..3],
             );
+        let root = Root {
+            hash: tree.root(),
+            nonce: 1,
+        };
 
-        // Ensure the tree roots are equal
-        assert_eq!(identity_tree.tree.root(), expected_tree.root());
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), leaves[1]),
+                (LeafIndex(2), leaves[2]),
+            ])),
+        )?;
+        let expected_updates = identity_tree.tree_updates[&root].clone();
 
-        // Assert that each of the leaves are not in the leaves hashmap
-        for leaf in 0..1 << TREE_DEPTH {
-            let leaf_hash = Hash::from(leaf);
-            assert_eq!(identity_tree.leaves.get(&leaf_hash), None);
+        // Corrupt an internal node and drop another one entirely
+        let updates = identity_tree.tree_updates.get_mut(&root).unwrap();
+        updates.insert(NodeIndex(1), Hash::from(1));
+        updates.remove(&NodeIndex(2));
+        assert!(identity_tree.validate_updates(&root).is_err());
+
+        identity_tree.recompute_updates(&root)?;
+        identity_tree.validate_updates(&root)?;
+        assert_eq!(identity_tree.tree_updates[&root], expected_updates);
+
+        for leaf in leaves[..3].iter() {
+            let proof = identity_tree
+                .inclusion_proof(*leaf, Some(&root))?
+                .context("Missing inclusion proof")?;
+            assert!(proof.verify(*leaf));
+            assert_eq!(proof.root, root.hash);
         }
 
+        // Corrupted leaves cannot be repaired since they no longer hash to the root
+        identity_tree.tree_updates.get_mut(&root).unwrap().insert(
+            NodeIndex(leaf_to_storage_idx(2, TREE_DEPTH)),
+            Hash::from(1),
+        );
+        assert!(identity_tree.recompute_updates(&root).is_err());
+
         Ok(())
     }
 
     #[test]
-    fn test_append_updates() -> eyre::Result<()> {
+    fn test_update_stats() -> eyre::Result<()> {
         let mut identity_tree = IdentityTree::new(TREE_DEPTH);
-
-        // Generate the first half of the leaves and insert into the tree
         let leaves = generate_all_leaves();
-        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
-            identity_tree.insert(idx as u32, *leaf)?;
-        }
-
-        // Generate the updated tree with all of the leaves
-        let updated_tree: CascadingMerkleTree<PoseidonHash> =
-            CascadingMerkleTree::new_with_leaves(
-                vec![],
-                TREE_DEPTH,
-                &Hash::ZERO,
-                &leaves,
-            );
-
-        // Append the new leaves to the tree
-        let new_root = Root {
-            hash: updated_tree.root(),
+
+        let small_root = Root {
+            hash: Hash::from(1),
             nonce: 1,
         };
+        identity_tree.append_updates(
+            small_root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(0), leaves[0])])),
+        )?;
 
-        // Collect the second half of the leaves
-        let offset = NUM_LEAVES / 2;
-        let leaf_updates = leaves[(NUM_LEAVES / 2)..NUM_LEAVES]
-            .iter()
-            .enumerate()
-            .map(|(idx, value)| (((idx + offset) as u32).into(), *value))
-            .collect::<HashMap<LeafIndex, Hash>>();
-
-        // Cache the expected root as the tree root should not change from the appended updates
-        let expected_root = identity_tree.tree.root();
-
+        let large_root = Root {
+            hash: Hash::from(2),
+            nonce: 2,
+        };
+        let large_batch = (1..4)
+            .map(|idx| (LeafIndex(idx), leaves[idx as usize]))
+            .collect::<HashMap<_, _>>();
         identity_tree
-            .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
+            .append_updates(large_root, LeafUpdates::Insert(large_batch))?;
 
-        // Ensure that the root is correct and the updates are stored
-        assert_eq!(identity_tree.tree.root(), expected_root);
-        assert_eq!(identity_tree.tree_updates.len(), 1);
+        let stats = identity_tree.pending_update_stats();
+        assert_eq!(
+            stats.iter().map(|(root, _)| *root).collect::<Vec<_>>(),
+            vec![small_root, large_root]
+        );
 
-        //TODO: assert expected updates
+        // A single leaf recomputes one node per level, including the leaf itself
+        assert_eq!(stats[0].1.num_recomputed_nodes, TREE_DEPTH + 1);
+        assert!(
+            stats[1].1.num_recomputed_nodes > stats[0].1.num_recomputed_nodes
+        );
 
-        identity_tree.apply_updates_to_root(&new_root);
+        // Stats are dropped along with the roots once applied
+        identity_tree.apply_updates_to_root(&small_root);
+        assert_eq!(identity_tree.pending_update_stats().len(), 1);
 
-        assert_eq!(identity_tree.tree.root(), updated_tree.root());
-        assert_eq!(identity_tree.tree_updates.len(), 0);
+        identity_tree.apply_updates_to_root(&large_root);
+        assert!(identity_tree.update_stats.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_apply_updates_to_root() -> eyre::Result<()> {
-        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
-
-        // Generate the first half of the leaves and insert into the tree
+    fn test_leaf_filter() -> eyre::Result<()> {
+        let mut identity_tree =
+            IdentityTree::new(TREE_DEPTH).with_leaf_filter(0.01);
         let leaves = generate_all_leaves();
 
-        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
-            identity_tree.insert(idx as u32, *leaf)?;
-        }
+        identity_tree.insert(0, leaves[0])?;
 
-        // Generate the updated tree with all of the leaves
-        let expected_tree: CascadingMerkleTree<PoseidonHash> =
+        let tree: CascadingMerkleTree<PoseidonHash> =
             CascadingMerkleTree::new_with_leaves(
                 vec![],
                 TREE_DEPTH,
                 &Hash::ZERO,
-                &leaves,
+                &leaves[..3],
             );
-
-        let expected_root = expected_tree.root();
-
-        // Append the new leaves to the tree
-        let new_root = Root {
-            hash: expected_root,
+        let root = Root {
+            hash: tree.root(),
             nonce: 1,
         };
 
-        // Collect the second half of the leaves
-        let leaf_updates = leaves[(NUM_LEAVES / 2)..]
-            .iter()
-            .enumerate()
-            .map(|(idx, value)| {
-                (LeafIndex((NUM_LEAVES / 2 + idx) as u32), *value)
-            })
-            .collect::<HashMap<LeafIndex, Hash>>();
-
-        identity_tree
-            .append_updates(new_root, LeafUpdates::Insert(leaf_updates))?;
-
-        // Apply updates to the tree
-        identity_tree.apply_updates_to_root(&new_root);
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), leaves[1]),
+                (LeafIndex(2), leaves[2]),
+            ])),
+        )?;
 
-        assert_eq!(identity_tree.tree.root(), expected_root);
-        assert_eq!(identity_tree.tree_updates.len(), 0);
+        // Leaves added by inserts and pending updates are never filtered out
+        let filter = identity_tree.leaf_filter.as_ref().unwrap();
+        assert!(leaves[..3].iter().all(|leaf| filter.might_contain(leaf)));
 
-        for (leaf_idx, leaf) in leaves.iter().enumerate() {
+        for leaf in leaves[..3].iter() {
             let proof = identity_tree
-                .inclusion_proof(*leaf, None)?
-                .ok_or(eyre!("Proof not found"))?;
+                .inclusion_proof(*leaf, Some(&root))?
+                .context("Missing inclusion proof")?;
+            assert!(proof.verify(*leaf));
+        }
 
-            assert_eq!(proof.root, expected_root);
-            assert_eq!(proof.proof, expected_tree.proof(leaf_idx));
+        // Applied leaves remain in the filter, which is only rebuilt once the tree drifts too far from it
+        identity_tree.apply_updates_to_root(&root);
+        let filter = identity_tree.leaf_filter.as_ref().unwrap();
+        assert!(identity_tree
+            .leaves
+            .keys()
+            .all(|leaf| filter.might_contain(leaf)));
+
+        for leaf in leaves[..3].iter() {
+            assert!(identity_tree.inclusion_proof(*leaf, None)?.is_some());
         }
 
+        let result = identity_tree.inclusion_proof(leaves[3], None);
+        assert!(matches!(result, Err(IdentityTreeError::LeafNotFound)));
+
         Ok(())
     }
 
     #[test]
-    fn test_compute_root() -> eyre::Result<()> {
+    fn test_reconcile() -> eyre::Result<()> {
         let mut identity_tree = IdentityTree::new(TREE_DEPTH);
-
-        // Generate the first half of the leaves and insert into the tree
         let leaves = generate_all_leaves();
 
-        for (idx, leaf) in leaves[0..NUM_LEAVES / 2].iter().enumerate() {
-            identity_tree.insert(idx as u32, *leaf)?;
-        }
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+        identity_tree.append_updates(
+            Root {
+                hash: Hash::from(1),
+                nonce: 1,
+            },
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(2), leaves[2])])),
+        )?;
 
-        // Generate the updated tree with all of the leaves
-        let expected_tree: CascadingMerkleTree<PoseidonHash> =
-            CascadingMerkleTree::new_with_leaves(
-                vec![],
-                TREE_DEPTH,
-                &Hash::ZERO,
-                &leaves,
-            );
+        // A consistent tree is left unchanged
+        assert_eq!(identity_tree.reconcile(), ReconcileReport::default());
 
-        // Collect the second half of the leaves
-        let leaf_updates = leaves[(NUM_LEAVES / 2)..]
-            .iter()
-            .cloned()
-            .collect::<Vec<Hash>>();
+        // Desynchronize the leaves hashmap from the tree
+        let unknown_leaf = leaves[3];
+        identity_tree.leaves.insert(unknown_leaf, 3);
+        identity_tree.leaves.remove(&leaves[1]);
 
-        let updated_root = identity_tree.compute_root(&leaf_updates, None)?;
-        let expected_root = expected_tree.root();
+        let report = identity_tree.reconcile();
+        assert_eq!(report.removed, vec![(3, unknown_leaf)]);
+        assert_eq!(report.restored, vec![(1, leaves[1])]);
 
-        assert_eq!(updated_root, expected_root);
+        assert_eq!(identity_tree.leaves.len(), 3);
+        assert_eq!(identity_tree.leaves.get(&leaves[1]), Some(&1));
+        assert_eq!(identity_tree.leaves.get(&leaves[2]), Some(&2));
+        assert_eq!(identity_tree.leaves.get(&unknown_leaf), None);
 
         Ok(())
     }
 
-    #[test]
-    fn test_flatten_leaf_updates() {}
-
-    #[test]
-    fn test_inclusion_proof() -> eyre::Result<()> {
-        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+    #[derive(Default)]
+    struct RecordingMetrics {
+        proofs: Mutex<usize>,
+        leaf_counts: Mutex<Vec<usize>>,
+        appends: Mutex<Vec<usize>>,
+    }
 
-        let leaves: Vec<_> = infinite_leaves().take(4).collect();
+    impl TreeMetrics for RecordingMetrics {
+        fn record_proof(&self, _latency: Duration) {
+            *self.proofs.lock().unwrap() += 1;
+        }
 
-        // We insert only the first leaf
-        identity_tree.insert(0, leaves[0])?;
+        fn set_leaf_count(&self, num_leaves: usize) {
+            self.leaf_counts.lock().unwrap().push(num_leaves);
+        }
 
-        // Simulate and create updates
-        let (root_012, updates) = {
-            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
-                vec![],
-                TREE_DEPTH,
-                &Hash::ZERO,
-            );
+        fn record_append(&self, num_nodes: usize) {
+            self.appends.lock().unwrap().push(num_nodes);
+        }
+    }
 
-            tree.push(leaves[0])?;
-            tree.push(leaves[1])?;
-            tree.push(leaves[2])?;
+    #[test]
+    fn test_metrics_hooks() -> eyre::Result<()> {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let mut identity_tree =
+            IdentityTree::new(TREE_DEPTH).with_metrics(metrics.clone());
+        let leaves = generate_all_leaves();
 
-            let updates = LeafUpdates::Insert(
-                vec![(1.into(), leaves[1]), (2.into(), leaves[2])]
-                    .into_iter()
-                    .collect::<HashMap<LeafIndex, Hash>>(),
-            );
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
 
-            let root = Root {
-                hash: tree.root(),
-                nonce: 1,
-            };
+        let root = Root {
+            hash: Hash::from(1),
+            nonce: 1,
+        };
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(2), leaves[2])])),
+        )?;
 
-            (root, updates)
+        // The second update flattens the first, but only recomputes its own path
+        let next_root = Root {
+            hash: Hash::from(2),
+            nonce: 2,
         };
+        identity_tree.append_updates(
+            next_root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(3), leaves[3])])),
+        )?;
+        identity_tree.apply_updates_to_root(&next_root);
 
-        identity_tree.append_updates(root_012, updates)?;
+        identity_tree
+            .inclusion_proof(leaves[0], None)?
+            .context("Missing proof")?;
+        identity_tree
+            .finalized_inclusion_proof(leaves[3])?
+            .context("Missing proof")?;
 
-        // Simulate and create updates
-        let (root_0123, updates) = {
-            let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
-                vec![],
-                TREE_DEPTH,
-                &Hash::ZERO,
-            );
+        assert_eq!(*metrics.leaf_counts.lock().unwrap(), vec![1, 2, 4]);
+        // The leaf, its parent and the root are recomputed for each update
+        assert_eq!(*metrics.appends.lock().unwrap(), vec![3, 3]);
+        assert_eq!(*metrics.proofs.lock().unwrap(), 2);
 
-            tree.push(leaves[0])?;
-            tree.push(leaves[1])?;
-            tree.push(leaves[2])?;
-            tree.push(leaves[3])?;
+        Ok(())
+    }
 
-            let updates = LeafUpdates::Insert(
-                vec![(3.into(), leaves[3])]
-                    .into_iter()
-                    .collect::<HashMap<LeafIndex, Hash>>(),
-            );
+    #[test]
+    fn test_inclusion_proofs() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-            let root = Root {
-                hash: tree.root(),
-                nonce: 2,
-            };
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
 
-            (root, updates)
-        };
+        let proofs = identity_tree
+            .inclusion_proofs(&[leaves[1], leaves[2], leaves[0]], None)?;
 
-        identity_tree.append_updates(root_0123, updates)?;
+        assert_eq!(proofs.len(), 3);
+        assert!(proofs[0].as_ref().is_some_and(|p| p.verify(leaves[1])));
+        assert!(proofs[1].is_none());
+        assert!(proofs[2].as_ref().is_some_and(|p| p.verify(leaves[0])));
 
-        let proof = identity_tree
-            .inclusion_proof(leaves[3], Some(&root_0123))?
-            .context("Missing proof")?;
+        Ok(())
+    }
 
-        assert_eq!(
-            proof.proof.0[0],
-            Branch::Right(leaves[2]),
-            "The first sibling of leaf 3 must be leaf 2"
-        );
+    #[test]
+    fn test_get_leaf() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
 
-        let proof = identity_tree
-            .inclusion_proof(leaves[2], Some(&root_012))?
-            .context("Missing proof")?;
+        identity_tree.insert(0, leaves[0])?;
+        identity_tree.insert(1, leaves[1])?;
+        identity_tree.remove(1)?;
 
-        assert_eq!(
-            proof.proof.0[0],
-            Branch::Left(Hash::ZERO),
-            "The first sibling of leaf 2 must be zero hash"
-        );
+        let root = Root {
+            hash: Hash::from(1),
+            nonce: 1,
+        };
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(2), leaves[2])])),
+        )?;
 
-        let proof = identity_tree.inclusion_proof(leaves[2], None)?;
+        assert_eq!(identity_tree.get_leaf(0, None)?, Some(leaves[0]));
+        // Removed, pending and out of range leaves are not in the canonical tree
+        assert_eq!(identity_tree.get_leaf(1, None)?, None);
+        assert_eq!(identity_tree.get_leaf(2, None)?, None);
+        assert_eq!(identity_tree.get_leaf(NUM_LEAVES as u32, None)?, None);
 
-        assert!(
-            proof.is_none(),
-            "The canonical tree does not contain this update yet"
-        );
+        // Pending leaves are available at their root
+        assert_eq!(identity_tree.get_leaf(0, Some(&root))?, Some(leaves[0]));
+        assert_eq!(identity_tree.get_leaf(2, Some(&root))?, Some(leaves[2]));
+        assert_eq!(identity_tree.get_leaf(3, Some(&root))?, None);
 
         Ok(())
     }
@@ -1108,4 +4556,218 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_apply_pending_insert_then_delete() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        let leaves = generate_all_leaves();
+
+        identity_tree.insert(0, leaves[0])?;
+
+        let mut tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+        );
+        tree.extend_from_slice(&leaves[0..3]);
+        let insert_root = Root::new(tree.root(), 1);
+
+        tree.set_leaf(1, Hash::ZERO);
+        let delete_root = Root::new(tree.root(), 2);
+
+        identity_tree.append_updates(
+            insert_root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), leaves[1]),
+                (LeafIndex(2), leaves[2]),
+            ])),
+        )?;
+        identity_tree.append_updates(
+            delete_root,
+            LeafUpdates::Delete(HashMap::from([(LeafIndex(1), Hash::ZERO)])),
+        )?;
+
+        // The deleted leaf is removed from the leaves hashmap while still pending
+        assert_eq!(identity_tree.leaves.get(&leaves[1]), None);
+        assert_eq!(identity_tree.leaves.get(&leaves[2]), Some(&2));
+        identity_tree.self_check(false)?;
+
+        // The leaf inserted after the deleted leaf keeps its index once applied
+        identity_tree.apply_updates_to_root(&delete_root);
+        assert_eq!(identity_tree.root(), delete_root.hash);
+        assert_eq!(identity_tree.tree.num_leaves(), 3);
+        assert_eq!(identity_tree.tree.get_leaf(1), Hash::ZERO);
+        assert_eq!(identity_tree.tree.get_leaf(2), leaves[2]);
+        identity_tree.self_check(true)?;
+
+        Ok(())
+    }
+
+    fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+        std::env::var(key)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// Randomly interleaves appends, applies and concurrent proof reads, asserting that `self_check` holds throughout.
+    /// Run with `cargo test stress_mixed_workload -- --ignored --nocapture`, setting `STRESS_SEED` to reproduce a
+    /// failure and `STRESS_DURATION_SECS` to change how long the workload runs for.
+    #[test]
+    #[ignore]
+    fn stress_mixed_workload() -> eyre::Result<()> {
+        const DEPTH: usize = 20;
+        const NUM_READERS: u64 = 4;
+
+        let seed = env_or("STRESS_SEED", rand::random::<u64>());
+        let duration = Duration::from_secs(env_or("STRESS_DURATION_SECS", 10));
+        tracing::info!(seed, "Running stress test");
+
+        let identity_tree = Arc::new(RwLock::new(IdentityTree::new(DEPTH)));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers = (0..NUM_READERS)
+            .map(|reader| {
+                let identity_tree = identity_tree.clone();
+                let done = done.clone();
+
+                std::thread::spawn(move || -> eyre::Result<usize> {
+                    let mut rng = rand::rngs::SmallRng::seed_from_u64(
+                        seed.wrapping_add(reader + 1),
+                    );
+                    let mut num_proofs = 0;
+
+                    while !done.load(Ordering::Relaxed) {
+                        let identity_tree = identity_tree.read().unwrap();
+                        if identity_tree.leaves.is_empty() {
+                            continue;
+                        }
+
+                        let (leaf, leaf_idx) = identity_tree
+                            .leaves
+                            .iter()
+                            .nth(rng.gen_range(0..identity_tree.leaves.len()))
+                            .map(|(leaf, leaf_idx)| (*leaf, *leaf_idx))
+                            .context("Missing leaf")?;
+
+                        // The latest pending root contains every leaf in the leaves hashmap, while the canonical
+                        // tree only contains leaves that have been applied
+                        let root = identity_tree
+                            .tree_updates
+                            .keys()
+                            .last()
+                            .copied()
+                            .filter(|_| rng.gen_bool(0.5));
+                        let in_tree = root.is_some()
+                            || identity_tree.get_leaf(leaf_idx, None)?
+                                == Some(leaf);
+
+                        let inclusion_proof =
+                            identity_tree.inclusion_proof(leaf, root.as_ref())?;
+
+                        if in_tree {
+                            let inclusion_proof =
+                                inclusion_proof.context("Missing proof")?;
+                            if !inclusion_proof.verify(leaf) {
+                                return Err(eyre!(
+                                    "Invalid proof for leaf {leaf_idx} at root {root:?}"
+                                ));
+                            }
+                        }
+
+                        num_proofs += 1;
+                    }
+
+                    Ok(num_proofs)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(seed);
+        let mut expected_tree = CascadingMerkleTree::<PoseidonHash>::new(
+            vec![],
+            DEPTH,
+            &Hash::ZERO,
+        );
+        let mut live_indices = vec![];
+        let mut nonce = 0;
+        let mut num_ops = 0;
+
+        let start_time = Instant::now();
+        while start_time.elapsed() < duration {
+            let mut identity_tree = identity_tree.write().unwrap();
+
+            match rng.gen_range(0..10) {
+                // Append a batch of insertions
+                0..=4 => {
+                    let mut updates = HashMap::new();
+                    for _ in 0..rng.gen_range(1..=16) {
+                        let leaf_idx = expected_tree.num_leaves() as u32;
+                        let leaf = random_field_elem(&mut rng);
+
+                        expected_tree.push(leaf)?;
+                        live_indices.push(leaf_idx);
+                        updates.insert(LeafIndex(leaf_idx), leaf);
+                    }
+
+                    nonce += 1;
+                    identity_tree.append_updates(
+                        Root::new(expected_tree.root(), nonce),
+                        LeafUpdates::Insert(updates),
+                    )?;
+                }
+                // Append a batch of deletions
+                5..=6 if !live_indices.is_empty() => {
+                    let mut updates = HashMap::new();
+                    for _ in 0..rng.gen_range(1..=4).min(live_indices.len()) {
+                        let leaf_idx = live_indices
+                            .swap_remove(rng.gen_range(0..live_indices.len()));
+
+                        expected_tree.set_leaf(leaf_idx as usize, Hash::ZERO);
+                        updates.insert(LeafIndex(leaf_idx), Hash::ZERO);
+                    }
+
+                    nonce += 1;
+                    identity_tree.append_updates(
+                        Root::new(expected_tree.root(), nonce),
+                        LeafUpdates::Delete(updates),
+                    )?;
+                }
+                // Apply a random pending root to the canonical tree
+                7..=8 if !identity_tree.tree_updates.is_empty() => {
+                    let root = *identity_tree
+                        .tree_updates
+                        .keys()
+                        .nth(rng.gen_range(0..identity_tree.tree_updates.len()))
+                        .context("Missing root")?;
+
+                    identity_tree.apply_updates_to_root(&root);
+                    assert_eq!(identity_tree.root(), root.hash);
+                }
+                _ => {}
+            }
+
+            identity_tree.self_check(rng.gen_ratio(1, 50))?;
+            num_ops += 1;
+        }
+
+        done.store(true, Ordering::Relaxed);
+
+        let mut num_proofs = 0;
+        for reader in readers {
+            num_proofs += reader.join().unwrap()?;
+        }
+
+        // Once every pending root is applied, the canonical tree matches the expected tree
+        let mut identity_tree = identity_tree.write().unwrap();
+        if let Some(root) = identity_tree.tree_updates.keys().last().copied() {
+            identity_tree.apply_updates_to_root(&root);
+        }
+        assert_eq!(identity_tree.root(), expected_tree.root());
+        identity_tree.self_check(true)?;
+
+        tracing::info!(num_ops, num_proofs, "Completed stress test");
+
+        Ok(())
+    }
 }
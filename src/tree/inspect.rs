@@ -0,0 +1,199 @@
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{BufWriter, Write as _};
+use std::path::Path;
+
+use semaphore::generic_storage::GenericStorage;
+use semaphore::merkle_tree::Branch;
+use serde::{Deserialize, Serialize};
+
+use super::identity_tree::{IdentityTree, InclusionProof};
+use super::Hash;
+
+/// Name of the file within an export directory containing one `ExportedProof` per line
+pub const PROOFS_FILE: &str = "proofs.jsonl";
+/// Name of the file within an export directory containing the `ExportManifest`
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// An inclusion proof exported for a single identity commitment
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedProof {
+    pub identity_commitment: Hash,
+    pub proof: InclusionProof,
+}
+
+/// Describes the root that every proof in an export was generated against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportManifest {
+    pub root: Hash,
+    /// Nonce of the canonical root, if the tree was built from chain events
+    pub nonce: Option<usize>,
+    pub tree_depth: usize,
+    pub num_proofs: usize,
+}
+
+/// Reads a JSON encoded inclusion proof, as returned by `/inclusionProof`
+pub fn read_proof(path: impl AsRef<Path>) -> eyre::Result<InclusionProof> {
+    let contents = std::fs::read_to_string(path)?;
+
+    Ok(serde_json::from_str(&contents)?)
+}
+
+/// Renders the root, depth and derived leaf index of a proof followed by each branch from the leaf to the root
+/// If a leaf is provided, also renders whether the proof verifies for that leaf
+pub fn describe_proof(proof: &InclusionProof, leaf: Option<Hash>) -> String {
+    let mut description = String::new();
+
+    // Note that writing to a String can not fail
+    let _ = writeln!(description, "root: {:#x}", proof.root);
+    let _ = writeln!(description, "depth: {}", proof.proof.0.len());
+    let _ = writeln!(description, "leaf index: {}", proof.leaf_index());
+
+    for (level, branch) in proof.proof.0.iter().enumerate() {
+        let (direction, sibling) = match branch {
+            Branch::Left(sibling) => ("Left", sibling),
+            Branch::Right(sibling) => ("Right", sibling),
+        };
+
+        let _ =
+            writeln!(description, "branch {level}: {direction} {sibling:#x}");
+    }
+
+    if let Some(leaf) = leaf {
+        let _ = writeln!(description, "verified: {}", proof.verify(leaf));
+    }
+
+    description
+}
+
+/// Writes an inclusion proof against the canonical root for every leaf of the canonical tree to `out_dir`,
+/// followed by a manifest describing the root. Proofs are written as they are generated rather than collected in memory.
+pub fn export_proofs<S>(
+    identity_tree: &IdentityTree<S>,
+    out_dir: impl AsRef<Path>,
+) -> eyre::Result<ExportManifest>
+where
+    S: GenericStorage<Hash>,
+{
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir)?;
+
+    let root = identity_tree.tree.root();
+    let mut writer = BufWriter::new(File::create(out_dir.join(PROOFS_FILE))?);
+    let mut num_proofs = 0;
+
+    for (leaf_idx, leaf) in identity_tree.tree.leaves().enumerate() {
+        // Deleted leaves have no proof
+        if leaf == Hash::ZERO {
+            continue;
+        }
+
+        let exported_proof = ExportedProof {
+            identity_commitment: leaf,
+            proof: InclusionProof::new(
+                root,
+                identity_tree.tree.proof(leaf_idx),
+            ),
+        };
+
+        serde_json::to_writer(&mut writer, &exported_proof)?;
+        writer.write_all(b"\n")?;
+        num_proofs += 1;
+    }
+
+    writer.flush()?;
+
+    let manifest = ExportManifest {
+        root,
+        nonce: identity_tree.canonical_root().map(|root| root.nonce),
+        tree_depth: identity_tree.tree.depth(),
+        num_proofs,
+    };
+
+    std::fs::write(
+        out_dir.join(MANIFEST_FILE),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_proof() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(3);
+        let leaves = (1..=5).map(Hash::from).collect::<Vec<_>>();
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        let inclusion_proof = identity_tree
+            .inclusion_proof(leaves[3], None)?
+            .expect("Missing proof");
+
+        let file = tempfile::NamedTempFile::new()?;
+        serde_json::to_writer(&file, &inclusion_proof)?;
+
+        let decoded = read_proof(file.path())?;
+        assert_eq!(decoded.root, inclusion_proof.root);
+        assert_eq!(decoded.leaf_index(), 3);
+
+        let description = describe_proof(&decoded, Some(leaves[3]));
+        assert!(description.contains("depth: 3\n"));
+        assert!(description.contains("leaf index: 3\n"));
+        assert_eq!(description.matches("branch ").count(), 3);
+        assert!(description.contains("verified: true\n"));
+
+        let description = describe_proof(&decoded, Some(leaves[0]));
+        assert!(description.contains("verified: false\n"));
+
+        let description = describe_proof(&decoded, None);
+        assert!(!description.contains("verified"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_proofs() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(3);
+        let leaves = (1..=5).map(Hash::from).collect::<Vec<_>>();
+        for (idx, leaf) in leaves.iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+        identity_tree.remove(1)?;
+
+        let out_dir = tempfile::tempdir()?;
+        let manifest = export_proofs(&identity_tree, out_dir.path())?;
+
+        assert_eq!(manifest.root, identity_tree.tree.root());
+        assert_eq!(manifest.tree_depth, 3);
+        assert_eq!(manifest.num_proofs, 4);
+
+        let written_manifest: ExportManifest = serde_json::from_str(
+            &std::fs::read_to_string(out_dir.path().join(MANIFEST_FILE))?,
+        )?;
+        assert_eq!(written_manifest, manifest);
+
+        // Every exported proof verifies against the manifest root
+        let proofs = std::fs::read_to_string(out_dir.path().join(PROOFS_FILE))?;
+        let mut exported = vec![];
+        for line in proofs.lines() {
+            let exported_proof: ExportedProof = serde_json::from_str(line)?;
+            assert_eq!(exported_proof.proof.root, manifest.root);
+            assert!(exported_proof
+                .proof
+                .verify(exported_proof.identity_commitment));
+
+            exported.push(exported_proof.identity_commitment);
+        }
+
+        assert_eq!(exported, [leaves[0], leaves[2], leaves[3], leaves[4]]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash as _, Hasher};
+
+use super::Hash;
+
+// Minimum number of leaves a filter is sized for, so that small or empty trees still get a useful filter
+const MIN_CAPACITY: usize = 1024;
+// Fraction of the capacity of a filter that removed leaves can take up before the filter needs to be rebuilt
+const MAX_STALE_RATIO: f64 = 0.1;
+
+/// Bloom filter over leaf hashes, used to reject absent leaves without a hashmap lookup.
+/// The filter never reports a present leaf as absent, but may report an absent leaf as present.
+#[derive(Debug, Clone)]
+pub struct LeafFilter {
+    bits: Vec<u64>,
+    num_hashes: u32,
+    false_positive_rate: f64,
+    // Number of leaves the filter was sized for
+    capacity: usize,
+    // Number of leaves inserted since the filter was built, including leaves that have since been removed
+    num_inserted: usize,
+}
+
+impl LeafFilter {
+    /// Initializes an empty filter sized to hold `capacity` leaves at the target `false_positive_rate`
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        let false_positive_rate = false_positive_rate.clamp(f64::EPSILON, 0.5);
+
+        // Optimal number of bits and hash functions for the capacity and false positive rate
+        let ln2 = std::f64::consts::LN_2;
+        let num_leaves = capacity as f64;
+        let num_bits =
+            (-num_leaves * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let num_hashes = ((num_bits / num_leaves) * ln2).round().max(1.0);
+
+        Self {
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+            num_hashes: num_hashes as u32,
+            false_positive_rate,
+            capacity,
+            num_inserted: 0,
+        }
+    }
+
+    /// Builds a filter over `leaves`, sized with headroom for the tree to double in size before the next rebuild
+    pub fn from_leaves<'a>(
+        leaves: impl ExactSizeIterator<Item = &'a Hash>,
+        false_positive_rate: f64,
+    ) -> Self {
+        let mut filter = Self::new(leaves.len() * 2, false_positive_rate);
+
+        for leaf in leaves {
+            filter.insert(leaf);
+        }
+
+        filter
+    }
+
+    /// Returns the false positive rate the filter was sized for
+    pub fn false_positive_rate(&self) -> f64 {
+        self.false_positive_rate
+    }
+
+    pub fn insert(&mut self, leaf: &Hash) {
+        for bit in self.bit_indices(leaf) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.num_inserted += 1;
+    }
+
+    /// Returns whether the filter should be rebuilt for a tree currently holding `num_leaves` leaves, either because
+    /// it holds more leaves than it was sized for or because removed leaves take up too much of its capacity
+    pub fn needs_rebuild(&self, num_leaves: usize) -> bool {
+        let num_removed = self.num_inserted.saturating_sub(num_leaves);
+
+        self.num_inserted > self.capacity
+            || num_removed as f64 > self.capacity as f64 * MAX_STALE_RATIO
+    }
+
+    /// Returns `false` only if the leaf was never inserted into the filter
+    pub fn might_contain(&self, leaf: &Hash) -> bool {
+        self.bit_indices(leaf)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // Derives the bit indices of a leaf by double hashing a single 64 bit hash
+    fn bit_indices(&self, leaf: &Hash) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        leaf.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let num_bits = (self.bits.len() * 64) as u64;
+        let h1 = hash;
+        let h2 = hash.rotate_left(32) | 1;
+
+        (0..self.num_hashes as u64).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leaf_filter() {
+        let leaves = (1..=10_000_u64).map(Hash::from).collect::<Vec<_>>();
+        let filter = LeafFilter::from_leaves(leaves.iter(), 0.01);
+
+        assert!(leaves.iter().all(|leaf| filter.might_contain(leaf)));
+
+        // Absent leaves may be reported as present, but only at roughly the target rate
+        let false_positives = (10_001..=20_000_u64)
+            .map(Hash::from)
+            .filter(|leaf| filter.might_contain(leaf))
+            .count();
+        assert!(false_positives < 500);
+    }
+
+    #[test]
+    fn test_needs_rebuild() {
+        let leaves = (1..=1000_u64).map(Hash::from).collect::<Vec<_>>();
+        let mut filter = LeafFilter::from_leaves(leaves.iter(), 0.01);
+        assert_eq!(filter.capacity, 2000);
+        assert!(!filter.needs_rebuild(leaves.len()));
+
+        // Removing leaves only requires a rebuild once they take up a tenth of the capacity
+        assert!(!filter.needs_rebuild(800));
+        assert!(filter.needs_rebuild(799));
+
+        // Growing past the capacity degrades the false positive rate, requiring a rebuild
+        for leaf in (1001..=2000_u64).map(Hash::from) {
+            filter.insert(&leaf);
+        }
+        assert!(!filter.needs_rebuild(2000));
+        filter.insert(&Hash::from(2001));
+        assert!(filter.needs_rebuild(2001));
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::{
+    BuildError, Matcher, PrometheusBuilder, PrometheusHandle,
+};
+use semaphore::generic_storage::GenericStorage;
+
+use super::identity_tree::IdentityTree;
+use super::Hash;
+
+/// Hooks invoked by `IdentityTree` to record metrics, allowing embedders to forward them to their own metrics backend
+pub trait TreeMetrics: Send + Sync {
+    /// Records the latency of constructing an inclusion proof
+    fn record_proof(&self, _latency: Duration) {}
+
+    /// Records the number of leaves in the canonical tree
+    fn set_leaf_count(&self, _num_leaves: usize) {}
+
+    /// Records the number of storage nodes recomputed by an update appended to `tree_updates`
+    fn record_append(&self, _num_nodes: usize) {}
+}
+
+/// `TreeMetrics` implementation that discards all metrics
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl TreeMetrics for NoopMetrics {}
+
+/// `TreeMetrics` implementation that records metrics via the global `metrics` recorder, only available with the
+/// `metrics` feature
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsRecorder;
+
+#[cfg(feature = "metrics")]
+impl TreeMetrics for MetricsRecorder {
+    fn record_proof(&self, latency: Duration) {
+        ::metrics::histogram!("world_tree.proof_latency")
+            .record(latency.as_secs_f64());
+    }
+
+    fn set_leaf_count(&self, num_leaves: usize) {
+        ::metrics::gauge!("world_tree.num_leaves").set(num_leaves as f64);
+    }
+
+    fn record_append(&self, num_nodes: usize) {
+        ::metrics::counter!("world_tree.recomputed_nodes")
+            .increment(num_nodes as u64);
+    }
+}
+
+/// Upper bounds of the proof latency histogram buckets, in seconds
+#[cfg(feature = "metrics")]
+const PROOF_LATENCY_BUCKETS: [f64; 8] =
+    [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+/// Installs a Prometheus recorder as the global `metrics` recorder, returning the handle used to render scrapes
+/// Fails if a global recorder, such as the statsd exporter, is already installed
+#[cfg(feature = "metrics")]
+pub fn install_prometheus_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("world_tree.proof_latency".to_string()),
+            &PROOF_LATENCY_BUCKETS,
+        )?
+        .install_recorder()
+}
+
+/// Snapshot of the state of the identity tree and the canonical tree manager
+#[derive(Debug, Clone, Copy)]
+pub struct TreeGauges {
+    pub num_leaves: usize,
+    pub pending_roots: usize,
+    pub tree_updates_memory_usage: usize,
+    /// Number of blocks the canonical tree is behind the chain head, as of the last scan
+    pub sync_lag: u64,
+    /// Number of proof siblings resolved from `tree_updates`
+    pub sibling_resolutions_updates: u64,
+    /// Number of proof siblings that fell back to the canonical tree
+    pub sibling_resolutions_canonical_tree: u64,
+}
+
+impl TreeGauges {
+    pub fn new<S>(identity_tree: &IdentityTree<S>, sync_lag: u64) -> Self
+    where
+        S: GenericStorage<Hash>,
+    {
+        Self {
+            num_leaves: identity_tree.tree.num_leaves(),
+            pending_roots: identity_tree.canonical_lag(),
+            tree_updates_memory_usage: identity_tree.memory_usage(),
+            sync_lag,
+            sibling_resolutions_updates: identity_tree
+                .sibling_resolutions
+                .updates
+                .load(Ordering::Relaxed),
+            sibling_resolutions_canonical_tree: identity_tree
+                .sibling_resolutions
+                .canonical_tree
+                .load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records the gauges via the global `metrics` recorder
+    pub fn record(&self) {
+        ::metrics::gauge!("world_tree.num_leaves").set(self.num_leaves as f64);
+        ::metrics::gauge!("world_tree.pending_roots")
+            .set(self.pending_roots as f64);
+        ::metrics::gauge!("world_tree.updates_memory_bytes")
+            .set(self.tree_updates_memory_usage as f64);
+        ::metrics::gauge!("world_tree.sync_lag_blocks")
+            .set(self.sync_lag as f64);
+        ::metrics::counter!(
+            "world_tree.proof_sibling_resolutions",
+            "source" => "updates"
+        )
+        .absolute(self.sibling_resolutions_updates);
+        ::metrics::counter!(
+            "world_tree.proof_sibling_resolutions",
+            "source" => "canonical_tree"
+        )
+        .absolute(self.sibling_resolutions_canonical_tree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_gauges() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(2);
+        for idx in 0..3 {
+            identity_tree.insert(idx, Hash::from(idx + 1))?;
+        }
+
+        let gauges = TreeGauges::new(&identity_tree, 7);
+
+        assert_eq!(gauges.num_leaves, 3);
+        assert_eq!(gauges.pending_roots, 0);
+        assert_eq!(gauges.sync_lag, 7);
+        assert_eq!(gauges.sibling_resolutions_canonical_tree, 0);
+
+        Ok(())
+    }
+}
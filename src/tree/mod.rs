@@ -1,9 +1,18 @@
 pub mod block_scanner;
 pub mod config;
+pub mod dry_run;
+pub mod encoding;
 pub mod error;
+pub mod events;
 pub mod identity_tree;
+pub mod inspect;
+pub mod leaf_filter;
+pub mod metrics;
+pub mod replay;
 pub mod service;
+pub mod task;
 pub mod tree_manager;
+pub mod wire;
 
 use std::collections::{BTreeMap, HashMap};
 use std::ops::{Deref, DerefMut};
@@ -12,7 +21,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use ethers::providers::Middleware;
-use ethers::types::{Log, U256};
+use ethers::types::{Log, H256, U256};
 use rayon::iter::{Either, IntoParallelIterator, ParallelIterator};
 use ruint::Uint;
 use semaphore::generic_storage::MmapVec;
@@ -26,10 +35,16 @@ use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tracing::instrument;
 
-use self::error::WorldTreeError;
-use self::identity_tree::{IdentityTree, InclusionProof, LeafUpdates, Root};
+use self::error::{IdentityTreeError, WorldTreeError};
+use self::events::{IndexerEvent, IndexerEvents};
+use self::identity_tree::{
+    IdentityTree, InclusionProof, LeafUpdates, Root, RootStatus,
+};
+use self::metrics::TreeGauges;
+use self::task::{spawn_named, BRIDGED_UPDATES_TASK, CANONICAL_UPDATES_TASK};
 use self::tree_manager::{
-    extract_identity_updates, BridgedTree, CanonicalTree, TreeManager,
+    extract_identity_updates, root_transactions, BridgedTree, CanonicalTree,
+    TreeManager,
 };
 use crate::abi::IBridgedWorldID;
 use crate::tree::identity_tree::flatten_leaf_updates;
@@ -50,6 +65,8 @@ pub struct WorldTree<M: Middleware + 'static> {
     pub chain_state: Arc<RwLock<HashMap<u64, Root>>>,
     /// Flag to indicate if the tree is synced to the latest block on startup. Once the tree is initially synced to the chain tip, this field is set to true
     pub synced: AtomicBool,
+    /// Publishes the progress of the canonical tree indexer, see `WorldTree::subscribe_events`
+    pub events: IndexerEvents,
 }
 
 impl<M> WorldTree<M>
@@ -71,6 +88,7 @@ where
             bridged_tree_manager,
             chain_state: Arc::new(RwLock::new(HashMap::new())),
             synced: AtomicBool::new(false),
+            events: IndexerEvents::default(),
         })
     }
 
@@ -94,13 +112,32 @@ where
         let (bridged_root_tx, bridged_root_rx) =
             tokio::sync::mpsc::channel(100);
 
+        // Every insertion after the sync must continue from the leaves already in the tree, so that a leaf gap in the first batch is detected
+        let (next_leaf_index, tree_depth) = {
+            let identity_tree = self.identity_tree.read().await;
+            (
+                identity_tree.latest_num_leaves() as u32,
+                identity_tree.depth(),
+            )
+        };
+
         // Spawn the tree managers to listen to the canonical and bridged trees for updates
         let mut handles = vec![];
-        handles.push(self.canonical_tree_manager.spawn(leaf_updates_tx));
+        handles.push(self.canonical_tree_manager.spawn(
+            leaf_updates_tx,
+            self.events.clone(),
+            Some(next_leaf_index),
+            tree_depth,
+        ));
 
         if !self.bridged_tree_manager.is_empty() {
             for bridged_tree in self.bridged_tree_manager.iter() {
-                handles.push(bridged_tree.spawn(bridged_root_tx.clone()));
+                handles.push(bridged_tree.spawn(
+                    bridged_root_tx.clone(),
+                    self.events.clone(),
+                    None,
+                    tree_depth,
+                ));
             }
 
             // Spawn a task to handle bridged updates, updating the tree with the latest root across all chains and applying
@@ -117,7 +154,7 @@ where
     /// All updates are added to `pending_updates` and the mainnet root is updated with the latest root
     fn handle_canonical_updates(
         &self,
-        leaf_updates_rx: Receiver<(Root, LeafUpdates)>,
+        leaf_updates_rx: Receiver<(Root, LeafUpdates, Option<H256>)>,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
         // If there are no bridged trees, apply canonical updates to the tree as they arrive
         if self.bridged_tree_manager.is_empty() {
@@ -132,25 +169,30 @@ where
     // Appends canonical updates to `tree_updates` as they arrive
     fn append_canonical_updates(
         &self,
-        mut leaf_updates_rx: Receiver<(Root, LeafUpdates)>,
+        mut leaf_updates_rx: Receiver<(Root, LeafUpdates, Option<H256>)>,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
         let canonical_chain_id = self.canonical_tree_manager.chain_id;
         let identity_tree = self.identity_tree.clone();
         let chain_state = self.chain_state.clone();
 
         // If we are monitoring bridged chains, apply canonical updates to tree updates until the root is bridged to all chains
-        tokio::spawn(async move {
-            while let Some((new_root, leaf_updates)) =
+        spawn_named(CANONICAL_UPDATES_TASK, async move {
+            while let Some((new_root, leaf_updates, tx_hash)) =
                 leaf_updates_rx.recv().await
             {
                 tracing::info!(
                     ?new_root,
+                    ?tx_hash,
                     "Leaf updates received, appending tree updates"
                 );
                 let mut identity_tree = identity_tree.write().await;
 
                 identity_tree.append_updates(new_root, leaf_updates)?;
 
+                if let Some(tx_hash) = tx_hash {
+                    identity_tree.record_root_tx(tx_hash, new_root);
+                }
+
                 // Update the root for the canonical chain
                 chain_state
                     .write()
@@ -165,15 +207,15 @@ where
     // Applies canonical updates to the tree as they arrive
     fn apply_canonical_updates(
         &self,
-        mut leaf_updates_rx: Receiver<(Root, LeafUpdates)>,
+        mut leaf_updates_rx: Receiver<(Root, LeafUpdates, Option<H256>)>,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
         let canonical_chain_id = self.canonical_tree_manager.chain_id;
         let identity_tree = self.identity_tree.clone();
         let chain_state: Arc<RwLock<HashMap<u64, Root>>> =
             self.chain_state.clone();
 
-        tokio::spawn(async move {
-            while let Some((new_root, leaf_updates)) =
+        spawn_named(CANONICAL_UPDATES_TASK, async move {
+            while let Some((new_root, leaf_updates, tx_hash)) =
                 leaf_updates_rx.recv().await
             {
                 tracing::info!(
@@ -181,10 +223,10 @@ where
                     "Leaf updates received, applying to the canonical tree"
                 );
 
+                let mut identity_tree = identity_tree.write().await;
+
                 match leaf_updates {
                     LeafUpdates::Insert(leaves) => {
-                        let mut identity_tree = identity_tree.write().await;
-
                         // Sort the leaf updates by index
                         let mut leaves = leaves
                             .into_iter()
@@ -196,14 +238,20 @@ where
                         identity_tree.extend_from_slice(&leaves);
                     }
                     LeafUpdates::Delete(leaves) => {
-                        let mut identity_tree = identity_tree.write().await;
-
                         for (leaf_idx, _) in leaves {
-                            identity_tree.remove(leaf_idx.0 as usize);
+                            identity_tree.remove(leaf_idx.0 as usize)?;
                         }
                     }
                 }
 
+                // The canonical tree now represents the new root
+                identity_tree.canonical_root = Some(new_root);
+
+                if let Some(tx_hash) = tx_hash {
+                    identity_tree.record_root_tx(tx_hash, new_root);
+                }
+                drop(identity_tree);
+
                 // Update the root for the canonical chain
                 chain_state
                     .write()
@@ -224,7 +272,7 @@ where
         let identity_tree = self.identity_tree.clone();
         let chain_state = self.chain_state.clone();
 
-        tokio::spawn(async move {
+        spawn_named(BRIDGED_UPDATES_TASK, async move {
             while let Some((chain_id, bridged_root)) =
                 bridged_root_rx.recv().await
             {
@@ -336,25 +384,126 @@ where
     /// Syncs the world tree to the latest block on mainnet, updating the canonical tree and bridged trees from identity updates extracted from logs
     #[instrument(skip(self))]
     pub async fn sync_to_head(&self) -> Result<(), WorldTreeError<M>> {
+        self.events.emit(IndexerEvent::BackfillStarted {
+            from_block: self
+                .canonical_tree_manager
+                .block_scanner
+                .next_block
+                .load(Ordering::SeqCst),
+        });
+
         // Get logs from the canonical tree on mainnet
         tracing::info!("Getting canonical logs");
         let logs = self.get_canonical_logs().await?;
 
         tracing::info!("Extracting identity updates from logs");
         // Extract identity updates from the logs and build the tree from the updates
+        let tree_depth = self.identity_tree.read().await.depth();
         let identity_updates = extract_identity_updates(
             &logs,
             self.canonical_tree_manager.block_scanner.middleware.clone(),
+            tree_depth,
         )
         .await?;
 
+        let latest_root = identity_updates.keys().last().map(|root| root.hash);
+        let roots = identity_updates.keys().copied().collect::<Vec<_>>();
         self.build_tree_from_updates(identity_updates).await?;
 
+        // Record the transactions that produced the canonical and pending roots, oldest first, so that they can be resolved by `root_by_tx`
+        let root_txs = root_transactions(&logs);
+        let mut identity_tree = self.identity_tree.write().await;
+        for root in roots {
+            if let Some(tx_hash) = root_txs.get(&root.hash) {
+                identity_tree.record_root_tx(*tx_hash, root);
+            }
+        }
+        drop(identity_tree);
+
         self.synced.store(true, Ordering::SeqCst);
 
+        let block_number = self.last_synced_block();
+        let root = match latest_root {
+            Some(root) => root,
+            None => self.identity_tree.read().await.root(),
+        };
+        self.events
+            .emit(IndexerEvent::BackfillCompleted { block_number, root });
+        self.events.emit(IndexerEvent::CaughtUp { block_number });
+
         Ok(())
     }
 
+    /// Subscribes to the progress events of the canonical tree indexer, including the initial backfill if subscribed
+    /// before the tree is spawned
+    pub fn subscribe_events(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<IndexerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the number of blocks between the chain head on mainnet and the last block processed by the canonical tree manager
+    pub async fn sync_lag(&self) -> Result<u64, WorldTreeError<M>> {
+        let block_scanner = &self.canonical_tree_manager.block_scanner;
+
+        let latest_block = block_scanner
+            .middleware
+            .get_block_number()
+            .await
+            .map_err(WorldTreeError::MiddlewareError)?
+            .as_u64();
+
+        // `next_block` is the next block to be scanned, so the last processed block is `next_block - 1`
+        let next_block = block_scanner.next_block.load(Ordering::SeqCst);
+
+        Ok((latest_block + 1).saturating_sub(next_block))
+    }
+
+    /// Returns the sync lag as of the last chain head observed by the canonical tree manager, without querying the provider
+    pub fn cached_sync_lag(&self) -> u64 {
+        let block_scanner = &self.canonical_tree_manager.block_scanner;
+
+        let latest_block = block_scanner.latest_block.load(Ordering::SeqCst);
+        let next_block = block_scanner.next_block.load(Ordering::SeqCst);
+
+        (latest_block + 1).saturating_sub(next_block)
+    }
+
+    /// Returns the last block on mainnet processed by the canonical tree manager
+    pub fn last_synced_block(&self) -> u64 {
+        self.canonical_tree_manager
+            .block_scanner
+            .next_block
+            .load(Ordering::SeqCst)
+            .saturating_sub(1)
+    }
+
+    /// Returns the number of consecutive failed attempts to process the canonical tree logs from the next block
+    pub fn sync_failures(&self) -> u32 {
+        self.canonical_tree_manager
+            .block_scanner
+            .consecutive_failures
+            .load(Ordering::SeqCst)
+    }
+
+    /// Returns the root produced by the transaction with hash `tx_hash`, if it is among the retained transactions
+    pub async fn root_by_tx(&self, tx_hash: H256) -> Option<Root> {
+        self.identity_tree.read().await.root_by_tx(tx_hash)
+    }
+
+    /// Returns the full root for a root hash that is canonical or pending
+    pub async fn root_info(&self, root_hash: &Hash) -> Option<Root> {
+        self.identity_tree.read().await.root_info(root_hash)
+    }
+
+    /// Returns a snapshot of the state of the identity tree and the cached sync lag of the canonical tree manager
+    pub async fn tree_gauges(&self) -> TreeGauges {
+        let sync_lag = self.cached_sync_lag();
+        let identity_tree = self.identity_tree.read().await;
+
+        TreeGauges::new(&identity_tree, sync_lag)
+    }
+
     async fn get_canonical_logs(&self) -> Result<Vec<Log>, WorldTreeError<M>> {
         let identity_tree = self.identity_tree.read().await;
 
@@ -374,7 +523,7 @@ where
             all_logs
         } else {
             // Split the logs
-            let latest_root = identity_tree.tree.root();
+            let latest_root = identity_tree.root();
 
             let mut pivot = all_logs.len();
             for log in all_logs.iter().rev() {
@@ -435,7 +584,7 @@ where
             // In this case, we can set all chains to the latest root, with the root nonce set to 0. When the next canonical update is received,
             // the root for the canonical chain_id will be updated and once the new root is bridged to all chains,
             // the pending tree_updates will be applied and the root with nonce 0 will no longer be in the chain state hashmap.
-            let latest_root = identity_tree.tree.root();
+            let latest_root = identity_tree.root();
 
             let root = Root {
                 hash: latest_root,
@@ -462,6 +611,13 @@ where
 
             chain_state.insert(self.canonical_tree_manager.chain_id, root);
 
+            // The canonical tree already represents the latest root, which is not known yet when the tree is restored from the cache
+            if identity_tree.canonical_root.map(|root| root.hash)
+                != Some(latest_root)
+            {
+                identity_tree.canonical_root = Some(root);
+            }
+
             // Note that we do not need to insert the root into roots since it is already in the canonical tree.
             // The roots hashmap is only used when applying updates to the tree.
         } else {
@@ -499,6 +655,11 @@ where
     ) -> Result<(), WorldTreeError<M>> {
         let mut identity_tree = self.identity_tree.write().await;
 
+        // The latest root of the canonical updates is the root represented by the canonical tree once built
+        if let Some(root) = identity_updates.keys().last() {
+            identity_tree.canonical_root = Some(*root);
+        }
+
         // Flatten the leaves and build the canonical tree
         let flattened_leaves = flatten_leaf_updates(identity_updates);
 
@@ -510,6 +671,7 @@ where
                 identity_tree.leaves.remove(hash);
             }
         }
+        identity_tree.rebuild_leaf_filter();
 
         // Build the tree from leaves
         tracing::info!(num_new_leaves = ?flattened_leaves.len(), "Building the canonical tree");
@@ -618,38 +780,255 @@ where
         Ok(inclusion_proof)
     }
 
+    /// Returns an inclusion proof against the canonical root, which has been observed by all chains and can no longer
+    /// be superseded by pending updates
+    pub async fn finalized_inclusion_proof(
+        &self,
+        identity_commitment: Hash,
+    ) -> Result<Option<InclusionProof>, WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let inclusion_proof = self
+            .identity_tree
+            .read()
+            .await
+            .finalized_inclusion_proof(identity_commitment)?;
+
+        Ok(inclusion_proof)
+    }
+
+    /// Returns inclusion proofs against the canonical root for multiple identity commitments, preserving their order
+    pub async fn finalized_inclusion_proofs(
+        &self,
+        identity_commitments: &[Hash],
+    ) -> Result<Vec<Option<InclusionProof>>, WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let inclusion_proofs = self
+            .identity_tree
+            .read()
+            .await
+            .finalized_inclusion_proofs(identity_commitments)?;
+
+        Ok(inclusion_proofs)
+    }
+
+    /// Returns inclusion proofs for multiple identity commitments, preserving their order.
+    /// Commitments that are not in the tree have no proof.
+    /// If a chain ID is provided, the proofs are generated for the given chain, and if a root hash is provided, the
+    /// proofs are generated against that root. A chain ID and a root hash can not be combined.
+    pub async fn inclusion_proofs(
+        &self,
+        identity_commitments: &[Hash],
+        chain_id: Option<ChainId>,
+        root_hash: Option<Hash>,
+    ) -> Result<Vec<Option<InclusionProof>>, WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let chain_state = self.chain_state.read().await;
+        let identity_tree = self.identity_tree.read().await;
+
+        let root =
+            request_root(&chain_state, &identity_tree, chain_id, root_hash)?;
+
+        let inclusion_proofs = identity_tree
+            .inclusion_proofs(identity_commitments, root.as_ref())?;
+
+        Ok(inclusion_proofs)
+    }
+
+    /// Returns the number of leaves in the canonical tree along with a page of up to `limit` leaves starting at `offset`
+    pub async fn leaves_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<(u32, Hash)>), WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let identity_tree = self.identity_tree.read().await;
+
+        let total = identity_tree.tree.num_leaves();
+        if offset > total {
+            return Err(WorldTreeError::OffsetOutOfRange { offset, total });
+        }
+
+        Ok((total, identity_tree.leaves_page(offset, limit)))
+    }
+
+    /// Returns the root that the paths were resolved at, along with the sibling hashes for each leaf index in `from..to`
+    /// If a root hash is provided, the paths are resolved at that root, otherwise at the canonical root
+    pub async fn paths(
+        &self,
+        from: u32,
+        to: u32,
+        root_hash: Option<Hash>,
+    ) -> Result<(Hash, Vec<Vec<Hash>>), WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let identity_tree = self.identity_tree.read().await;
+
+        let root = match root_hash {
+            Some(hash) => resolve_root(&identity_tree, hash)?,
+            None => None,
+        };
+
+        let paths = identity_tree.paths(from, to, root.as_ref())?;
+        let root_hash =
+            root.map_or_else(|| identity_tree.root(), |root| root.hash);
+
+        Ok((root_hash, paths))
+    }
+
+    /// Returns the identity commitment at a given leaf index along with its inclusion proof.
+    /// If a chain ID is provided, the proof is generated for the given chain, and if a root hash is provided, the proof
+    /// is generated against that root. A chain ID and a root hash can not be combined.
+    pub async fn inclusion_proof_by_index(
+        &self,
+        leaf_idx: u32,
+        chain_id: Option<ChainId>,
+        root_hash: Option<Hash>,
+    ) -> Result<(Hash, Option<InclusionProof>), WorldTreeError<M>> {
+        if !self.synced.load(Ordering::SeqCst) {
+            return Err(WorldTreeError::TreeNotSynced);
+        }
+
+        let chain_state = self.chain_state.read().await;
+        let identity_tree = self.identity_tree.read().await;
+
+        let root =
+            request_root(&chain_state, &identity_tree, chain_id, root_hash)?;
+
+        let identity_commitment = identity_tree
+            .get_leaf(leaf_idx, root.as_ref())?
+            .ok_or(IdentityTreeError::LeafNotFound)?;
+
+        let inclusion_proof = identity_tree
+            .inclusion_proof(identity_commitment, root.as_ref())?;
+
+        Ok((identity_commitment, inclusion_proof))
+    }
+
     /// Computes the updated root given a set of identity commitments.
     /// If a chain ID is provided, the updated root is calculated from the latest root on the specified chain.
-    /// If no chain ID is provided, the updated root is calculated from the latest root bridged to all chains.
+    /// If a root hash is provided, the updated root is calculated from that root. A chain ID and a root hash can not be combined.
+    /// If neither is provided, the updated root is calculated from the latest root bridged to all chains.
     pub async fn compute_root(
         &self,
         identity_commitements: &[Hash],
         chain_id: Option<ChainId>,
+        root_hash: Option<Hash>,
     ) -> Result<Hash, WorldTreeError<M>> {
         if !self.synced.load(Ordering::SeqCst) {
             return Err(WorldTreeError::TreeNotSynced);
         }
 
         let chain_state = self.chain_state.read().await;
+        let identity_tree = self.identity_tree.read().await;
 
-        let root = if let Some(chain_id) = chain_id {
+        let root =
+            request_root(&chain_state, &identity_tree, chain_id, root_hash)?;
+
+        let updated_root =
+            identity_tree.compute_root(identity_commitements, root.as_ref())?;
+
+        Ok(updated_root)
+    }
+}
+
+/// Resolves a root hash to the canonical root, returned as `None`, or to a pending root
+fn resolve_root(
+    identity_tree: &IdentityTree<MmapVec<Hash>>,
+    hash: Hash,
+) -> Result<Option<Root>, IdentityTreeError> {
+    if hash == identity_tree.root() {
+        return Ok(None);
+    }
+
+    let nonce = identity_tree.roots.get(&hash).copied().ok_or_else(|| {
+        match identity_tree.root_status(&hash) {
+            RootStatus::Pruned => IdentityTreeError::RootPruned,
+            _ => IdentityTreeError::RootNotFound,
+        }
+    })?;
+
+    Ok(Some(Root::new(hash, nonce)))
+}
+
+/// Resolves the root that a request is served against from either the latest root on a chain or a root hash,
+/// returning `None` for the canonical tree
+fn request_root<M: Middleware + 'static>(
+    chain_state: &HashMap<u64, Root>,
+    identity_tree: &IdentityTree<MmapVec<Hash>>,
+    chain_id: Option<ChainId>,
+    root_hash: Option<Hash>,
+) -> Result<Option<Root>, WorldTreeError<M>> {
+    match (chain_id, root_hash) {
+        (Some(_), Some(_)) => Err(WorldTreeError::InvalidRequest(
+            "chainId and root can not be combined".to_string(),
+        )),
+        (Some(chain_id), None) => {
             let root = chain_state
                 .get(&chain_id)
                 .ok_or(WorldTreeError::ChainIdNotFound)?;
 
-            Some(root)
-        } else {
-            None
-        };
+            Ok(Some(*root))
+        }
+        (None, Some(hash)) => Ok(resolve_root(identity_tree, hash)?),
+        (None, None) => Ok(None),
+    }
+}
 
-        let updated_root = self
-            .identity_tree
-            .read()
-            .await
-            .compute_root(identity_commitements, root)?;
+/// Computes the root of a tree of depth `tree_depth` containing `leaves` at their indices and `empty` everywhere else,
+/// hashing each level of the tree from scratch rather than through an `IdentityTree`. If an index appears more than once,
+/// the last leaf is used.
+///
+/// # Panics
+///
+/// Panics if a leaf index does not fit in a tree of depth `tree_depth`.
+pub fn compute_root_from_leaves(
+    tree_depth: usize,
+    leaves: &[(u32, Hash)],
+    empty: Hash,
+) -> Hash {
+    let mut level = BTreeMap::new();
+    for (leaf_idx, leaf) in leaves {
+        assert!(
+            (*leaf_idx as u64) < 1 << tree_depth,
+            "Leaf index {leaf_idx} out of range for tree depth {tree_depth}"
+        );
+        level.insert(*leaf_idx as u64, *leaf);
+    }
 
-        Ok(updated_root)
+    // Only the nodes with a non empty descendant are hashed, all other nodes at a level share the same empty hash
+    let mut empty_node = empty;
+    for _ in 0..tree_depth {
+        let mut parents = BTreeMap::new();
+        for &idx in level.keys() {
+            let parent_idx = idx / 2;
+            if parents.contains_key(&parent_idx) {
+                continue;
+            }
+
+            let left = level.get(&(parent_idx * 2)).unwrap_or(&empty_node);
+            let right = level.get(&(parent_idx * 2 + 1)).unwrap_or(&empty_node);
+            parents.insert(parent_idx, PoseidonHash::hash_node(left, right));
+        }
+
+        empty_node = PoseidonHash::hash_node(&empty_node, &empty_node);
+        level = parents;
     }
+
+    level.get(&0).copied().unwrap_or(empty_node)
 }
 
 macro_rules! primitive_newtype {
@@ -710,3 +1089,175 @@ macro_rules! primitive_newtype {
 primitive_newtype!(pub struct ChainId(u64));
 primitive_newtype!(pub struct NodeIndex(u32));
 primitive_newtype!(pub struct LeafIndex(u32));
+
+#[cfg(test)]
+impl WorldTree<ethers::providers::Provider<ethers::providers::MockProvider>> {
+    /// Returns a synced world tree without bridged chains, along with the mock serving responses to its provider.
+    /// Mocked responses are returned in reverse order.
+    pub(crate) async fn mocked(
+        tree_depth: usize,
+        cache: &PathBuf,
+    ) -> eyre::Result<(Self, ethers::providers::MockProvider)> {
+        use ethers::providers::Provider;
+        use ethers::types::{Bytes, H160};
+
+        let (provider, mock) = Provider::mocked();
+
+        // Responses to the calls made in `TreeManager::new`
+        mock.push(U256::from(1))?;
+        mock.push(Bytes::from(vec![0x60, 0x80]))?;
+        mock.push(U256::from(1))?;
+
+        let canonical_tree_manager = TreeManager::new(
+            H160::from_low_u64_be(1),
+            1000,
+            10,
+            0,
+            Arc::new(provider),
+        )
+        .await?;
+
+        let world_tree =
+            Self::new(tree_depth, canonical_tree_manager, vec![], cache)?;
+        world_tree.synced.store(true, Ordering::SeqCst);
+
+        Ok((world_tree, mock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use eyre::ContextCompat;
+    use semaphore::cascading_merkle_tree::CascadingMerkleTree;
+
+    use super::*;
+
+    const TREE_DEPTH: usize = 4;
+    const NUM_LEAVES: u32 = 1 << TREE_DEPTH;
+
+    #[test]
+    fn test_compute_root_from_leaves() -> eyre::Result<()> {
+        let leaves = (0..NUM_LEAVES)
+            .map(|leaf_idx| (leaf_idx, Hash::from(leaf_idx + 1)))
+            .collect::<Vec<_>>();
+
+        let mut identity_tree = IdentityTree::new(TREE_DEPTH);
+        assert_eq!(
+            compute_root_from_leaves(TREE_DEPTH, &[], Hash::ZERO),
+            identity_tree.root()
+        );
+
+        for (num_leaves, (leaf_idx, leaf)) in leaves.iter().enumerate() {
+            identity_tree.insert(*leaf_idx, *leaf)?;
+
+            assert_eq!(
+                compute_root_from_leaves(
+                    TREE_DEPTH,
+                    &leaves[..=num_leaves],
+                    Hash::ZERO
+                ),
+                identity_tree.root()
+            );
+        }
+
+        // The last leaf at an index replaces earlier ones
+        let mut overwritten = leaves.clone();
+        overwritten.insert(0, (5, Hash::from(100)));
+        assert_eq!(
+            compute_root_from_leaves(TREE_DEPTH, &overwritten, Hash::ZERO),
+            identity_tree.root()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_root_from_sparse_leaves() {
+        let leaves =
+            [(1, Hash::from(1)), (6, Hash::from(2)), (13, Hash::from(3))];
+
+        let mut padded = vec![Hash::ZERO; NUM_LEAVES as usize];
+        for (leaf_idx, leaf) in leaves {
+            padded[leaf_idx as usize] = leaf;
+        }
+        let tree: CascadingMerkleTree<PoseidonHash> =
+            CascadingMerkleTree::new_with_leaves(
+                vec![],
+                TREE_DEPTH,
+                &Hash::ZERO,
+                &padded,
+            );
+
+        assert_eq!(
+            compute_root_from_leaves(TREE_DEPTH, &leaves, Hash::ZERO),
+            tree.root()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_compute_root_from_leaves_out_of_range() {
+        compute_root_from_leaves(
+            TREE_DEPTH,
+            &[(NUM_LEAVES, Hash::from(1))],
+            Hash::ZERO,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_canonical_root_without_bridged_chains() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(TREE_DEPTH, &dir.path().join("cache")).await?;
+
+        // Restoring a tree without new updates marks the current root as canonical
+        world_tree.initialize_roots(&BTreeMap::new()).await?;
+        let empty_root = world_tree.identity_tree.read().await.root();
+        assert_eq!(
+            world_tree.identity_tree.read().await.canonical_root(),
+            Some(Root::new(empty_root, 0))
+        );
+
+        // Without bridged chains, canonical updates are applied to the canonical tree as they arrive
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let handle = world_tree.handle_canonical_updates(rx);
+
+        let leaf = Hash::from(1);
+        let mut expected_tree = IdentityTree::new(TREE_DEPTH);
+        expected_tree.insert(0, leaf)?;
+        let root = Root::new(expected_tree.root(), 1);
+        let tx_hash = H256::from_low_u64_be(1);
+
+        tx.send((
+            root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(0), leaf)])),
+            Some(tx_hash),
+        ))
+        .await?;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while world_tree.identity_tree.read().await.canonical_root()
+                != Some(root)
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        handle.abort();
+
+        // Finalized proofs are served against the applied root
+        let inclusion_proof = world_tree
+            .finalized_inclusion_proof(leaf)
+            .await?
+            .context("Missing inclusion proof")?;
+        assert_eq!(inclusion_proof.root, root.hash);
+        assert!(inclusion_proof.verify(leaf));
+
+        // The applied root can still be looked up by the transaction that produced it
+        assert_eq!(world_tree.root_by_tx(tx_hash).await, Some(root));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::IdentityTreeError;
+use super::identity_tree::{IdentityTree, LeafUpdates, Root};
+use super::Hash;
+
+/// A tree change event as consumed by the canonical tree, consisting of the root emitted onchain and the leaf updates that produced it
+#[derive(Serialize, Deserialize)]
+pub struct TreeChangeEvent {
+    pub root: Root,
+    pub updates: LeafUpdates,
+}
+
+/// The outcome of replaying a single `TreeChangeEvent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStep {
+    /// The root recorded with the event
+    pub expected_root: Root,
+    /// The root of the tree after applying the event
+    pub computed_root: Hash,
+}
+
+impl ReplayStep {
+    /// Returns true if the computed root does not match the recorded root
+    pub fn diverged(&self) -> bool {
+        self.expected_root.hash != self.computed_root
+    }
+}
+
+/// Reads recorded tree change events from a file containing one JSON encoded event per line
+pub fn read_events(
+    path: impl AsRef<Path>,
+) -> eyre::Result<Vec<TreeChangeEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut events = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        events.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(events)
+}
+
+/// Applies each event to a fresh tree in order, returning the root of the tree after each event
+pub fn replay_events(
+    tree_depth: usize,
+    events: impl IntoIterator<Item = TreeChangeEvent>,
+) -> Result<Vec<ReplayStep>, IdentityTreeError> {
+    let mut identity_tree = IdentityTree::new(tree_depth);
+
+    let mut steps = vec![];
+    for event in events {
+        identity_tree.append_updates(event.root, event.updates)?;
+        identity_tree.apply_updates_to_root(&event.root);
+
+        steps.push(ReplayStep {
+            expected_root: event.root,
+            computed_root: identity_tree.root(),
+        });
+    }
+
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use semaphore::cascading_merkle_tree::CascadingMerkleTree;
+    use semaphore::poseidon_tree::PoseidonHash;
+
+    use super::*;
+    use crate::tree::LeafIndex;
+
+    const TREE_DEPTH: usize = 3;
+
+    fn expected_root(leaves: &[Hash]) -> Hash {
+        CascadingMerkleTree::<PoseidonHash>::new_with_leaves(
+            vec![],
+            TREE_DEPTH,
+            &Hash::ZERO,
+            leaves,
+        )
+        .root()
+    }
+
+    fn insert_event(leaves: &[Hash], start: usize, nonce: usize) -> String {
+        let updates = leaves[start..]
+            .iter()
+            .enumerate()
+            .map(|(idx, leaf)| (LeafIndex((start + idx) as u32), *leaf))
+            .collect::<HashMap<LeafIndex, Hash>>();
+
+        let event = TreeChangeEvent {
+            root: Root {
+                hash: expected_root(leaves),
+                nonce,
+            },
+            updates: LeafUpdates::Insert(updates),
+        };
+
+        serde_json::to_string(&event).unwrap()
+    }
+
+    #[test]
+    fn test_replay_events() -> eyre::Result<()> {
+        let leaves = (1..=6).map(Hash::from).collect::<Vec<_>>();
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "{}", insert_event(&leaves[..2], 0, 1))?;
+        writeln!(file, "{}", insert_event(&leaves[..4], 2, 2))?;
+        writeln!(file, "{}", insert_event(&leaves, 4, 3))?;
+
+        let events = read_events(file.path())?;
+        assert_eq!(events.len(), 3);
+
+        let steps = replay_events(TREE_DEPTH, events)?;
+
+        assert!(steps.iter().all(|step| !step.diverged()));
+        assert_eq!(steps.last().unwrap().computed_root, expected_root(&leaves));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_events_divergence() -> eyre::Result<()> {
+        let leaves = (1..=4).map(Hash::from).collect::<Vec<_>>();
+
+        let mut events = vec![
+            serde_json::from_str::<TreeChangeEvent>(&insert_event(
+                &leaves[..2],
+                0,
+                1,
+            ))?,
+            serde_json::from_str::<TreeChangeEvent>(&insert_event(
+                &leaves, 2, 2,
+            ))?,
+        ];
+
+        // Record an incorrect root for the second event
+        events[1].root.hash = Hash::from(42);
+
+        let steps = replay_events(TREE_DEPTH, events)?;
+
+        let first_divergence = steps.iter().position(ReplayStep::diverged);
+        assert_eq!(first_divergence, Some(1));
+
+        Ok(())
+    }
+}
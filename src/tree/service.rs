@@ -1,30 +1,135 @@
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use axum::extract::rejection::{JsonRejection, QueryRejection};
 use axum::extract::{Query, State};
-use axum::http::StatusCode;
-use axum::{middleware, Json};
+use axum::http::{
+    header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+};
+use axum::{middleware, Extension, Json};
 use axum_middleware::logging;
+use axum_middleware::rate_limit::{self, RateLimiter};
+use axum_server::tls_rustls::RustlsConfig;
 use ethers::providers::Middleware;
+use ethers::types::H256;
+use eyre::WrapErr;
+#[cfg(feature = "metrics")]
+use metrics_exporter_prometheus::PrometheusHandle;
+use semaphore::generic_storage::GenericStorage;
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
+use tower_http::compression::predicate::SizeAbove;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
 
-use super::error::WorldTreeError;
+use super::config::{
+    CompressionConfig, CorsConfig, RateLimitConfig, ServerConfig,
+    StalledSyncConfig, TlsConfig,
+};
+use super::encoding::{EncodedField, EncodedInclusionProof, FieldEncoding};
+use super::error::{IdentityTreeError, WorldTreeError};
+use super::identity_tree::{IdentityTree, Root};
+#[cfg(feature = "metrics")]
+use super::metrics;
+#[cfg(feature = "metrics")]
+use super::task::METRICS_SERVER_TASK;
+use super::task::{
+    spawn_named, CANONICAL_LAG_MONITOR_TASK, HTTP_SERVER_TASK,
+    STALE_ROOT_MONITOR_TASK, STALLED_SYNC_MONITOR_TASK,
+};
 use super::{ChainId, Hash, InclusionProof, WorldTree};
 
+/// Maximum number of leaves returned in a single page by `/leaves`
+pub const MAX_LEAVES_PAGE_SIZE: usize = 1000;
+
+/// Header carrying the root that the proofs in a response were generated against
+pub const ROOT_HEADER: &str = "x-world-tree-root";
+/// Header carrying the nonce of the root that the proofs in a response were generated against, if the root is known
+pub const ROOT_NONCE_HEADER: &str = "x-world-tree-root-nonce";
+/// Header carrying the last block processed by the indexer when the response was served
+pub const SYNCED_BLOCK_HEADER: &str = "x-world-tree-synced-block";
+
+/// Hash function used to hash the nodes of the tree, reported by `/info`
+pub const HASH_FUNCTION: &str = "poseidon";
+
+/// Interval at which the lag of the canonical root behind the latest root is checked
+const CANONICAL_LAG_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval at which the number of consecutive failed sync attempts is checked when exiting on a stalled sync
+const STALLED_SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval at which the age of the latest canonical root is checked
+const STALE_ROOT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Service that keeps the World Tree synced with `WorldIDIdentityManager` and exposes an API endpoint to serve inclusion proofs for a given World ID.
 
 pub struct InclusionProofService<M: Middleware + 'static> {
     /// In-memory representation of the merkle tree containing all verified World IDs.
     pub world_tree: Arc<WorldTree<M>>,
+    /// Configuration for the HTTP server
+    pub config: Arc<ServerConfig>,
 }
 
 impl<M> InclusionProofService<M>
 where
     M: Middleware,
 {
-    pub fn new(world_tree: Arc<WorldTree<M>>) -> Self {
-        Self { world_tree }
+    pub fn new(world_tree: Arc<WorldTree<M>>, config: ServerConfig) -> Self {
+        Self {
+            world_tree,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Builds the router serving the API, health and info endpoints
+    pub fn router(&self) -> eyre::Result<axum::Router> {
+        let proof_routes: axum::Router<Arc<WorldTree<M>>> = rate_limited(
+            axum::Router::new()
+                .route("/inclusionProof", axum::routing::post(inclusion_proof))
+                .route(
+                    "/inclusionProofs",
+                    axum::routing::post(inclusion_proofs),
+                )
+                .route(
+                    "/inclusionProofByIndex",
+                    axum::routing::get(inclusion_proof_by_index),
+                )
+                .route("/paths", axum::routing::get(paths)),
+            self.config.proof_rate_limit.as_ref(),
+        );
+
+        let root_routes: axum::Router<Arc<WorldTree<M>>> = rate_limited(
+            axum::Router::new()
+                .route("/computeRoot", axum::routing::post(compute_root)),
+            self.config.root_rate_limit.as_ref(),
+        );
+
+        let mut api_routes = axum::Router::new()
+            .merge(proof_routes)
+            .merge(root_routes)
+            .route("/rootByTx", axum::routing::get(root_by_tx));
+
+        if self.config.leaves_endpoint {
+            api_routes =
+                api_routes.route("/leaves", axum::routing::get(leaves));
+        }
+
+        if let Some(compression) = &self.config.compression {
+            api_routes = api_routes.layer(compression_layer(compression));
+        }
+
+        if let Some(cors) = &self.config.cors {
+            api_routes = api_routes.layer(cors_layer(cors)?);
+        }
+
+        Ok(axum::Router::new()
+            .merge(api_routes)
+            .route("/health", axum::routing::get(health))
+            .route("/ready", axum::routing::get(ready))
+            .route("/info", axum::routing::get(info))
+            .layer(middleware::from_fn(logging::middleware))
+            .layer(Extension(self.config.clone()))
+            .with_state(self.world_tree.clone()))
     }
 
     /// Spawns an axum server and exposes an API endpoint to serve inclusion proofs for requested identity commitments.
@@ -46,21 +151,81 @@ where
         // Initialize a new router and spawn the server
         tracing::info!(?addr, "Initializing axum server");
 
-        let router = axum::Router::new()
-            .route("/inclusionProof", axum::routing::post(inclusion_proof))
-            .route("/computeRoot", axum::routing::post(compute_root))
-            .route("/health", axum::routing::get(health))
-            .layer(middleware::from_fn(logging::middleware))
-            .with_state(self.world_tree.clone());
+        let router = self.router()?;
 
-        let server_handle = tokio::spawn(async move {
-            tracing::info!("Spawning server");
-            axum::Server::bind(&addr)
-                .serve(router.into_make_service())
-                .await?;
+        let make_service =
+            router.into_make_service_with_connect_info::<SocketAddr>();
 
-            Ok(())
-        });
+        let server_handle = match &self.config.tls {
+            Some(tls) => {
+                // Load the certificate before spawning so that a misconfiguration fails startup
+                let rustls_config = load_tls_config(tls).await?;
+
+                spawn_named(HTTP_SERVER_TASK, async move {
+                    tracing::info!("Spawning server with TLS");
+                    axum_server::bind_rustls(addr, rustls_config)
+                        .serve(make_service)
+                        .await?;
+
+                    Ok(())
+                })
+            }
+            None => spawn_named(HTTP_SERVER_TASK, async move {
+                tracing::info!("Spawning server");
+                axum::Server::bind(&addr).serve(make_service).await?;
+
+                Ok(())
+            }),
+        };
+
+        if let Some(metrics_address) = self.config.metrics_address {
+            tracing::info!(?metrics_address, "Spawning metrics server");
+
+            handles.push(self.spawn_metrics_server(metrics_address)?);
+        }
+
+        if let Some(max_canonical_lag) = self.config.max_canonical_lag {
+            tracing::info!(max_canonical_lag, "Spawning canonical lag monitor");
+
+            handles.push(spawn_named(
+                CANONICAL_LAG_MONITOR_TASK,
+                monitor_canonical_lag(
+                    self.world_tree.clone(),
+                    max_canonical_lag,
+                ),
+            ));
+        }
+
+        if let Some(max_root_age) = self.config.max_root_age {
+            tracing::info!(max_root_age, "Spawning stale root monitor");
+
+            handles.push(spawn_named(
+                STALE_ROOT_MONITOR_TASK,
+                monitor_stale_root(
+                    self.world_tree.clone(),
+                    Duration::from_secs(max_root_age),
+                    self.config.max_sync_lag,
+                ),
+            ));
+        }
+
+        if let Some(stalled_sync) = self
+            .config
+            .stalled_sync
+            .clone()
+            .filter(|stalled_sync| stalled_sync.exit)
+        {
+            tracing::info!(
+                max_consecutive_failures =
+                    stalled_sync.max_consecutive_failures,
+                "Spawning stalled sync monitor"
+            );
+
+            handles.push(spawn_named(
+                STALLED_SYNC_MONITOR_TASK,
+                monitor_stalled_sync(self.world_tree.clone(), stalled_sync),
+            ));
+        }
 
         // Spawn a task to sync and maintain the state of the world tree
         tracing::info!("Spawning world tree");
@@ -70,6 +235,298 @@ where
 
         Ok(handles)
     }
+
+    /// Installs the Prometheus recorder and spawns a server exposing the recorded metrics at `metrics_address`
+    #[cfg(feature = "metrics")]
+    fn spawn_metrics_server(
+        &self,
+        metrics_address: SocketAddr,
+    ) -> eyre::Result<JoinHandle<Result<(), WorldTreeError<M>>>> {
+        let handle = metrics::install_prometheus_recorder()
+            .wrap_err("Failed to install the Prometheus recorder")?;
+        let metrics_router = metrics_router(self.world_tree.clone(), handle);
+
+        Ok(spawn_named(METRICS_SERVER_TASK, async move {
+            axum::Server::bind(&metrics_address)
+                .serve(metrics_router.into_make_service())
+                .await?;
+
+            Ok(())
+        }))
+    }
+
+    /// Fails startup, since the Prometheus exporter is only available with the `metrics` feature
+    #[cfg(not(feature = "metrics"))]
+    fn spawn_metrics_server(
+        &self,
+        _metrics_address: SocketAddr,
+    ) -> eyre::Result<JoinHandle<Result<(), WorldTreeError<M>>>> {
+        eyre::bail!("Serving metrics requires the `metrics` feature")
+    }
+}
+
+/// Applies a per-IP rate limit to all routes in the router, if configured
+fn rate_limited<S>(
+    router: axum::Router<S>,
+    config: Option<&RateLimitConfig>,
+) -> axum::Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    match config {
+        Some(config) => {
+            let rate_limiter = Arc::new(RateLimiter::new(
+                config.requests_per_second,
+                config.burst,
+            ));
+
+            router.route_layer(middleware::from_fn_with_state(
+                rate_limiter,
+                rate_limit::middleware,
+            ))
+        }
+        None => router,
+    }
+}
+
+/// Periodically checks how far the canonical root lags behind the latest root
+async fn monitor_canonical_lag<M: Middleware + 'static>(
+    world_tree: Arc<WorldTree<M>>,
+    max_canonical_lag: usize,
+) -> Result<(), WorldTreeError<M>> {
+    let mut interval = tokio::time::interval(CANONICAL_LAG_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let canonical_lag =
+            world_tree.identity_tree.read().await.canonical_lag();
+        check_canonical_lag(canonical_lag, max_canonical_lag);
+    }
+}
+
+/// Records the number of pending roots the canonical tree is behind the latest root, warning once it exceeds `max_canonical_lag`
+/// Returns `true` if the lag exceeds the threshold
+fn check_canonical_lag(canonical_lag: usize, max_canonical_lag: usize) -> bool {
+    ::metrics::gauge!("world_tree.canonical_lag").set(canonical_lag as f64);
+
+    let lagging = canonical_lag > max_canonical_lag;
+    if lagging {
+        tracing::warn!(
+            canonical_lag,
+            max_canonical_lag,
+            "Canonical root is lagging behind the latest root"
+        );
+    }
+
+    lagging
+}
+
+/// Cause of the latest canonical root going unchanged for longer than `max_root_age`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleRoot {
+    /// The indexer is synced to the chain head, so the source contract has not emitted any new roots
+    SourceIdle,
+    /// The indexer is failing or lagging behind the chain head, so new roots may not have been seen
+    UpdatesStopped,
+}
+
+/// Tracks when the latest canonical root last changed
+#[derive(Debug)]
+struct RootAge {
+    root: Hash,
+    changed_at: Instant,
+}
+
+impl RootAge {
+    fn new(root: Hash, now: Instant) -> Self {
+        Self {
+            root,
+            changed_at: now,
+        }
+    }
+
+    /// Returns the time since the root last changed, resetting it if `root` differs from the last observed root
+    fn observe(&mut self, root: Hash, now: Instant) -> Duration {
+        if root != self.root {
+            *self = Self::new(root, now);
+        }
+
+        now.saturating_duration_since(self.changed_at)
+    }
+}
+
+/// Periodically checks how long the latest canonical root has gone unchanged, warning once it exceeds `max_root_age`
+async fn monitor_stale_root<M: Middleware + 'static>(
+    world_tree: Arc<WorldTree<M>>,
+    max_root_age: Duration,
+    max_sync_lag: u64,
+) -> Result<(), WorldTreeError<M>> {
+    let mut interval = tokio::time::interval(STALE_ROOT_CHECK_INTERVAL);
+    let mut root_age: Option<RootAge> = None;
+
+    loop {
+        interval.tick().await;
+
+        let canonical_chain_id = world_tree.canonical_tree_manager.chain_id;
+        let Some(root) = world_tree
+            .chain_state
+            .read()
+            .await
+            .get(&canonical_chain_id)
+            .map(|root| root.hash)
+        else {
+            continue;
+        };
+
+        let now = Instant::now();
+        let age = match &mut root_age {
+            Some(root_age) => root_age.observe(root, now),
+            None => {
+                root_age = Some(RootAge::new(root, now));
+                Duration::ZERO
+            }
+        };
+
+        // A failed lag check is treated as lagging, since the indexer cannot be confirmed to be at the chain head
+        let synced = world_tree.sync_failures() == 0
+            && world_tree
+                .sync_lag()
+                .await
+                .is_ok_and(|sync_lag| sync_lag <= max_sync_lag);
+
+        check_root_age(age, max_root_age, synced);
+    }
+}
+
+/// Records the time since the latest canonical root changed, warning once it exceeds `max_root_age`
+/// Returns the cause of the stale root if the age exceeds the threshold
+fn check_root_age(
+    age: Duration,
+    max_root_age: Duration,
+    synced: bool,
+) -> Option<StaleRoot> {
+    ::metrics::gauge!("world_tree.root_age_seconds").set(age.as_secs_f64());
+
+    if age <= max_root_age {
+        return None;
+    }
+
+    let root_age = age.as_secs();
+    if synced {
+        tracing::warn!(
+            root_age,
+            "Canonical root has not changed, the source contract has not emitted new roots"
+        );
+
+        Some(StaleRoot::SourceIdle)
+    } else {
+        tracing::warn!(
+            root_age,
+            "Canonical root has not changed while the indexer is failing or lagging, updates may have stopped"
+        );
+
+        Some(StaleRoot::UpdatesStopped)
+    }
+}
+
+/// Periodically checks whether the canonical tree has stopped syncing, returning an error to shut down the service once
+/// `max_consecutive_failures` is reached
+async fn monitor_stalled_sync<M: Middleware + 'static>(
+    world_tree: Arc<WorldTree<M>>,
+    config: StalledSyncConfig,
+) -> Result<(), WorldTreeError<M>> {
+    let mut interval = tokio::time::interval(STALLED_SYNC_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let failures = world_tree.sync_failures();
+        if sync_stalled(failures, Some(&config)) {
+            let block = world_tree
+                .canonical_tree_manager
+                .block_scanner
+                .next_block
+                .load(Ordering::SeqCst);
+            tracing::error!(block, failures, "Sync stalled, shutting down");
+
+            return Err(WorldTreeError::SyncStalled { block, failures });
+        }
+    }
+}
+
+/// Returns `true` once the number of consecutive failed sync attempts reaches the configured threshold
+fn sync_stalled(failures: u32, config: Option<&StalledSyncConfig>) -> bool {
+    config.is_some_and(|config| failures >= config.max_consecutive_failures)
+}
+
+/// Loads the PEM encoded certificate chain and private key used to serve the API over HTTPS
+async fn load_tls_config(config: &TlsConfig) -> eyre::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .wrap_err_with(|| {
+            format!(
+                "Failed to load TLS certificate {} and key {}",
+                config.cert_path.display(),
+                config.key_path.display()
+            )
+        })
+}
+
+/// Builds a layer compressing responses above the configured size with the encoding negotiated via `Accept-Encoding`
+fn compression_layer(
+    config: &CompressionConfig,
+) -> CompressionLayer<SizeAbove> {
+    CompressionLayer::new()
+        .gzip(true)
+        .br(true)
+        .compress_when(SizeAbove::new(config.min_size))
+}
+
+/// Builds a CORS layer allowing the configured origins, methods and headers
+fn cors_layer(config: &CorsConfig) -> eyre::Result<CorsLayer> {
+    let is_wildcard = |values: &[String]| values.iter().any(|v| v == "*");
+
+    let allow_origin = if is_wildcard(&config.allowed_origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let allow_methods = if is_wildcard(&config.allowed_methods) {
+        AllowMethods::any()
+    } else {
+        AllowMethods::list(
+            config
+                .allowed_methods
+                .iter()
+                .map(|method| Method::from_bytes(method.as_bytes()))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let allow_headers = if is_wildcard(&config.allowed_headers) {
+        AllowHeaders::any()
+    } else {
+        AllowHeaders::list(
+            config
+                .allowed_headers
+                .iter()
+                .map(|header| HeaderName::from_bytes(header.as_bytes()))
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -86,18 +543,52 @@ impl InclusionProofRequest {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct InclusionProofsRequest {
+    pub identity_commitments: Vec<Hash>,
+    /// Root to generate the proofs against, which can not be combined with the `chainId` query parameter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Hash>,
+}
+
+impl InclusionProofsRequest {
+    pub fn new(identity_commitments: Vec<Hash>) -> InclusionProofsRequest {
+        Self {
+            identity_commitments,
+            root: None,
+        }
+    }
+
+    /// Requests the proofs against `root` rather than the latest root
+    pub fn with_root(mut self, root: Hash) -> Self {
+        self.root = Some(root);
+        self
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ComputeRootRequest {
     pub identity_commitments: Vec<Hash>,
+    /// Root to compute the updated root from, which can not be combined with the `chainId` query parameter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Hash>,
 }
 
 impl ComputeRootRequest {
     pub fn new(identity_commitments: Vec<Hash>) -> ComputeRootRequest {
         Self {
             identity_commitments,
+            root: None,
         }
     }
+
+    /// Computes the updated root from `root` rather than the latest root
+    pub fn with_root(mut self, root: Hash) -> Self {
+        self.root = Some(root);
+        self
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -106,35 +597,1507 @@ pub struct ChainIdQueryParams {
     chain_id: Option<ChainId>,
 }
 
-#[tracing::instrument(skip(world_tree))]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct InclusionProofQueryParams {
+    chain_id: Option<ChainId>,
+    /// Attaches a checksum to each proof so that clients can detect corrupted responses
+    #[serde(default)]
+    checksum: bool,
+    /// Serves the proof against the latest finalized root rather than the newest pending root
+    #[serde(default)]
+    finalized_only: bool,
+    /// Renders the field elements of each proof as `hex` or `decimal` strings
+    #[serde(default)]
+    encoding: FieldEncoding,
+}
+
+/// Builds the freshness headers for a response containing proofs against `root_hash`
+async fn proof_headers<M: Middleware + 'static>(
+    world_tree: &WorldTree<M>,
+    root_hash: Option<Hash>,
+) -> HeaderMap {
+    let root_nonce = match root_hash {
+        Some(root_hash) => world_tree
+            .root_info(&root_hash)
+            .await
+            .map(|root| root.nonce),
+        None => None,
+    };
+
+    freshness_headers(root_hash, root_nonce, world_tree.last_synced_block())
+}
+
+/// Builds the headers that allow clients to determine how fresh the proofs in a response are
+/// The root headers are omitted if the response does not contain a proof or the root is no longer tracked
+fn freshness_headers(
+    root_hash: Option<Hash>,
+    root_nonce: Option<usize>,
+    synced_block: u64,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    if let Some(root_hash) = root_hash {
+        headers.insert(
+            HeaderName::from_static(ROOT_HEADER),
+            HeaderValue::from_str(&format!("{root_hash:#x}"))
+                .expect("Hex encoded roots are valid header values"),
+        );
+    }
+
+    if let Some(root_nonce) = root_nonce {
+        headers.insert(
+            HeaderName::from_static(ROOT_NONCE_HEADER),
+            HeaderValue::from(root_nonce),
+        );
+    }
+
+    headers.insert(
+        HeaderName::from_static(SYNCED_BLOCK_HEADER),
+        HeaderValue::from(synced_block),
+    );
+
+    headers
+}
+
+/// Attaches a checksum to the proof if requested by the client
+fn maybe_checksum(
+    inclusion_proof: Option<InclusionProof>,
+    checksum: bool,
+) -> Option<InclusionProof> {
+    if checksum {
+        inclusion_proof.map(InclusionProof::with_checksum)
+    } else {
+        inclusion_proof
+    }
+}
+
+/// Returns an inclusion proof for the identity commitment. If `finalizedOnly` is set or `finalized_only` is configured,
+/// the proof is served against the finalized root since it is valid on every chain
+#[tracing::instrument(skip(world_tree, config))]
 pub async fn inclusion_proof<M: Middleware + 'static>(
     State(world_tree): State<Arc<WorldTree<M>>>,
-    Query(query_params): Query<ChainIdQueryParams>,
-    Json(req): Json<InclusionProofRequest>,
-) -> Result<(StatusCode, Json<Option<InclusionProof>>), WorldTreeError<M>> {
-    let chain_id = query_params.chain_id;
-    let inclusion_proof = world_tree
-        .inclusion_proof(req.identity_commitment, chain_id)
+    Extension(config): Extension<Arc<ServerConfig>>,
+    query_params: Result<Query<InclusionProofQueryParams>, QueryRejection>,
+    req: Result<Json<InclusionProofRequest>, JsonRejection>,
+) -> Result<
+    (StatusCode, HeaderMap, Json<Option<EncodedInclusionProof>>),
+    WorldTreeError<M>,
+> {
+    let Query(query_params) = query_params?;
+    let Json(req) = req?;
+
+    let inclusion_proof =
+        if query_params.finalized_only || config.finalized_only {
+            world_tree
+                .finalized_inclusion_proof(req.identity_commitment)
+                .await?
+        } else {
+            world_tree
+                .inclusion_proof(req.identity_commitment, query_params.chain_id)
+                .await?
+        };
+    let inclusion_proof =
+        maybe_checksum(inclusion_proof, query_params.checksum);
+
+    let headers = proof_headers(
+        &world_tree,
+        inclusion_proof.as_ref().map(|proof| proof.root),
+    )
+    .await;
+
+    let inclusion_proof = inclusion_proof
+        .map(|proof| EncodedInclusionProof::new(proof, query_params.encoding));
+
+    Ok((StatusCode::OK, headers, Json(inclusion_proof)))
+}
+
+/// Returns inclusion proofs for multiple identity commitments in the order requested, or `413` if the batch exceeds `max_batch_size`
+#[tracing::instrument(skip(world_tree, config, req), fields(batch_size))]
+pub async fn inclusion_proofs<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    Extension(config): Extension<Arc<ServerConfig>>,
+    query_params: Result<Query<InclusionProofQueryParams>, QueryRejection>,
+    req: Result<Json<InclusionProofsRequest>, JsonRejection>,
+) -> Result<
+    (
+        StatusCode,
+        HeaderMap,
+        Json<Vec<Option<EncodedInclusionProof>>>,
+    ),
+    WorldTreeError<M>,
+> {
+    let Query(query_params) = query_params?;
+    let Json(req) = req?;
+
+    tracing::Span::current()
+        .record("batch_size", req.identity_commitments.len());
+    check_batch_size(req.identity_commitments.len(), config.max_batch_size)?;
+
+    let inclusion_proofs = if query_params.finalized_only
+        || config.finalized_only
+    {
+        if req.root.is_some() {
+            return Err(WorldTreeError::InvalidRequest(
+                "root can not be combined with finalized proofs".to_string(),
+            ));
+        }
+
+        world_tree
+            .finalized_inclusion_proofs(&req.identity_commitments)
+            .await?
+    } else {
+        world_tree
+            .inclusion_proofs(
+                &req.identity_commitments,
+                query_params.chain_id,
+                req.root,
+            )
+            .await?
+    };
+
+    let inclusion_proofs: Vec<_> = inclusion_proofs
+        .into_iter()
+        .map(|proof| maybe_checksum(proof, query_params.checksum))
+        .collect();
+
+    // Every proof in the batch is generated against the same root
+    let root_hash = inclusion_proofs
+        .iter()
+        .flatten()
+        .map(|proof| proof.root)
+        .next();
+    let headers = proof_headers(&world_tree, root_hash).await;
+
+    let inclusion_proofs = inclusion_proofs
+        .into_iter()
+        .map(|proof| {
+            proof.map(|proof| {
+                EncodedInclusionProof::new(proof, query_params.encoding)
+            })
+        })
+        .collect();
+
+    Ok((StatusCode::OK, headers, Json(inclusion_proofs)))
+}
+
+fn check_batch_size<M: Middleware + 'static>(
+    size: usize,
+    max: usize,
+) -> Result<(), WorldTreeError<M>> {
+    if size > max {
+        return Err(WorldTreeError::BatchTooLarge { size, max });
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct InclusionProofByIndexQueryParams {
+    index: u32,
+    chain_id: Option<ChainId>,
+    /// Root to generate the proof against, which can not be combined with `chain_id`
+    root: Option<Hash>,
+    #[serde(default)]
+    checksum: bool,
+    /// Renders the identity commitment and the field elements of the proof as `hex` or `decimal` strings
+    #[serde(default)]
+    encoding: FieldEncoding,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InclusionProofByIndexResponse {
+    pub identity_commitment: EncodedField,
+    pub inclusion_proof: Option<EncodedInclusionProof>,
+}
+
+/// Returns the identity commitment at a leaf index along with its inclusion proof, or `404` if the index is empty
+#[tracing::instrument(skip(world_tree))]
+pub async fn inclusion_proof_by_index<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    query_params: Result<
+        Query<InclusionProofByIndexQueryParams>,
+        QueryRejection,
+    >,
+) -> Result<
+    (StatusCode, HeaderMap, Json<InclusionProofByIndexResponse>),
+    WorldTreeError<M>,
+> {
+    let Query(query_params) = query_params?;
+
+    let (identity_commitment, inclusion_proof) = world_tree
+        .inclusion_proof_by_index(
+            query_params.index,
+            query_params.chain_id,
+            query_params.root,
+        )
+        .await?;
+
+    let headers = proof_headers(
+        &world_tree,
+        inclusion_proof.as_ref().map(|proof| proof.root),
+    )
+    .await;
+
+    Ok((
+        StatusCode::OK,
+        headers,
+        Json(InclusionProofByIndexResponse {
+            identity_commitment: EncodedField::new(
+                identity_commitment,
+                query_params.encoding,
+            ),
+            inclusion_proof: maybe_checksum(
+                inclusion_proof,
+                query_params.checksum,
+            )
+            .map(|proof| {
+                EncodedInclusionProof::new(proof, query_params.encoding)
+            }),
+        }),
+    ))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PathsQueryParams {
+    from: u32,
+    to: u32,
+    root: Option<Hash>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PathsResponse {
+    pub root: Hash,
+    /// Sibling hashes ordered from the leaf to the root, for each leaf index in `from..to`
+    pub paths: Vec<Vec<Hash>>,
+}
+
+/// Returns the Merkle path for each leaf index in `from..to`, or `413` if the range is wider than `max_batch_size`
+#[tracing::instrument(skip(world_tree, config))]
+pub async fn paths<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    Extension(config): Extension<Arc<ServerConfig>>,
+    query_params: Result<Query<PathsQueryParams>, QueryRejection>,
+) -> Result<(StatusCode, Json<PathsResponse>), WorldTreeError<M>> {
+    let Query(query_params) = query_params?;
+
+    let width = query_params.to.saturating_sub(query_params.from) as usize;
+    check_batch_size(width, config.max_batch_size)?;
+
+    let (root, paths) = world_tree
+        .paths(query_params.from, query_params.to, query_params.root)
         .await?;
 
-    Ok((StatusCode::OK, Json(inclusion_proof)))
+    Ok((StatusCode::OK, Json(PathsResponse { root, paths })))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RootByTxQueryParams {
+    tx_hash: H256,
+}
+
+/// Returns the pending or canonical root produced by the transaction `txHash`, or `404` if the transaction is unknown
+/// or older than the `ROOT_TX_RETENTION` most recent transactions
+#[tracing::instrument(level = "debug", skip(world_tree))]
+pub async fn root_by_tx<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    query_params: Result<Query<RootByTxQueryParams>, QueryRejection>,
+) -> Result<(StatusCode, Json<Root>), WorldTreeError<M>> {
+    let Query(query_params) = query_params?;
+
+    let root = world_tree
+        .root_by_tx(query_params.tx_hash)
+        .await
+        .ok_or(IdentityTreeError::RootNotFound)?;
+
+    Ok((StatusCode::OK, Json(root)))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LeavesQueryParams {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LeavesResponse {
+    /// Number of leaves in the canonical tree, including deleted leaves
+    pub total: usize,
+    /// `(index, identity_commitment)` pairs ordered by index, deleted leaves are zero
+    pub leaves: Vec<(u32, Hash)>,
+}
+
+/// Returns a page of the leaves in the canonical tree, with `limit` capped at `MAX_LEAVES_PAGE_SIZE`
+#[tracing::instrument(skip(world_tree))]
+pub async fn leaves<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    query_params: Result<Query<LeavesQueryParams>, QueryRejection>,
+) -> Result<(StatusCode, Json<LeavesResponse>), WorldTreeError<M>> {
+    let Query(query_params) = query_params?;
+
+    let limit = query_params
+        .limit
+        .unwrap_or(MAX_LEAVES_PAGE_SIZE)
+        .min(MAX_LEAVES_PAGE_SIZE);
+
+    let (total, leaves) =
+        world_tree.leaves_page(query_params.offset, limit).await?;
+
+    Ok((StatusCode::OK, Json(LeavesResponse { total, leaves })))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyResponse {
+    pub synced: bool,
+    /// Number of blocks the canonical tree is behind the chain head
+    pub lag: u64,
+    /// Number of pending roots that have not been applied to the canonical tree
+    pub canonical_lag: usize,
+    /// Number of consecutive failed attempts to sync the canonical tree
+    pub sync_failures: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InfoResponse {
+    /// Depth of the tree
+    pub depth: usize,
+    /// Hash function used to hash the nodes of the tree
+    pub hash: String,
+    /// Number of leaves in the canonical tree, including deleted leaves
+    pub leaf_count: usize,
+    /// Root of the canonical tree
+    pub canonical_root: Hash,
+}
+
+impl InfoResponse {
+    pub fn new<S>(identity_tree: &IdentityTree<S>) -> Self
+    where
+        S: GenericStorage<Hash>,
+    {
+        Self {
+            depth: identity_tree.depth(),
+            hash: HASH_FUNCTION.to_string(),
+            leaf_count: identity_tree.tree.num_leaves(),
+            canonical_root: identity_tree.root(),
+        }
+    }
+}
+
+/// Returns the parameters of the tree needed to configure a verifier, along with the size and root of the canonical tree
+#[tracing::instrument(level = "debug", skip(world_tree))]
+pub async fn info<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+) -> (StatusCode, Json<InfoResponse>) {
+    let identity_tree = world_tree.identity_tree.read().await;
+
+    (StatusCode::OK, Json(InfoResponse::new(&identity_tree)))
 }
 
+/// Returns `200` as long as the process is alive, regardless of the sync status of the tree
 #[tracing::instrument(level = "debug")]
 pub async fn health() -> StatusCode {
     StatusCode::OK
 }
 
+/// Returns `200` once the tree is synced and within `max_sync_lag` blocks of the chain head, otherwise `503`.
+/// Also returns `503` once syncing has failed `stalled_sync.max_consecutive_failures` times in a row.
+#[tracing::instrument(level = "debug", skip(world_tree, config))]
+pub async fn ready<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    Extension(config): Extension<Arc<ServerConfig>>,
+) -> Result<(StatusCode, Json<ReadyResponse>), WorldTreeError<M>> {
+    let synced = world_tree.synced.load(Ordering::SeqCst);
+    let lag = world_tree.sync_lag().await?;
+    let canonical_lag = world_tree.identity_tree.read().await.canonical_lag();
+    let sync_failures = world_tree.sync_failures();
+
+    let status_code = readiness_status(
+        synced,
+        lag,
+        config.max_sync_lag,
+        sync_stalled(sync_failures, config.stalled_sync.as_ref()),
+    );
+
+    Ok((
+        status_code,
+        Json(ReadyResponse {
+            synced,
+            lag,
+            canonical_lag,
+            sync_failures,
+        }),
+    ))
+}
+
+fn readiness_status(
+    synced: bool,
+    lag: u64,
+    max_sync_lag: u64,
+    stalled: bool,
+) -> StatusCode {
+    if synced && lag <= max_sync_lag && !stalled {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Builds the router serving the metrics recorded by the Prometheus recorder
+#[cfg(feature = "metrics")]
+fn metrics_router<M: Middleware + 'static>(
+    world_tree: Arc<WorldTree<M>>,
+    handle: PrometheusHandle,
+) -> axum::Router {
+    axum::Router::new()
+        .route("/metrics", axum::routing::get(metrics))
+        .layer(Extension(handle))
+        .with_state(world_tree)
+}
+
+/// Records the tree gauges and serves every recorded metric in the Prometheus text exposition format
+/// The gauges are read from memory, so scrapes do not issue any requests to the provider
+#[cfg(feature = "metrics")]
+#[tracing::instrument(level = "debug", skip(world_tree, handle))]
+pub async fn metrics<M: Middleware + 'static>(
+    State(world_tree): State<Arc<WorldTree<M>>>,
+    Extension(handle): Extension<PrometheusHandle>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    world_tree.tree_gauges().await.record();
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+}
+
 #[tracing::instrument(level = "debug", skip(world_tree))]
 pub async fn compute_root<M: Middleware + 'static>(
     State(world_tree): State<Arc<WorldTree<M>>>,
-    Query(query_params): Query<ChainIdQueryParams>,
-    Json(req): Json<ComputeRootRequest>,
+    query_params: Result<Query<ChainIdQueryParams>, QueryRejection>,
+    req: Result<Json<ComputeRootRequest>, JsonRejection>,
 ) -> Result<(StatusCode, Json<Hash>), WorldTreeError<M>> {
+    let Query(query_params) = query_params?;
+    let Json(req) = req?;
+
     let chain_id = query_params.chain_id;
     let updated_root = world_tree
-        .compute_root(&req.identity_commitments, chain_id)
+        .compute_root(&req.identity_commitments, chain_id, req.root)
         .await?;
 
     Ok((StatusCode::OK, Json(updated_root)))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    use axum::response::IntoResponse;
+    use eyre::ContextCompat;
+
+    use super::*;
+    use crate::tree::compute_root_from_leaves;
+    use crate::tree::error::ErrorResponse;
+    use crate::tree::identity_tree::{IdentityTree, LeafUpdates, Root};
+    use crate::tree::LeafIndex;
+
+    /// Serves the service router on a local port, returning its base url
+    fn spawn_service<M: Middleware + 'static>(
+        world_tree: Arc<WorldTree<M>>,
+        config: ServerConfig,
+    ) -> eyre::Result<String> {
+        let router = InclusionProofService::new(world_tree, config).router()?;
+
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        Ok(url)
+    }
+
+    async fn spawn_cors_server(config: &CorsConfig) -> eyre::Result<String> {
+        let router = axum::Router::new()
+            .route("/inclusionProof", axum::routing::post(|| async {}))
+            .layer(cors_layer(config)?);
+
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(router.into_make_service());
+        let url = format!("http://{}/inclusionProof", server.local_addr());
+        tokio::spawn(server);
+
+        Ok(url)
+    }
+
+    async fn preflight(
+        url: &str,
+        origin: &str,
+    ) -> eyre::Result<reqwest::Response> {
+        Ok(reqwest::Client::new()
+            .request(Method::OPTIONS, url)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+            .send()
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() -> eyre::Result<()> {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://example.com".to_string()],
+            allowed_methods: vec!["POST".to_string()],
+            allowed_headers: vec!["content-type".to_string()],
+        };
+        let url = spawn_cors_server(&config).await?;
+
+        let response = preflight(&url, "https://example.com").await?;
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type"
+        );
+
+        // Origins that are not configured are not allowed
+        let response = preflight(&url, "https://other.com").await?;
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cors_wildcard() -> eyre::Result<()> {
+        let config = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["*".to_string()],
+            allowed_headers: vec!["*".to_string()],
+        };
+        let url = spawn_cors_server(&config).await?;
+
+        let response = preflight(&url, "https://other.com").await?;
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "*"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(7, &dir.path().join("cache")).await?;
+
+        {
+            let mut identity_tree = world_tree.identity_tree.write().await;
+            for idx in 0..100 {
+                identity_tree.insert(idx, Hash::from(idx + 1))?;
+            }
+        }
+
+        let config = ServerConfig {
+            leaves_endpoint: true,
+            compression: Some(CompressionConfig { min_size: 1024 }),
+            ..Default::default()
+        };
+        let url = spawn_service(Arc::new(world_tree), config)?;
+
+        let client = reqwest::Client::new();
+        let get_leaves = |encoding: Option<&str>| {
+            let mut request = client.get(format!("{url}/leaves"));
+            if let Some(encoding) = encoding {
+                request = request.header(header::ACCEPT_ENCODING, encoding);
+            }
+            request.send()
+        };
+
+        let uncompressed = get_leaves(None).await?;
+        assert!(uncompressed
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .is_none());
+        let uncompressed = uncompressed.bytes().await?;
+        assert!(uncompressed.len() > 1024);
+
+        let compressed = get_leaves(Some("gzip")).await?;
+        assert_eq!(compressed.headers()[header::CONTENT_ENCODING], "gzip");
+        let compressed = compressed.bytes().await?;
+        assert!(compressed.len() < uncompressed.len());
+
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(compressed.as_ref())
+            .read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, uncompressed);
+
+        // Responses below the threshold are not compressed
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .json(&InclusionProofRequest::new(Hash::from(1)))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let inclusion_proof = response
+            .json::<Option<InclusionProof>>()
+            .await?
+            .context("Missing proof")?;
+        assert!(inclusion_proof.verify(Hash::from(1)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_freshness_headers() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        let leaves = (1..=4_u64).map(Hash::from).collect::<Vec<_>>();
+        let pending_root = populate_pending(&world_tree, &leaves).await?;
+        world_tree
+            .canonical_tree_manager
+            .block_scanner
+            .next_block
+            .store(101, Ordering::SeqCst);
+        let canonical_root = world_tree.identity_tree.read().await.root();
+
+        let url = spawn_service(Arc::new(world_tree), ServerConfig::default())?;
+        let client = reqwest::Client::new();
+
+        // Proofs of pending leaves carry the pending root and its nonce
+        let request = InclusionProofsRequest::new(vec![leaves[3]])
+            .with_root(pending_root.hash);
+        let response = client
+            .post(format!("{url}/inclusionProofs"))
+            .json(&request)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers().clone();
+        let proofs = response.json::<Vec<Option<InclusionProof>>>().await?;
+        let proof = proofs[0].as_ref().context("Missing proof")?;
+        assert_eq!(headers[ROOT_HEADER], format!("{:#x}", proof.root).as_str());
+        assert_eq!(proof.root, pending_root.hash);
+        assert_eq!(headers[ROOT_NONCE_HEADER], "1");
+        assert_eq!(headers[SYNCED_BLOCK_HEADER], "100");
+
+        // Proofs against the canonical tree carry the canonical root
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .json(&InclusionProofRequest::new(leaves[1]))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers().clone();
+        let proof = response
+            .json::<Option<InclusionProof>>()
+            .await?
+            .context("Missing proof")?;
+        assert_eq!(proof.root, canonical_root);
+        assert_eq!(
+            headers[ROOT_HEADER],
+            format!("{canonical_root:#x}").as_str()
+        );
+        assert_eq!(headers[SYNCED_BLOCK_HEADER], "100");
+
+        // Responses without a proof only carry the synced block
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .json(&InclusionProofRequest::new(Hash::from(42_u64)))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert!(headers.get(ROOT_HEADER).is_none());
+        assert!(headers.get(ROOT_NONCE_HEADER).is_none());
+        assert_eq!(headers[SYNCED_BLOCK_HEADER], "100");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tls() -> eyre::Result<()> {
+        let cert =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_pem = cert.serialize_pem()?;
+
+        let dir = tempfile::tempdir()?;
+        let tls = TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+        };
+        std::fs::write(&tls.cert_path, &cert_pem)?;
+        std::fs::write(&tls.key_path, cert.serialize_private_key_pem())?;
+
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        world_tree
+            .identity_tree
+            .write()
+            .await
+            .insert(0, Hash::from(1))?;
+
+        let config = ServerConfig {
+            tls: Some(tls.clone()),
+            ..Default::default()
+        };
+        let router = InclusionProofService::new(Arc::new(world_tree), config)
+            .router()?;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let server = axum_server::from_tcp_rustls(
+            listener,
+            load_tls_config(&tls).await?,
+        )
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>());
+        tokio::spawn(server);
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(
+                cert_pem.as_bytes(),
+            )?)
+            .build()?;
+
+        let response = client
+            .post(format!("https://localhost:{port}/inclusionProof"))
+            .json(&InclusionProofRequest::new(Hash::from(1)))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let inclusion_proof = response
+            .json::<Option<InclusionProof>>()
+            .await?
+            .context("Missing proof")?;
+        assert!(inclusion_proof.verify(Hash::from(1)));
+
+        // Plain HTTP is not served on the TLS port
+        let result = client
+            .post(format!("http://localhost:{port}/inclusionProof"))
+            .json(&InclusionProofRequest::new(Hash::from(1)))
+            .send()
+            .await;
+        assert!(result.is_err() || !result?.status().is_success());
+
+        // Missing certificates fail with the offending paths in the error
+        let missing = TlsConfig {
+            cert_path: dir.path().join("missing.pem"),
+            key_path: tls.key_path.clone(),
+        };
+        let err = load_tls_config(&missing).await.unwrap_err();
+        assert!(err.to_string().contains("missing.pem"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        let leaves = (1..=5_u64).map(Hash::from).collect::<Vec<_>>();
+        let pruned_root = populate_pending(&world_tree, &leaves[..4]).await?;
+
+        {
+            // Applying a later root prunes the pending root
+            let mut identity_tree = world_tree.identity_tree.write().await;
+            let indexed_leaves = leaves
+                .iter()
+                .enumerate()
+                .map(|(idx, leaf)| (idx as u32, *leaf))
+                .collect::<Vec<_>>();
+            let root = Root::new(
+                compute_root_from_leaves(3, &indexed_leaves, Hash::ZERO),
+                2,
+            );
+            identity_tree.append_updates(
+                root,
+                LeafUpdates::Insert(HashMap::from([(LeafIndex(4), leaves[4])])),
+            )?;
+            identity_tree.apply_updates_to_root(&root);
+
+            // Point a commitment at the wrong leaf so that its proof fails verification
+            identity_tree.leaves.insert(Hash::from(42_u64), 0);
+        }
+
+        let world_tree = Arc::new(world_tree);
+        let url = spawn_service(world_tree.clone(), ServerConfig::default())?;
+        let client = reqwest::Client::new();
+
+        let assert_error = |response: reqwest::Response,
+                            status: StatusCode,
+                            code: &'static str| async move {
+            assert_eq!(response.status(), status);
+            let error = response.json::<ErrorResponse>().await?;
+            assert_eq!(error.error.code, code);
+            assert!(!error.error.message.is_empty());
+
+            eyre::Ok(())
+        };
+
+        // Malformed hex in the request body
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .json(&serde_json::json!({ "identityCommitment": "0xzz" }))
+            .send()
+            .await?;
+        assert_error(response, StatusCode::BAD_REQUEST, "invalid_request")
+            .await?;
+
+        // Malformed query parameters
+        let response = client
+            .post(format!("{url}/inclusionProof?chainId=mainnet"))
+            .json(&InclusionProofRequest::new(leaves[0]))
+            .send()
+            .await?;
+        assert_error(response, StatusCode::BAD_REQUEST, "invalid_request")
+            .await?;
+
+        // Chains that are not bridged
+        let response = client
+            .post(format!("{url}/inclusionProof?chainId=1"))
+            .json(&InclusionProofRequest::new(leaves[0]))
+            .send()
+            .await?;
+        assert_error(response, StatusCode::NOT_FOUND, "chain_id_not_found")
+            .await?;
+
+        // Leaves missing from the tree
+        let response = client
+            .get(format!("{url}/inclusionProofByIndex"))
+            .query(&[("index", 6)])
+            .send()
+            .await?;
+        assert_error(response, StatusCode::NOT_FOUND, "leaf_not_found").await?;
+
+        // Roots that have been pruned
+        let response = client
+            .get(format!("{url}/inclusionProofByIndex"))
+            .query(&[
+                ("index", "0"),
+                ("root", format!("{:#x}", pruned_root.hash).as_str()),
+            ])
+            .send()
+            .await?;
+        assert_error(response, StatusCode::GONE, "root_pruned").await?;
+
+        // Proofs that fail verification against the tree
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .json(&InclusionProofRequest::new(Hash::from(42_u64)))
+            .send()
+            .await?;
+        assert_error(response, StatusCode::INTERNAL_SERVER_ERROR, "internal")
+            .await?;
+
+        // Requests while the tree is syncing
+        world_tree.synced.store(false, Ordering::SeqCst);
+        let response = client
+            .post(format!("{url}/inclusionProof"))
+            .json(&InclusionProofRequest::new(leaves[0]))
+            .send()
+            .await?;
+        assert_error(
+            response,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "tree_not_synced",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_batch_size_limit() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+
+        {
+            let mut identity_tree = world_tree.identity_tree.write().await;
+            for idx in 0..3 {
+                identity_tree.insert(idx, Hash::from(idx + 1))?;
+            }
+        }
+
+        let config = ServerConfig {
+            max_batch_size: 2,
+            ..Default::default()
+        };
+        let url = spawn_service(Arc::new(world_tree), config)?;
+        let url = format!("{url}/inclusionProofs");
+
+        let client = reqwest::Client::new();
+        let request = |num_commitments: u64| {
+            InclusionProofsRequest::new(
+                (1..=num_commitments).map(Hash::from).collect(),
+            )
+        };
+
+        // Batches within the limit are served
+        let response = client.post(&url).json(&request(2)).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let proofs = response.json::<Vec<Option<InclusionProof>>>().await?;
+        assert_eq!(proofs.len(), 2);
+        for (idx, proof) in proofs.iter().enumerate() {
+            let proof = proof.as_ref().context("Missing proof")?;
+            assert!(proof.verify(Hash::from(idx as u64 + 1)));
+        }
+
+        // Batches exceeding the limit are rejected before any proof is constructed
+        let response = client.post(&url).json(&request(3)).send().await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let error = response.json::<ErrorResponse>().await?;
+        assert_eq!(error.error.code, "batch_too_large");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leaves_endpoint() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+
+        {
+            let mut identity_tree = world_tree.identity_tree.write().await;
+            for idx in 0..5 {
+                identity_tree.insert(idx, Hash::from(idx + 1))?;
+            }
+            identity_tree.remove(2)?;
+        }
+        let world_tree = Arc::new(world_tree);
+        let client = reqwest::Client::new();
+
+        // The endpoint is only served when enabled
+        let url = spawn_service(world_tree.clone(), ServerConfig::default())?;
+        let response = client.get(format!("{url}/leaves")).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = ServerConfig {
+            leaves_endpoint: true,
+            ..Default::default()
+        };
+        let url = spawn_service(world_tree, config)?;
+
+        // Page through the tree until an empty page is returned
+        let mut leaves = vec![];
+        loop {
+            let response = client
+                .get(format!("{url}/leaves"))
+                .query(&[("offset", leaves.len()), ("limit", 2)])
+                .send()
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let page = response.json::<LeavesResponse>().await?;
+            assert_eq!(page.total, 5);
+            assert!(page.leaves.len() <= 2);
+
+            if page.leaves.is_empty() {
+                break;
+            }
+            leaves.extend(page.leaves);
+        }
+
+        assert_eq!(
+            leaves,
+            vec![
+                (0, Hash::from(1)),
+                (1, Hash::from(2)),
+                (2, Hash::ZERO),
+                (3, Hash::from(4)),
+                (4, Hash::from(5)),
+            ]
+        );
+
+        // Offsets past the end of the tree are rejected
+        let response = client
+            .get(format!("{url}/leaves"))
+            .query(&[("offset", 6)])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    /// Builds a tree with three canonical leaves and a fourth leaf pending at the returned root
+    async fn populate_pending<M: Middleware + 'static>(
+        world_tree: &WorldTree<M>,
+        leaves: &[Hash],
+    ) -> eyre::Result<Root> {
+        let mut identity_tree = world_tree.identity_tree.write().await;
+        for (idx, leaf) in leaves[..3].iter().enumerate() {
+            identity_tree.insert(idx as u32, *leaf)?;
+        }
+
+        let indexed_leaves = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, leaf)| (idx as u32, *leaf))
+            .collect::<Vec<_>>();
+        let root = Root::new(
+            compute_root_from_leaves(
+                identity_tree.depth(),
+                &indexed_leaves,
+                Hash::ZERO,
+            ),
+            1,
+        );
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([(LeafIndex(3), leaves[3])])),
+        )?;
+
+        Ok(root)
+    }
+
+    #[tokio::test]
+    async fn test_inclusion_proof_by_index_endpoint() -> eyre::Result<()> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Response {
+            identity_commitment: Hash,
+            inclusion_proof: Option<InclusionProof>,
+        }
+
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        let leaves = (1..=4_u64).map(Hash::from).collect::<Vec<_>>();
+        let pending_root = populate_pending(&world_tree, &leaves).await?;
+
+        let url = spawn_service(Arc::new(world_tree), ServerConfig::default())?;
+        let url = format!("{url}/inclusionProofByIndex");
+        let client = reqwest::Client::new();
+        let root = format!("{:#x}", pending_root.hash);
+
+        // Leaves in the canonical tree are proven against the canonical root by default
+        let response = client.get(&url).query(&[("index", 1)]).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = response.json::<Response>().await?;
+        assert_eq!(response.identity_commitment, leaves[1]);
+        let proof = response.inclusion_proof.context("Missing proof")?;
+        assert!(proof.verify(leaves[1]));
+
+        // Pending leaves are proven against the requested root
+        let response = client
+            .get(&url)
+            .query(&[("index", "3"), ("root", root.as_str())])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = response.json::<Response>().await?;
+        assert_eq!(response.identity_commitment, leaves[3]);
+        let proof = response.inclusion_proof.context("Missing proof")?;
+        assert_eq!(proof.root, pending_root.hash);
+        assert!(proof.verify(leaves[3]));
+
+        // The pending leaf is not in the canonical tree
+        let response = client.get(&url).query(&[("index", 3)]).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Unknown roots are not found
+        let unknown_root = format!("{:#x}", Hash::from(42_u64));
+        let response = client
+            .get(&url)
+            .query(&[("index", "0"), ("root", unknown_root.as_str())])
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = response.json::<ErrorResponse>().await?;
+        assert_eq!(error.error.code, "root_not_found");
+
+        // A chain ID and a root can not be combined
+        let query = [("index", "0"), ("chainId", "1"), ("root", root.as_str())];
+        let response = client.get(&url).query(&query).send().await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_inclusion_proofs_endpoint() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        let leaves = (1..=4_u64).map(Hash::from).collect::<Vec<_>>();
+        let pending_root = populate_pending(&world_tree, &leaves).await?;
+
+        let url = spawn_service(Arc::new(world_tree), ServerConfig::default())?;
+        let url = format!("{url}/inclusionProofs");
+        let client = reqwest::Client::new();
+        let commitments = vec![leaves[1], Hash::from(42_u64), leaves[3]];
+
+        // Pending leaves are proven against the requested root and absent commitments have no proof
+        let request = InclusionProofsRequest::new(commitments.clone())
+            .with_root(pending_root.hash);
+        let response = client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let proofs = response.json::<Vec<Option<InclusionProof>>>().await?;
+        assert_eq!(proofs.len(), 3);
+        assert!(proofs[1].is_none());
+        for idx in [0, 2] {
+            let proof = proofs[idx].as_ref().context("Missing proof")?;
+            assert_eq!(proof.root, pending_root.hash);
+            assert!(proof.verify(commitments[idx]));
+        }
+
+        // Without a root the proofs are generated against the canonical tree
+        let request = InclusionProofsRequest::new(commitments.clone());
+        let response = client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let proofs = response.json::<Vec<Option<InclusionProof>>>().await?;
+        let proof = proofs[0].as_ref().context("Missing proof")?;
+        assert!(proof.verify(leaves[1]));
+        assert!(proofs[1].is_none());
+        assert!(proofs[2].is_none());
+
+        // A root can not be combined with a chain ID or finalized proofs
+        let request = InclusionProofsRequest::new(commitments)
+            .with_root(pending_root.hash);
+        for query in [("chainId", "1"), ("finalizedOnly", "true")] {
+            let response = client
+                .post(&url)
+                .query(&[query])
+                .json(&request)
+                .send()
+                .await?;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compute_root_endpoint() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+        let leaves = (1..=6_u64).map(Hash::from).collect::<Vec<_>>();
+        let pending_root = populate_pending(&world_tree, &leaves).await?;
+
+        let url = spawn_service(Arc::new(world_tree), ServerConfig::default())?;
+        let url = format!("{url}/computeRoot");
+        let client = reqwest::Client::new();
+        let expected_root = |leaves: &[Hash]| {
+            let indexed_leaves = leaves
+                .iter()
+                .enumerate()
+                .map(|(idx, leaf)| (idx as u32, *leaf))
+                .collect::<Vec<_>>();
+            compute_root_from_leaves(3, &indexed_leaves, Hash::ZERO)
+        };
+
+        // The commitments are appended after the pending leaf at the requested root
+        let request = ComputeRootRequest::new(leaves[4..].to_vec())
+            .with_root(pending_root.hash);
+        let response = client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.json::<Hash>().await?, expected_root(&leaves));
+
+        // Without a root the commitments are appended at the latest root, which includes the pending leaf
+        let request = ComputeRootRequest::new(leaves[4..].to_vec());
+        let response = client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.json::<Hash>().await?, expected_root(&leaves));
+
+        // Unknown roots are not found
+        let request = ComputeRootRequest::new(leaves[4..].to_vec())
+            .with_root(Hash::from(42_u64));
+        let response = client.post(&url).json(&request).send().await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // A root can not be combined with a chain ID
+        let request = ComputeRootRequest::new(leaves[4..].to_vec())
+            .with_root(pending_root.hash);
+        let response = client
+            .post(&url)
+            .query(&[("chainId", "1")])
+            .json(&request)
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics_scrape() -> eyre::Result<()> {
+        use crate::tree::metrics::MetricsRecorder;
+
+        // The recorder must be installed before any metric is recorded, since metrics recorded without one are dropped
+        let handle = metrics::install_prometheus_recorder()?;
+
+        let dir = tempfile::tempdir()?;
+        let (world_tree, _mock) =
+            WorldTree::mocked(3, &dir.path().join("cache")).await?;
+
+        {
+            let mut identity_tree = world_tree.identity_tree.write().await;
+            identity_tree.metrics = Arc::new(MetricsRecorder);
+            for idx in 0..3 {
+                identity_tree.insert(idx, Hash::from(idx + 1))?;
+            }
+            identity_tree.inclusion_proof(Hash::from(1), None)?;
+        }
+
+        // The sync lag is derived from the last observed chain head, since no responses are mocked for the scrape
+        let block_scanner = &world_tree.canonical_tree_manager.block_scanner;
+        block_scanner.latest_block.store(10, Ordering::SeqCst);
+        block_scanner.next_block.store(6, Ordering::SeqCst);
+
+        let router = metrics_router(Arc::new(world_tree), handle);
+
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(router.into_make_service());
+        let url = format!("http://{}/metrics", server.local_addr());
+        tokio::spawn(server);
+
+        let response = reqwest::get(&url).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.text().await?;
+
+        let value = |name: &str| {
+            body.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .and_then(|value| value.parse::<f64>().ok())
+        };
+
+        assert_eq!(value("world_tree_num_leaves"), Some(3.0));
+        assert_eq!(value("world_tree_pending_roots"), Some(0.0));
+        assert_eq!(value("world_tree_sync_lag_blocks"), Some(5.0));
+        assert_eq!(value("world_tree_proof_latency_count"), Some(1.0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_batch_size() {
+        type M = ethers::providers::Provider<ethers::providers::MockProvider>;
+
+        assert!(check_batch_size::<M>(100, 100).is_ok());
+
+        let err = check_batch_size::<M>(101, 100).unwrap_err();
+        assert!(matches!(
+            err,
+            WorldTreeError::BatchTooLarge {
+                size: 101,
+                max: 100
+            }
+        ));
+        assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_readiness_status() {
+        // The tree is still backfilling
+        assert_eq!(
+            readiness_status(false, 1000, 10, false),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // The tree has synced but fell behind the chain head
+        assert_eq!(
+            readiness_status(true, 11, 10, false),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // The tree has caught up to the chain head
+        assert_eq!(readiness_status(true, 10, 10, false), StatusCode::OK);
+        assert_eq!(readiness_status(true, 0, 10, false), StatusCode::OK);
+
+        // The tree is within the lag but repeatedly failing to sync
+        assert_eq!(
+            readiness_status(true, 0, 10, true),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stalled_sync() -> eyre::Result<()> {
+        use ethers::providers::{MockProvider, Provider};
+        use ethers::types::{Bytes, Log, H160, U256, U64};
+
+        use crate::tree::events::IndexerEvents;
+        use crate::tree::tree_manager::{CanonicalTree, TreeManager};
+
+        type M = Provider<MockProvider>;
+
+        let (provider, mock) = Provider::mocked();
+
+        // Mocked responses are returned in reverse order. The canonical tree manager scans blocks 0 to 10 and finds a
+        // single log, then fails to fetch its transaction once the responses run out.
+        mock.push(vec![Log {
+            topics: vec![H256::zero(); 4],
+            transaction_hash: Some(H256::from_low_u64_be(1)),
+            ..Default::default()
+        }])?;
+        mock.push(U64::from(10))?;
+        mock.push(U256::from(1))?;
+        mock.push(U256::from(1))?;
+        mock.push(Bytes::from(vec![0x60, 0x80]))?;
+        mock.push(U256::from(1))?;
+
+        let canonical_tree_manager = TreeManager::<M, CanonicalTree>::new(
+            H160::from_low_u64_be(1),
+            1000,
+            10,
+            0,
+            Arc::new(provider),
+        )
+        .await?;
+
+        let dir = tempfile::tempdir()?;
+        let world_tree = WorldTree::new(
+            3,
+            canonical_tree_manager,
+            vec![],
+            &dir.path().join("cache"),
+        )?;
+
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let handle = world_tree.canonical_tree_manager.spawn(
+            tx,
+            IndexerEvents::default(),
+            Some(0),
+            3,
+        );
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while world_tree.sync_failures() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        handle.abort();
+
+        // The failed range is retried rather than skipped
+        assert_eq!(world_tree.sync_failures(), 1);
+        assert_eq!(
+            world_tree
+                .canonical_tree_manager
+                .block_scanner
+                .next_block
+                .load(Ordering::SeqCst),
+            0
+        );
+
+        // Persistent failures mark the service unready once the threshold is reached
+        let config = |max_consecutive_failures| StalledSyncConfig {
+            max_consecutive_failures,
+            exit: false,
+        };
+        let failures = world_tree.sync_failures();
+        assert!(sync_stalled(failures, Some(&config(1))));
+        assert!(!sync_stalled(failures, Some(&config(2))));
+        assert_eq!(
+            readiness_status(true, 0, 10, true),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // Failures never affect readiness when the policy is unset
+        assert!(!sync_stalled(u32::MAX, None));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_root_alert() {
+        let max_root_age = Duration::from_secs(60);
+        let start = Instant::now();
+
+        let mut root_age = RootAge::new(Hash::from(1), start);
+
+        // The root has not changed yet, but is still within the threshold
+        let age = root_age.observe(Hash::from(1), start + max_root_age);
+        assert_eq!(check_root_age(age, max_root_age, true), None);
+
+        // Advancing past the threshold without a root change fires the alert, with the cause depending on the indexer
+        let later = start + max_root_age + Duration::from_secs(1);
+        let age = root_age.observe(Hash::from(1), later);
+        assert_eq!(
+            check_root_age(age, max_root_age, true),
+            Some(StaleRoot::SourceIdle)
+        );
+        assert_eq!(
+            check_root_age(age, max_root_age, false),
+            Some(StaleRoot::UpdatesStopped)
+        );
+
+        // A new root resets the age
+        let age = root_age.observe(Hash::from(2), later);
+        assert_eq!(age, Duration::ZERO);
+        assert_eq!(check_root_age(age, max_root_age, false), None);
+    }
+
+    #[test]
+    fn test_canonical_lag_alert() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(10);
+        identity_tree.insert(0, Hash::from(1))?;
+
+        // Append roots without applying them to the canonical tree
+        for idx in 1..=3 {
+            let root = Root {
+                hash: Hash::from(100 + idx),
+                nonce: idx as usize,
+            };
+            let leaf_updates =
+                HashMap::from([(LeafIndex(idx), Hash::from(idx + 1))]);
+
+            identity_tree
+                .append_updates(root, LeafUpdates::Insert(leaf_updates))?;
+            let canonical_lag = identity_tree.canonical_lag();
+            assert_eq!(canonical_lag, idx as usize);
+
+            // The alert only fires once the lag exceeds the threshold
+            assert_eq!(check_canonical_lag(canonical_lag, 2), idx > 2);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_info() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(10);
+        for idx in 0..3 {
+            identity_tree.insert(idx, Hash::from(idx + 1))?;
+        }
+
+        let info = serde_json::to_value(InfoResponse::new(&identity_tree))?;
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "depth": 10,
+                "hash": "poseidon",
+                "leafCount": 3,
+                "canonicalRoot": identity_tree.root(),
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Syncs the canonical tree from the `WorldIDIdentityManager` events on mainnet
+pub const CANONICAL_TREE_TASK: &str = "canonical_tree";
+/// Applies or appends the identity updates received from the canonical tree
+pub const CANONICAL_UPDATES_TASK: &str = "canonical_updates";
+/// Tracks the roots received from the bridged trees, applying pending updates once a root is bridged to all chains
+pub const BRIDGED_UPDATES_TASK: &str = "bridged_updates";
+/// Serves the inclusion proof API
+pub const HTTP_SERVER_TASK: &str = "http_server";
+/// Serves Prometheus metrics
+pub const METRICS_SERVER_TASK: &str = "metrics_server";
+/// Periodically checks the canonical lag
+pub const CANONICAL_LAG_MONITOR_TASK: &str = "canonical_lag_monitor";
+/// Periodically checks for a stalled canonical sync
+pub const STALLED_SYNC_MONITOR_TASK: &str = "stalled_sync_monitor";
+/// Periodically checks how long the latest canonical root has gone unchanged
+pub const STALE_ROOT_MONITOR_TASK: &str = "stale_root_monitor";
+
+/// Name of the task syncing the `BridgedWorldID` events on a chain
+pub fn bridged_tree_task(chain_id: u64) -> String {
+    format!("bridged_tree:{chain_id}")
+}
+
+/// Spawns a future instrumented with a `task` span carrying its name. With the `tokio-console` feature and
+/// `RUSTFLAGS="--cfg tokio_unstable"`, the name is also attached to the tokio task so that it is shown in tokio-console.
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future =
+        future.instrument(tracing::info_span!("task", task.name = name));
+
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("Failed to spawn task")
+    }
+
+    #[cfg(not(all(feature = "tokio-console", tokio_unstable)))]
+    {
+        tokio::spawn(future)
+    }
+}
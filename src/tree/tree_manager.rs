@@ -1,12 +1,16 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
 use ethers::abi::{AbiDecode, RawLog};
 use ethers::contract::{EthCall, EthEvent};
 use ethers::providers::Middleware;
-use ethers::types::{Filter, Log, Selector, ValueOrArray, H160, H256, U256};
+use ethers::types::{
+    Filter, Log, Selector, Transaction, ValueOrArray, H160, H256, U256,
+};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use tokio::sync::mpsc::Sender;
@@ -14,11 +18,13 @@ use tokio::task::JoinHandle;
 
 use super::block_scanner::BlockScanner;
 use super::error::WorldTreeError;
+use super::events::{block_processed_events, IndexerEvent, IndexerEvents};
 use super::identity_tree::{LeafUpdates, Root};
+use super::task::{bridged_tree_task, spawn_named, CANONICAL_TREE_TASK};
 use super::{Hash, LeafIndex};
 use crate::abi::{
-    DeleteIdentitiesCall, RegisterIdentitiesCall, RootAddedFilter,
-    TreeChangedFilter,
+    DeleteIdentitiesCall, IWorldIDIdentityManager, RegisterIdentitiesCall,
+    RootAddedFilter, TreeChangedFilter,
 };
 use crate::error::{ok, Log as _};
 
@@ -30,6 +36,10 @@ pub trait TreeVersion: Default {
     fn spawn<M: Middleware + 'static>(
         tx: Sender<Self::ChannelData>,
         block_scanner: Arc<BlockScanner<M>>,
+        events: IndexerEvents,
+        root_verifier: Option<Arc<RootVerifier<M>>>,
+        next_leaf_index: Option<u32>,
+        tree_depth: usize,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>>;
 
     fn tree_changed_signature() -> H256;
@@ -39,6 +49,8 @@ pub struct TreeManager<M: Middleware + 'static, T: TreeVersion> {
     pub address: H160,
     pub block_scanner: Arc<BlockScanner<M>>,
     pub chain_id: u64,
+    /// Cross checks new roots against the onchain root before they are sent to the tree, only used by the canonical tree
+    pub root_verifier: Option<Arc<RootVerifier<M>>>,
     _tree_version: PhantomData<T>,
 }
 
@@ -50,6 +62,7 @@ where
     pub async fn new(
         address: H160,
         window_size: u64,
+        max_concurrent_log_requests: usize,
         last_synced_block: u64,
         middleware: Arc<M>,
     ) -> Result<Self, WorldTreeError<M>> {
@@ -59,6 +72,19 @@ where
             .map_err(WorldTreeError::MiddlewareError)?
             .as_u64();
 
+        // Ensure that the address points to a deployed contract on this chain
+        let code = middleware
+            .get_code(address, None)
+            .await
+            .map_err(WorldTreeError::MiddlewareError)?;
+
+        if code.is_empty() {
+            return Err(WorldTreeError::ContractCodeNotFound {
+                address,
+                chain_id,
+            });
+        }
+
         let filter = Filter::new()
             .address(address)
             .topic0(ValueOrArray::Value(T::tree_changed_signature()));
@@ -67,6 +93,7 @@ where
             BlockScanner::new(
                 middleware,
                 window_size,
+                max_concurrent_log_requests,
                 last_synced_block,
                 filter,
             )
@@ -78,39 +105,120 @@ where
             address,
             block_scanner,
             chain_id,
+            root_verifier: None,
             _tree_version: PhantomData,
         })
     }
 
+    /// Enables cross checking each new root against `latestRoot()` at the block it was emitted in before it is sent to
+    /// the tree, rejecting roots that do not match
+    pub fn with_root_verification(mut self, enabled: bool) -> Self {
+        self.root_verifier = enabled.then(|| {
+            Arc::new(RootVerifier::new(
+                self.address,
+                self.block_scanner.middleware.clone(),
+            ))
+        });
+        self
+    }
+
+    /// Spawns the tree manager, where `next_leaf_index` is the index the first insertion must continue from and `tree_depth`
+    /// bounds the leaf indices of each tree change, both only used by the canonical tree
     pub fn spawn(
         &self,
         tx: Sender<T::ChannelData>,
+        events: IndexerEvents,
+        next_leaf_index: Option<u32>,
+        tree_depth: usize,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
-        T::spawn(tx, self.block_scanner.clone())
+        T::spawn(
+            tx,
+            self.block_scanner.clone(),
+            events,
+            self.root_verifier.clone(),
+            next_leaf_index,
+            tree_depth,
+        )
+    }
+}
+
+impl<M> TreeManager<M, CanonicalTree>
+where
+    M: Middleware + 'static,
+{
+    /// Reads the depth of the tree from the `WorldIDIdentityManager` contract
+    pub async fn tree_depth(&self) -> Result<usize, WorldTreeError<M>> {
+        let identity_manager = IWorldIDIdentityManager::new(
+            self.address,
+            self.block_scanner.middleware.clone(),
+        );
+
+        let tree_depth = identity_manager.get_tree_depth().call().await?;
+
+        Ok(tree_depth as usize)
+    }
+
+    /// Returns the onchain tree depth, erroring if it conflicts with the configured tree depth
+    pub async fn resolve_tree_depth(
+        &self,
+        configured: Option<usize>,
+    ) -> Result<usize, WorldTreeError<M>> {
+        let onchain = self.tree_depth().await?;
+
+        match configured {
+            Some(configured) if configured != onchain => {
+                Err(WorldTreeError::TreeDepthMismatch {
+                    configured,
+                    onchain,
+                })
+            }
+            _ => Ok(onchain),
+        }
     }
 }
 
 #[derive(Default)]
 pub struct CanonicalTree;
 impl TreeVersion for CanonicalTree {
-    type ChannelData = (Root, LeafUpdates);
+    /// The root, its leaf updates and the hash of the transaction that produced it
+    type ChannelData = (Root, LeafUpdates, Option<H256>);
 
     fn spawn<M: Middleware + 'static>(
         tx: Sender<Self::ChannelData>,
         block_scanner: Arc<BlockScanner<M>>,
+        events: IndexerEvents,
+        root_verifier: Option<Arc<RootVerifier<M>>>,
+        mut next_leaf_index: Option<u32>,
+        tree_depth: usize,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
-        tokio::spawn(async move {
+        spawn_named(CANONICAL_TREE_TASK, async move {
             let chain_id = block_scanner
                 .middleware
                 .get_chainid()
                 .await
                 .map_err(WorldTreeError::MiddlewareError)?
                 .as_u64();
+
+            // The tree is synced to the chain head before the canonical tree manager is spawned
+            let mut caught_up = true;
+
             loop {
-                async {
+                let from_block =
+                    block_scanner.next_block.load(Ordering::SeqCst);
+                let result = async {
                     let logs = block_scanner.next().await?;
 
                     if logs.is_empty() {
+                        if !caught_up {
+                            caught_up = true;
+                            events.emit(IndexerEvent::CaughtUp {
+                                block_number: block_scanner
+                                    .next_block
+                                    .load(Ordering::SeqCst)
+                                    .saturating_sub(1),
+                            });
+                        }
+
                         tokio::time::sleep(Duration::from_secs(
                             BLOCK_SCANNER_SLEEP_TIME,
                         ))
@@ -122,17 +230,105 @@ impl TreeVersion for CanonicalTree {
                     let identity_updates = extract_identity_updates(
                         &logs,
                         block_scanner.middleware.clone(),
+                        tree_depth,
+                    )
+                    .await?;
+
+                    // Rescan the same blocks if an insertion skips leaf indices, since the provider may have dropped a log
+                    let rescan = {
+                        let block_scanner = block_scanner.clone();
+                        move || async move {
+                            block_scanner
+                                .next_block
+                                .store(from_block, Ordering::SeqCst);
+                            let logs = block_scanner
+                                .next()
+                                .await
+                                .map_err(WorldTreeError::MiddlewareError)?;
+
+                            let identity_updates = extract_identity_updates(
+                                &logs,
+                                block_scanner.middleware.clone(),
+                                tree_depth,
+                            )
+                            .await?;
+
+                            Ok((logs, identity_updates))
+                        }
+                    };
+
+                    // The rescanned logs replace the original logs, so that every later step sees the logs the updates were extracted from
+                    let (logs, identity_updates) = fill_leaf_gap(
+                        next_leaf_index,
+                        (logs, identity_updates),
+                        rescan,
                     )
                     .await?;
 
-                    for update in identity_updates {
-                        tracing::info!(?chain_id, new_root = ?update.0.hash, "Root updated");
-                        tx.send(update).await?;
+                    // Reject the batch if a root disagrees with the onchain root
+                    if let Some(root_verifier) = &root_verifier {
+                        root_verifier.verify(&logs, &identity_updates).await?;
+                    }
+
+                    let processed = block_processed_events(
+                        &logs,
+                        &identity_updates,
+                        block_scanner
+                            .next_block
+                            .load(Ordering::SeqCst)
+                            .saturating_sub(1),
+                    );
+
+                    let root_txs = root_transactions(&logs);
+
+                    for ((root, leaf_updates), event) in
+                        identity_updates.into_iter().zip(processed)
+                    {
+                        tracing::info!(?chain_id, new_root = ?root.hash, "Root updated");
+                        next_leaf_index =
+                            advance_leaf_index(next_leaf_index, &leaf_updates);
+
+                        let tx_hash = root_txs.get(&root.hash).copied();
+                        tx.send((root, leaf_updates, tx_hash)).await?;
+                        events.emit(event);
                     }
+                    caught_up = false;
                     ok(())
                 }
-                .await
-                .log();
+                .await;
+
+                match result {
+                    Ok(()) => {
+                        block_scanner
+                            .consecutive_failures
+                            .store(0, Ordering::SeqCst);
+                    }
+                    Err(err) => {
+                        // Retry the same blocks on the next attempt rather than skipping past the logs that failed to process
+                        block_scanner
+                            .next_block
+                            .store(from_block, Ordering::SeqCst);
+
+                        let failures = block_scanner
+                            .consecutive_failures
+                            .fetch_add(1, Ordering::SeqCst)
+                            + 1;
+                        let stuck_block = from_block;
+
+                        tracing::error!(
+                            ?chain_id,
+                            stuck_block,
+                            failures,
+                            "Failed to process canonical tree logs: {err:?}"
+                        );
+
+                        // Back off rather than retrying the same range immediately
+                        tokio::time::sleep(Duration::from_secs(
+                            BLOCK_SCANNER_SLEEP_TIME,
+                        ))
+                        .await;
+                    }
+                }
             }
         })
     }
@@ -149,8 +345,14 @@ impl TreeVersion for BridgedTree {
     fn spawn<M: Middleware + 'static>(
         tx: Sender<Self::ChannelData>,
         block_scanner: Arc<BlockScanner<M>>,
+        _events: IndexerEvents,
+        _root_verifier: Option<Arc<RootVerifier<M>>>,
+        _next_leaf_index: Option<u32>,
+        _tree_depth: usize,
     ) -> JoinHandle<Result<(), WorldTreeError<M>>> {
-        tokio::spawn(async move {
+        let name = bridged_tree_task(block_scanner.chain_id);
+
+        spawn_named(&name, async move {
             let chain_id = block_scanner
                 .middleware
                 .get_chainid()
@@ -192,14 +394,187 @@ impl TreeVersion for BridgedTree {
     }
 }
 
+/// Cross checks roots decoded from the canonical tree events against `latestRoot()` of the `WorldIDIdentityManager`,
+/// so that a faulty or malicious provider cannot feed fabricated tree changes into the tree
+#[derive(Debug)]
+pub struct RootVerifier<M: Middleware + 'static> {
+    identity_manager: IWorldIDIdentityManager<M>,
+}
+
+impl<M> RootVerifier<M>
+where
+    M: Middleware + 'static,
+{
+    pub fn new(address: H160, middleware: Arc<M>) -> Self {
+        Self {
+            identity_manager: IWorldIDIdentityManager::new(address, middleware),
+        }
+    }
+
+    /// Verifies the last root of each block against the onchain root at that block, since earlier roots in the same
+    /// block are overwritten before the block ends. `logs` must be the logs that `identity_updates` were extracted from.
+    ///
+    /// # Errors
+    ///
+    /// Returns `RootVerificationFailed` for the first block whose last root does not match the onchain root
+    pub async fn verify(
+        &self,
+        logs: &[Log],
+        identity_updates: &BTreeMap<Root, LeafUpdates>,
+    ) -> Result<(), WorldTreeError<M>> {
+        let roots = identity_updates
+            .keys()
+            .map(|root| root.hash)
+            .collect::<HashSet<_>>();
+
+        let mut logs = logs
+            .iter()
+            .filter_map(|log| {
+                let block_number = log.block_number?.as_u64();
+                let log_index = log.log_index.unwrap_or_default();
+                let post_root = Hash::from_be_bytes(log.topics.get(3)?.0);
+
+                roots.contains(&post_root).then_some((
+                    block_number,
+                    log_index,
+                    post_root,
+                ))
+            })
+            .collect::<Vec<_>>();
+        logs.sort_unstable_by_key(|(block_number, log_index, _)| {
+            (*block_number, *log_index)
+        });
+
+        // Later logs in a block overwrite earlier ones, leaving the last root of each block
+        let last_roots = logs
+            .into_iter()
+            .map(|(block_number, _, post_root)| (block_number, post_root))
+            .collect::<BTreeMap<_, _>>();
+
+        for (block_number, root) in last_roots {
+            let onchain_root = self
+                .identity_manager
+                .latest_root()
+                .block(block_number)
+                .call()
+                .await?;
+            let onchain_root = Hash::from_limbs(onchain_root.0);
+
+            if onchain_root != root {
+                return Err(WorldTreeError::RootVerificationFailed {
+                    block_number,
+                    root,
+                    onchain_root,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Rescans for identity updates once if an insertion does not continue from `next_leaf_index`, so that leaves are never
+/// appended at the wrong index when a log is dropped by the provider. Returns the logs alongside the updates extracted
+/// from them, which are the rescanned logs if a rescan was needed.
+///
+/// # Errors
+///
+/// Returns `LeafIndexGap` if the rescanned updates still skip leaf indices
+pub async fn fill_leaf_gap<M, F, Fut>(
+    next_leaf_index: Option<u32>,
+    scanned: (Vec<Log>, BTreeMap<Root, LeafUpdates>),
+    rescan: F,
+) -> Result<(Vec<Log>, BTreeMap<Root, LeafUpdates>), WorldTreeError<M>>
+where
+    M: Middleware + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<
+        Output = Result<
+            (Vec<Log>, BTreeMap<Root, LeafUpdates>),
+            WorldTreeError<M>,
+        >,
+    >,
+{
+    let Some((expected, found)) = find_leaf_gap(next_leaf_index, &scanned.1)
+    else {
+        return Ok(scanned);
+    };
+
+    tracing::warn!(expected, found, "Insertion skips leaf indices, rescanning");
+
+    let (logs, identity_updates) = rescan().await?;
+    if let Some((expected, found)) =
+        find_leaf_gap(next_leaf_index, &identity_updates)
+    {
+        return Err(WorldTreeError::LeafIndexGap { expected, found });
+    }
+
+    tracing::info!(expected, found, "Filled leaf index gap");
+
+    Ok((logs, identity_updates))
+}
+
+/// Returns the next expected leaf index and the first leaf index of the first insertion that skips it, if any
+pub fn find_leaf_gap(
+    mut next_leaf_index: Option<u32>,
+    identity_updates: &BTreeMap<Root, LeafUpdates>,
+) -> Option<(u32, u32)> {
+    for updates in identity_updates.values() {
+        if let LeafUpdates::Insert(leaves) = updates {
+            let first = leaves.keys().min().map(|idx| idx.0);
+
+            if let (Some(expected), Some(first)) = (next_leaf_index, first) {
+                if first > expected {
+                    return Some((expected, first));
+                }
+            }
+        }
+
+        next_leaf_index = advance_leaf_index(next_leaf_index, updates);
+    }
+
+    None
+}
+
+/// Returns the leaf index following the last leaf inserted by `updates`, deletions do not move the next leaf index
+fn advance_leaf_index(
+    next_leaf_index: Option<u32>,
+    updates: &LeafUpdates,
+) -> Option<u32> {
+    let LeafUpdates::Insert(leaves) = updates else {
+        return next_leaf_index;
+    };
+
+    match leaves.keys().max() {
+        Some(last) => Some(next_leaf_index.unwrap_or(0).max(last.0 + 1)),
+        None => next_leaf_index,
+    }
+}
+
+/// Maps the post root of each `TreeChanged` log to the hash of the transaction that emitted it
+pub fn root_transactions(logs: &[Log]) -> HashMap<Hash, H256> {
+    logs.iter()
+        .filter_map(|log| {
+            let post_root = Hash::from_be_bytes(log.topics.get(3)?.0);
+
+            Some((post_root, log.transaction_hash?))
+        })
+        .collect()
+}
+
 /// Extract identity updates from logs emitted by the `WorldIdIdentityManager`.
+/// Malformed tree changes, including those with leaf indices past the capacity of a tree of depth `tree_depth`, are rejected individually,
+/// so that they do not prevent the valid updates in the same batch from being applied.
 pub async fn extract_identity_updates<M: Middleware + 'static>(
     logs: &[Log],
     middleware: Arc<M>,
+    tree_depth: usize,
 ) -> Result<BTreeMap<Root, LeafUpdates>, WorldTreeError<M>> {
     let mut tree_updates = BTreeMap::new();
 
     let mut tasks = FuturesUnordered::new();
+    // A transaction can emit several `TreeChanged` logs, so the post roots are keyed by transaction hash and log index
+    let mut post_roots = BTreeMap::new();
 
     // Fetch the transactions for each log concurrently
     for log in logs {
@@ -207,6 +582,19 @@ pub async fn extract_identity_updates<M: Middleware + 'static>(
             .transaction_hash
             .ok_or(WorldTreeError::TransactionHashNotFound)?;
 
+        // `TreeChanged` indexes the pre root, kind and post root alongside the event signature
+        if log.topics.len() != 4 {
+            reject_tree_change::<M>(
+                tx_hash,
+                format!("expected 4 topics, found {}", log.topics.len()),
+            );
+            continue;
+        }
+        post_roots.insert(
+            (tx_hash, log.log_index.unwrap_or_default()),
+            Hash::from_be_bytes(log.topics[3].0),
+        );
+
         tracing::debug!(?tx_hash, "Getting transaction");
         tasks.push(middleware.get_transaction(tx_hash));
     }
@@ -226,64 +614,159 @@ pub async fn extract_identity_updates<M: Middleware + 'static>(
 
     // Process each transaction, constructing identity updates for each root
     for (nonce, transaction) in sorted_transactions {
-        let calldata = &transaction.input;
+        // The rejection has already been logged and counted, only the offending transaction is skipped
+        let Ok((root, leaf_updates)) = decode_tree_change::<M>(
+            &transaction,
+            nonce,
+            &post_roots,
+            tree_depth,
+        ) else {
+            continue;
+        };
+
+        tracing::debug!(?root, "Canonical tree updated");
+        tree_updates.insert(root, leaf_updates);
+    }
 
-        let mut identity_updates: HashMap<LeafIndex, Hash> = HashMap::new();
+    Ok(tree_updates)
+}
+
+/// Decodes the root and leaf updates from the calldata of a `registerIdentities` or `deleteIdentities` transaction
+fn decode_tree_change<M: Middleware + 'static>(
+    transaction: &Transaction,
+    nonce: U256,
+    post_roots: &BTreeMap<(H256, U256), Hash>,
+    tree_depth: usize,
+) -> Result<(Root, LeafUpdates), WorldTreeError<M>> {
+    let tx_hash = transaction.hash;
+    let capacity = 1_u64 << tree_depth;
+    let calldata = &transaction.input;
+
+    let mut identity_updates: HashMap<LeafIndex, Hash> = HashMap::new();
 
-        let function_selector = Selector::try_from(&calldata[0..4])
-            .map_err(|_| WorldTreeError::MissingFunctionSelector)?;
+    let function_selector = calldata
+        .get(0..4)
+        .and_then(|selector| Selector::try_from(selector).ok())
+        .ok_or_else(|| {
+            reject_tree_change(
+                tx_hash,
+                "calldata is too short to contain a function selector"
+                    .to_string(),
+            )
+        })?;
 
-        if function_selector == RegisterIdentitiesCall::selector() {
-            tracing::debug!("Decoding registerIdentities calldata");
+    if function_selector == RegisterIdentitiesCall::selector() {
+        tracing::debug!("Decoding registerIdentities calldata");
 
-            let register_identities_call =
-                RegisterIdentitiesCall::decode(calldata.as_ref())?;
+        let register_identities_call =
+            RegisterIdentitiesCall::decode(calldata.as_ref())
+                .map_err(|err| reject_tree_change(tx_hash, format!("{err}")))?;
 
-            let start_index = register_identities_call.start_index;
-            let identities = register_identities_call.identity_commitments;
+        let start_index = register_identities_call.start_index;
+        let identities = register_identities_call.identity_commitments;
 
-            for (i, identity) in identities
-                .into_iter()
-                .take_while(|x| *x != U256::zero())
-                .enumerate()
-            {
-                identity_updates.insert(
-                    (start_index + i as u32).into(),
-                    Hash::from_limbs(identity.0),
-                );
+        for (i, identity) in identities
+            .into_iter()
+            .take_while(|x| *x != U256::zero())
+            .enumerate()
+        {
+            let leaf_idx = start_index as u64 + i as u64;
+            if leaf_idx >= capacity {
+                return Err(reject_tree_change(
+                    tx_hash,
+                    format!("leaf index {leaf_idx} exceeds the tree capacity {capacity}"),
+                ));
             }
 
-            let root = Root {
-                hash: Hash::from_limbs(register_identities_call.post_root.0),
-                nonce: nonce.as_u64() as usize,
-            };
-            tracing::debug!(?root, "Canonical tree updated");
-            tree_updates.insert(root, LeafUpdates::Insert(identity_updates));
-        } else if function_selector == DeleteIdentitiesCall::selector() {
-            tracing::debug!("Decoding deleteIdentities calldata");
+            identity_updates
+                .insert((leaf_idx as u32).into(), Hash::from_limbs(identity.0));
+        }
+
+        let root = Root {
+            hash: Hash::from_limbs(register_identities_call.post_root.0),
+            nonce: nonce.as_u64() as usize,
+        };
+        check_post_root(post_roots, tx_hash, &root)?;
 
-            let delete_identities_call =
-                DeleteIdentitiesCall::decode(calldata.as_ref())?;
+        Ok((root, LeafUpdates::Insert(identity_updates)))
+    } else if function_selector == DeleteIdentitiesCall::selector() {
+        tracing::debug!("Decoding deleteIdentities calldata");
 
-            let indices = unpack_indices(
-                delete_identities_call.packed_deletion_indices.as_ref(),
-            );
+        let delete_identities_call =
+            DeleteIdentitiesCall::decode(calldata.as_ref())
+                .map_err(|err| reject_tree_change(tx_hash, format!("{err}")))?;
 
-            // Note that we use 2**30 as padding for deletions in order to fill the deletion batch size
-            for i in indices.into_iter().take_while(|x| *x < 2_u32.pow(30)) {
-                identity_updates.insert(i.into(), Hash::ZERO);
+        let indices = unpack_indices(
+            delete_identities_call.packed_deletion_indices.as_ref(),
+        );
+
+        // Note that we use 2**30 as padding for deletions in order to fill the deletion batch size
+        for i in indices.into_iter().take_while(|x| *x < 2_u32.pow(30)) {
+            if i as u64 >= capacity {
+                return Err(reject_tree_change(
+                    tx_hash,
+                    format!(
+                        "leaf index {i} exceeds the tree capacity {capacity}"
+                    ),
+                ));
             }
 
-            let root = Root {
-                hash: Hash::from_limbs(delete_identities_call.post_root.0),
-                nonce: nonce.as_u64() as usize,
-            };
-            tracing::debug!(?root, "Canonical tree updated");
-            tree_updates.insert(root, LeafUpdates::Delete(identity_updates));
+            identity_updates.insert(i.into(), Hash::ZERO);
         }
+
+        let root = Root {
+            hash: Hash::from_limbs(delete_identities_call.post_root.0),
+            nonce: nonce.as_u64() as usize,
+        };
+        check_post_root(post_roots, tx_hash, &root)?;
+
+        Ok((root, LeafUpdates::Delete(identity_updates)))
+    } else {
+        Err(reject_tree_change(
+            tx_hash,
+            format!("unknown function selector {function_selector:?}"),
+        ))
     }
+}
 
-    Ok(tree_updates)
+/// Verifies that the post root decoded from the calldata matches the post root emitted in one of the `TreeChanged` logs of the transaction
+fn check_post_root<M: Middleware + 'static>(
+    post_roots: &BTreeMap<(H256, U256), Hash>,
+    tx_hash: H256,
+    root: &Root,
+) -> Result<(), WorldTreeError<M>> {
+    let log_post_roots = post_roots
+        .range((tx_hash, U256::zero())..=(tx_hash, U256::MAX))
+        .map(|(_, post_root)| *post_root)
+        .collect::<Vec<_>>();
+
+    if log_post_roots.is_empty() {
+        Err(reject_tree_change(
+            tx_hash,
+            "transaction does not match any log".to_string(),
+        ))
+    } else if log_post_roots.contains(&root.hash) {
+        Ok(())
+    } else {
+        Err(reject_tree_change(
+            tx_hash,
+            format!(
+                "calldata post root {:?} does not match log post roots {:?}",
+                root.hash, log_post_roots
+            ),
+        ))
+    }
+}
+
+/// Logs and counts a rejected tree change, which usually indicates that the indexer is pointed at the wrong contract
+fn reject_tree_change<M: Middleware + 'static>(
+    tx_hash: H256,
+    reason: String,
+) -> WorldTreeError<M> {
+    tracing::warn!(?tx_hash, reason, "Rejecting malformed tree change");
+    ::metrics::counter!("world_tree.rejected_tree_changes").increment(1);
+
+    WorldTreeError::MalformedTreeChange { tx_hash, reason }
 }
 
 /// Unpacks a contiguous byte array into a vector of 32-bit indices.
@@ -322,7 +805,369 @@ pub fn pack_indices(indices: &[u32]) -> Vec<u8> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    use ethers::abi::AbiEncode;
+    use ethers::providers::{MockProvider, Provider};
+    use ethers::types::Bytes;
+    use eyre::ContextCompat;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
     use super::*;
+    use crate::tree::identity_tree::{IdentityTree, ROOT_TX_RETENTION};
+
+    const TREE_DEPTH: usize = 10;
+
+    /// Returns a mocked provider that responds to the calls made in `TreeManager::new`
+    fn mocked_provider(code: Bytes) -> Arc<Provider<MockProvider>> {
+        let (provider, mock) = Provider::mocked();
+
+        // Mocked responses are returned in reverse order
+        mock.push(U256::from(1)).unwrap();
+        mock.push(code).unwrap();
+        mock.push(U256::from(1)).unwrap();
+
+        Arc::new(provider)
+    }
+
+    #[tokio::test]
+    async fn test_tree_manager_requires_contract_code() {
+        let address = H160::from_low_u64_be(1);
+
+        let deployed = TreeManager::<_, CanonicalTree>::new(
+            address,
+            1000,
+            10,
+            0,
+            mocked_provider(Bytes::from(vec![0x60, 0x80])),
+        )
+        .await;
+        assert!(deployed.is_ok());
+
+        let not_deployed = TreeManager::<_, CanonicalTree>::new(
+            address,
+            1000,
+            10,
+            0,
+            mocked_provider(Bytes::new()),
+        )
+        .await;
+
+        match not_deployed {
+            Err(WorldTreeError::ContractCodeNotFound {
+                address: err_address,
+                chain_id,
+            }) => {
+                assert_eq!(err_address, address);
+                assert_eq!(chain_id, 1);
+            }
+            Err(e) => panic!("Unexpected error: {e:?}"),
+            Ok(_) => panic!("Expected an error for an address without code"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_tree_depth() -> eyre::Result<()> {
+        let (provider, mock) = Provider::mocked();
+
+        // Mocked responses are returned in reverse order, so the calls made after `TreeManager::new` are pushed first
+        for _ in 0..3 {
+            mock.push(Bytes::from(ethers::abi::encode(&[
+                ethers::abi::Token::Uint(U256::from(20)),
+            ])))?;
+        }
+        mock.push(U256::from(1))?;
+        mock.push(Bytes::from(vec![0x60, 0x80]))?;
+        mock.push(U256::from(1))?;
+
+        let tree_manager = TreeManager::<_, CanonicalTree>::new(
+            H160::from_low_u64_be(1),
+            1000,
+            10,
+            0,
+            Arc::new(provider),
+        )
+        .await?;
+
+        assert_eq!(tree_manager.resolve_tree_depth(None).await?, 20);
+        assert_eq!(tree_manager.resolve_tree_depth(Some(20)).await?, 20);
+
+        match tree_manager.resolve_tree_depth(Some(30)).await {
+            Err(WorldTreeError::TreeDepthMismatch {
+                configured,
+                onchain,
+            }) => {
+                assert_eq!(configured, 30);
+                assert_eq!(onchain, 20);
+            }
+            Err(e) => panic!("Unexpected error: {e:?}"),
+            Ok(_) => panic!("Expected a tree depth mismatch"),
+        }
+
+        Ok(())
+    }
+
+    /// Returns a `TreeChanged` log emitted by `tx_hash` with the given post root
+    fn tree_changed_log(tx_hash: H256, post_root: U256) -> Log {
+        let mut post_root_topic = [0u8; 32];
+        post_root.to_big_endian(&mut post_root_topic);
+
+        Log {
+            topics: vec![
+                TreeChangedFilter::signature(),
+                H256::zero(),
+                H256::zero(),
+                H256::from(post_root_topic),
+            ],
+            transaction_hash: Some(tx_hash),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a `registerIdentities` transaction inserting `identities` at index 0
+    fn register_identities_tx(
+        tx_hash: H256,
+        identities: Vec<U256>,
+        post_root: U256,
+    ) -> Transaction {
+        let call = RegisterIdentitiesCall {
+            insertion_proof: [U256::zero(); 8],
+            pre_root: U256::zero(),
+            start_index: 0,
+            identity_commitments: identities,
+            post_root,
+        };
+
+        Transaction {
+            hash: tx_hash,
+            nonce: U256::from(1),
+            input: call.encode().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_identity_updates() -> eyre::Result<()> {
+        let tx_hash = H256::from_low_u64_be(1);
+        let post_root = U256::from(42);
+
+        let (provider, mock) = Provider::mocked();
+        mock.push(register_identities_tx(
+            tx_hash,
+            vec![U256::from(1), U256::from(2)],
+            post_root,
+        ))?;
+
+        let tree_updates = extract_identity_updates(
+            &[tree_changed_log(tx_hash, post_root)],
+            Arc::new(provider),
+            TREE_DEPTH,
+        )
+        .await?;
+
+        let (root, updates) = tree_updates.first_key_value().unwrap();
+        assert_eq!(root.hash, Hash::from(42));
+        match updates {
+            LeafUpdates::Insert(leaves) => {
+                assert_eq!(leaves.len(), 2);
+                assert_eq!(leaves[&LeafIndex(1)], Hash::from(2));
+            }
+            LeafUpdates::Delete(_) => panic!("Expected an insertion"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_malformed_tree_change() -> eyre::Result<()> {
+        let tx_hash = H256::from_low_u64_be(1);
+
+        // A log missing the post root topic is rejected before any transaction is fetched
+        let mut log = tree_changed_log(tx_hash, U256::from(42));
+        log.topics.pop();
+
+        let (provider, _mock) = Provider::mocked();
+        let tree_updates =
+            extract_identity_updates(&[log], Arc::new(provider), TREE_DEPTH)
+                .await?;
+        assert!(tree_updates.is_empty());
+
+        // A transaction whose calldata disagrees with the log is rejected
+        let (provider, mock) = Provider::mocked();
+        mock.push(register_identities_tx(
+            tx_hash,
+            vec![U256::from(1)],
+            U256::from(43),
+        ))?;
+
+        let tree_updates = extract_identity_updates(
+            &[tree_changed_log(tx_hash, U256::from(42))],
+            Arc::new(provider),
+            TREE_DEPTH,
+        )
+        .await?;
+        assert!(tree_updates.is_empty());
+
+        // Calldata too short to contain a selector is rejected rather than panicking
+        let (provider, mock) = Provider::mocked();
+        mock.push(Transaction {
+            hash: tx_hash,
+            input: Bytes::from(vec![0x01, 0x02]),
+            ..Default::default()
+        })?;
+
+        let tree_updates = extract_identity_updates(
+            &[tree_changed_log(tx_hash, U256::from(42))],
+            Arc::new(provider),
+            TREE_DEPTH,
+        )
+        .await?;
+        assert!(tree_updates.is_empty());
+
+        // Only the offending transaction is rejected, the valid updates in the same batch are kept
+        let malformed_tx_hash = H256::from_low_u64_be(2);
+        let (provider, mock) = Provider::mocked();
+        mock.push(Transaction {
+            nonce: U256::from(2),
+            ..register_identities_tx(
+                malformed_tx_hash,
+                vec![U256::from(2)],
+                U256::from(44),
+            )
+        })?;
+        mock.push(register_identities_tx(
+            tx_hash,
+            vec![U256::from(1)],
+            U256::from(42),
+        ))?;
+
+        let tree_updates = extract_identity_updates(
+            &[
+                tree_changed_log(tx_hash, U256::from(42)),
+                tree_changed_log(malformed_tx_hash, U256::from(43)),
+            ],
+            Arc::new(provider),
+            TREE_DEPTH,
+        )
+        .await?;
+        assert_eq!(
+            tree_updates
+                .keys()
+                .map(|root| root.hash)
+                .collect::<Vec<_>>(),
+            vec![Hash::from(42)]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_out_of_range_tree_change() -> eyre::Result<()> {
+        let capacity = 1_u32 << TREE_DEPTH;
+        let insert_tx_hash = H256::from_low_u64_be(1);
+        let delete_tx_hash = H256::from_low_u64_be(2);
+        let valid_tx_hash = H256::from_low_u64_be(3);
+
+        // The second identity of the insertion lands past the last leaf of the tree
+        let insert_call = RegisterIdentitiesCall {
+            start_index: capacity - 1,
+            ..RegisterIdentitiesCall::decode(
+                register_identities_tx(
+                    insert_tx_hash,
+                    vec![U256::from(1), U256::from(2)],
+                    U256::from(42),
+                )
+                .input,
+            )?
+        };
+        let delete_call = DeleteIdentitiesCall {
+            deletion_proof: [U256::zero(); 8],
+            packed_deletion_indices: capacity.to_be_bytes().to_vec().into(),
+            pre_root: U256::zero(),
+            post_root: U256::from(43),
+        };
+
+        // Mocked responses are returned in reverse order
+        let (provider, mock) = Provider::mocked();
+        mock.push(Transaction {
+            nonce: U256::from(3),
+            ..register_identities_tx(
+                valid_tx_hash,
+                vec![U256::from(3)],
+                U256::from(44),
+            )
+        })?;
+        mock.push(Transaction {
+            hash: delete_tx_hash,
+            nonce: U256::from(2),
+            input: delete_call.encode().into(),
+            ..Default::default()
+        })?;
+        mock.push(Transaction {
+            hash: insert_tx_hash,
+            nonce: U256::from(1),
+            input: insert_call.encode().into(),
+            ..Default::default()
+        })?;
+
+        // Only the out of range tree changes are rejected, rather than failing the batch
+        let tree_updates = extract_identity_updates(
+            &[
+                tree_changed_log(insert_tx_hash, U256::from(42)),
+                tree_changed_log(delete_tx_hash, U256::from(43)),
+                tree_changed_log(valid_tx_hash, U256::from(44)),
+            ],
+            Arc::new(provider),
+            TREE_DEPTH,
+        )
+        .await?;
+        assert_eq!(
+            tree_updates
+                .keys()
+                .map(|root| root.hash)
+                .collect::<Vec<_>>(),
+            vec![Hash::from(44)]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_logs_per_transaction() -> eyre::Result<()> {
+        let tx_hash = H256::from_low_u64_be(1);
+
+        // The post root of the calldata is emitted by the first of two logs in the same transaction
+        let logs = [(0_u64, 42_u64), (1, 41)]
+            .map(|(log_index, post_root)| Log {
+                log_index: Some(log_index.into()),
+                ..tree_changed_log(tx_hash, U256::from(post_root))
+            })
+            .to_vec();
+
+        let (provider, mock) = Provider::mocked();
+        for _ in 0..logs.len() {
+            mock.push(register_identities_tx(
+                tx_hash,
+                vec![U256::from(1)],
+                U256::from(42),
+            ))?;
+        }
+
+        let tree_updates =
+            extract_identity_updates(&logs, Arc::new(provider), TREE_DEPTH)
+                .await?;
+        assert_eq!(
+            tree_updates
+                .keys()
+                .map(|root| root.hash)
+                .collect::<Vec<_>>(),
+            vec![Hash::from(42)]
+        );
+
+        Ok(())
+    }
 
     #[test]
     fn test_pack_indices() {
@@ -336,4 +1181,311 @@ mod tests {
 
         assert_eq!(unpacked, indices);
     }
+
+    fn insertion(nonce: usize, leaf_indices: &[u32]) -> (Root, LeafUpdates) {
+        let root = Root {
+            hash: Hash::from(100 + nonce as u64),
+            nonce,
+        };
+        let leaves = leaf_indices
+            .iter()
+            .map(|idx| (LeafIndex(*idx), Hash::from(*idx + 1)))
+            .collect();
+
+        (root, LeafUpdates::Insert(leaves))
+    }
+
+    #[test]
+    fn test_root_by_tx() -> eyre::Result<()> {
+        let mut identity_tree = IdentityTree::new(4);
+
+        let tx_hashes = [H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+        let updates = [insertion(1, &[0]), insertion(2, &[1])];
+        let logs = tx_hashes
+            .iter()
+            .zip(updates.iter())
+            .map(|(tx_hash, (root, _))| {
+                let post_root =
+                    U256::from_big_endian(&root.hash.to_be_bytes::<32>());
+                tree_changed_log(*tx_hash, post_root)
+            })
+            .collect::<Vec<_>>();
+
+        let root_txs = root_transactions(&logs);
+        for (root, leaf_updates) in updates {
+            identity_tree.append_updates(root, leaf_updates)?;
+            identity_tree.record_root_tx(root_txs[&root.hash], root);
+        }
+
+        let first_root = identity_tree
+            .root_by_tx(tx_hashes[0])
+            .context("Missing root")?;
+        assert_eq!(first_root.nonce, 1);
+        assert_eq!(
+            identity_tree
+                .root_by_tx(tx_hashes[1])
+                .map(|root| root.nonce),
+            Some(2)
+        );
+        assert_eq!(identity_tree.root_by_tx(H256::zero()), None);
+
+        // Roots remain resolvable once they are applied to the canonical tree
+        identity_tree.apply_updates_to_root(&first_root);
+        assert_eq!(identity_tree.root_by_tx(tx_hashes[0]), Some(first_root));
+        assert!(identity_tree.root_by_tx(tx_hashes[1]).is_some());
+
+        // Only the most recent transactions are retained
+        for idx in 0..ROOT_TX_RETENTION as u64 {
+            identity_tree
+                .record_root_tx(H256::from_low_u64_be(idx + 3), first_root);
+        }
+        assert_eq!(identity_tree.root_by_tx(tx_hashes[0]), None);
+        assert_eq!(identity_tree.root_by_tx(tx_hashes[1]), None);
+        assert_eq!(identity_tree.root_tx_order.len(), ROOT_TX_RETENTION);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_root_verification() -> eyre::Result<()> {
+        let roots =
+            [insertion(1, &[0]), insertion(2, &[1]), insertion(3, &[2])]
+                .map(|(root, _)| root);
+        let identity_updates = || {
+            BTreeMap::from([
+                insertion(1, &[0]),
+                insertion(2, &[1]),
+                insertion(3, &[2]),
+            ])
+        };
+
+        // The first two roots are emitted in block 10 and the third in block 11
+        let logs = [(10_u64, 0_u64), (10, 1), (11, 0)]
+            .into_iter()
+            .zip(roots)
+            .map(|((block_number, log_index), root)| {
+                let post_root =
+                    U256::from_big_endian(&root.hash.to_be_bytes::<32>());
+
+                Log {
+                    block_number: Some(block_number.into()),
+                    log_index: Some(log_index.into()),
+                    ..tree_changed_log(H256::zero(), post_root)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let verifier = |onchain_roots: &[Hash]| -> eyre::Result<_> {
+            let (provider, mock) = Provider::mocked();
+
+            // Mocked responses are returned in reverse order
+            for onchain_root in onchain_roots.iter().rev() {
+                mock.push(Bytes::from(
+                    onchain_root.to_be_bytes::<32>().to_vec(),
+                ))?;
+            }
+
+            Ok(RootVerifier::new(H160::zero(), Arc::new(provider)))
+        };
+
+        // Only the last root of each block is compared against the onchain root
+        verifier(&[roots[1].hash, roots[2].hash])?
+            .verify(&logs, &identity_updates())
+            .await?;
+
+        let fake_root = Hash::from(1);
+        let result = verifier(&[roots[1].hash, fake_root])?
+            .verify(&logs, &identity_updates())
+            .await;
+
+        match result {
+            Err(WorldTreeError::RootVerificationFailed {
+                block_number,
+                root,
+                onchain_root,
+            }) => {
+                assert_eq!(block_number, 11);
+                assert_eq!(root, roots[2].hash);
+                assert_eq!(onchain_root, fake_root);
+            }
+            Err(e) => panic!("Unexpected error: {e:?}"),
+            Ok(_) => panic!("Expected the root to be rejected"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fill_leaf_gap() -> eyre::Result<()> {
+        type M = Provider<MockProvider>;
+
+        let complete = || {
+            BTreeMap::from([
+                insertion(1, &[0, 1]),
+                insertion(2, &[2, 3]),
+                insertion(3, &[4]),
+            ])
+        };
+
+        // The insertion at index 4 arrives while the insertion at indices 2 and 3 is missing
+        let dropped = || {
+            let mut identity_updates = complete();
+            identity_updates.remove(&insertion(2, &[]).0);
+            identity_updates
+        };
+        assert_eq!(find_leaf_gap(None, &dropped()), Some((2, 4)));
+
+        // Each scan is tagged with a log so that the returned logs show which scan the updates came from
+        let scan_log = |block_number: u64| Log {
+            block_number: Some(block_number.into()),
+            ..Default::default()
+        };
+
+        let rescans = AtomicUsize::new(0);
+        let (logs, filled) = fill_leaf_gap::<M, _, _>(
+            None,
+            (vec![scan_log(1)], dropped()),
+            || async {
+                rescans.fetch_add(1, Ordering::SeqCst);
+                Ok((vec![scan_log(2)], complete()))
+            },
+        )
+        .await?;
+        assert_eq!(rescans.load(Ordering::SeqCst), 1);
+
+        // The rescanned logs are returned so that the rescanned roots are verified against them
+        assert_eq!(logs, vec![scan_log(2)]);
+
+        // The missing leaves are applied before the insertion that skipped them
+        let leaf_indices = filled
+            .values()
+            .flat_map(|updates| match updates {
+                LeafUpdates::Insert(leaves) => {
+                    let mut indices =
+                        leaves.keys().map(|idx| idx.0).collect::<Vec<_>>();
+                    indices.sort();
+                    indices
+                }
+                LeafUpdates::Delete(_) => vec![],
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(leaf_indices, vec![0, 1, 2, 3, 4]);
+
+        // Contiguous insertions are not rescanned, including across batches
+        let next_batch = BTreeMap::from([insertion(4, &[5])]);
+        assert_eq!(find_leaf_gap(Some(5), &next_batch), None);
+        assert_eq!(find_leaf_gap(Some(4), &next_batch), Some((4, 5)));
+        let (logs, _) = fill_leaf_gap::<M, _, _>(
+            Some(5),
+            (vec![scan_log(1)], next_batch),
+            || async { Err(WorldTreeError::LeafChannelClosed) },
+        )
+        .await?;
+        assert_eq!(logs, vec![scan_log(1)]);
+
+        // The gap is reported if the rescan does not recover the missing leaves
+        let result = fill_leaf_gap::<M, _, _>(
+            None,
+            (vec![scan_log(1)], dropped()),
+            || async { Ok((vec![scan_log(2)], dropped())) },
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(WorldTreeError::LeafIndexGap {
+                expected: 2,
+                found: 4
+            })
+        ));
+
+        Ok(())
+    }
+
+    /// Records the `task.name` of every `task` span created while the layer is active
+    #[derive(Clone, Default)]
+    struct TaskNameRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> Layer<S> for TaskNameRecorder {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: Context<'_, S>,
+        ) {
+            if attrs.metadata().name() == "task" {
+                attrs.record(&mut TaskNameVisitor(&mut self.0.lock().unwrap()));
+            }
+        }
+    }
+
+    struct TaskNameVisitor<'a>(&'a mut Vec<String>);
+
+    impl tracing::field::Visit for TaskNameVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            if field.name() == "task.name" {
+                self.0.push(value.to_string());
+            }
+        }
+
+        fn record_debug(
+            &mut self,
+            _field: &tracing::field::Field,
+            _value: &dyn std::fmt::Debug,
+        ) {
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn_task_names() -> eyre::Result<()> {
+        let recorder = TaskNameRecorder::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(recorder.clone()),
+        );
+
+        let canonical_tree_manager = TreeManager::<_, CanonicalTree>::new(
+            H160::from_low_u64_be(1),
+            1000,
+            10,
+            0,
+            mocked_provider(Bytes::from(vec![0x60, 0x80])),
+        )
+        .await?;
+        let bridged_tree_manager = TreeManager::<_, BridgedTree>::new(
+            H160::from_low_u64_be(2),
+            1000,
+            10,
+            0,
+            mocked_provider(Bytes::from(vec![0x60, 0x80])),
+        )
+        .await?;
+
+        let (canonical_tx, _canonical_rx) = tokio::sync::mpsc::channel(1);
+        let (bridged_tx, _bridged_rx) = tokio::sync::mpsc::channel(1);
+        let handles = [
+            canonical_tree_manager.spawn(
+                canonical_tx,
+                IndexerEvents::default(),
+                Some(0),
+                TREE_DEPTH,
+            ),
+            bridged_tree_manager.spawn(
+                bridged_tx,
+                IndexerEvents::default(),
+                None,
+                TREE_DEPTH,
+            ),
+        ];
+
+        // The tasks fail once the mocked responses run out, only their names are of interest
+        for handle in handles {
+            handle.abort();
+        }
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![CANONICAL_TREE_TASK.to_string(), bridged_tree_task(1)]
+        );
+
+        Ok(())
+    }
 }
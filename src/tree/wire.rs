@@ -0,0 +1,183 @@
+use super::error::DeltaDecodeError;
+use super::identity_tree::{Root, StorageUpdates};
+use super::{Hash, NodeIndex};
+
+/// Version of the delta encoding, bumped whenever the layout changes
+pub const DELTA_FORMAT_VERSION: u8 = 1;
+
+/// Version tag, root hash, root nonce and number of nodes
+const HEADER_LEN: usize = 1 + 32 + 8 + 4;
+/// Node index followed by the node hash
+const NODE_LEN: usize = 4 + 32;
+
+/// Encodes a root and its storage updates as a version tag, the big endian root hash, the little endian nonce and
+/// node count, followed by each node index and hash sorted by node index
+pub fn encode_delta(root: &Root, updates: &StorageUpdates) -> Vec<u8> {
+    let mut nodes = updates.iter().collect::<Vec<_>>();
+    nodes.sort_unstable_by_key(|(node_idx, _)| node_idx.0);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + nodes.len() * NODE_LEN);
+    bytes.push(DELTA_FORMAT_VERSION);
+    bytes.extend_from_slice(&root.hash.to_be_bytes::<32>());
+    bytes.extend_from_slice(&(root.nonce as u64).to_le_bytes());
+    bytes.extend_from_slice(&(nodes.len() as u32).to_le_bytes());
+
+    for (node_idx, hash) in nodes {
+        bytes.extend_from_slice(&node_idx.0.to_le_bytes());
+        bytes.extend_from_slice(&hash.to_be_bytes::<32>());
+    }
+
+    bytes
+}
+
+/// Decodes a delta produced by `encode_delta`, rejecting unknown versions, payloads whose length does not match the
+/// encoded node count and duplicate node indices
+pub fn decode_delta(
+    bytes: &[u8],
+) -> Result<(Root, StorageUpdates), DeltaDecodeError> {
+    let version = *bytes.first().ok_or(DeltaDecodeError::LengthMismatch {
+        expected: HEADER_LEN,
+        found: 0,
+    })?;
+
+    if version != DELTA_FORMAT_VERSION {
+        return Err(DeltaDecodeError::UnsupportedVersion {
+            expected: DELTA_FORMAT_VERSION,
+            found: version,
+        });
+    }
+
+    if bytes.len() < HEADER_LEN {
+        return Err(DeltaDecodeError::LengthMismatch {
+            expected: HEADER_LEN,
+            found: bytes.len(),
+        });
+    }
+
+    let hash = Hash::from_be_bytes::<32>(bytes[1..33].try_into().unwrap());
+    let nonce = u64::from_le_bytes(bytes[33..41].try_into().unwrap());
+    let num_nodes = u32::from_le_bytes(bytes[41..45].try_into().unwrap());
+
+    let expected = HEADER_LEN + num_nodes as usize * NODE_LEN;
+    if bytes.len() != expected {
+        return Err(DeltaDecodeError::LengthMismatch {
+            expected,
+            found: bytes.len(),
+        });
+    }
+
+    let mut updates = StorageUpdates::with_capacity(num_nodes as usize);
+    for node in bytes[HEADER_LEN..].chunks_exact(NODE_LEN) {
+        let node_idx = u32::from_le_bytes(node[..4].try_into().unwrap());
+        let hash = Hash::from_be_bytes::<32>(node[4..].try_into().unwrap());
+
+        if updates.insert(NodeIndex(node_idx), hash).is_some() {
+            return Err(DeltaDecodeError::DuplicateNode { node_idx });
+        }
+    }
+
+    let root = Root {
+        hash,
+        nonce: nonce as usize,
+    };
+
+    Ok((root, updates))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::tree::identity_tree::{IdentityTree, LeafUpdates};
+    use crate::tree::LeafIndex;
+
+    fn delta() -> eyre::Result<(Root, StorageUpdates)> {
+        let mut identity_tree = IdentityTree::new(3);
+        identity_tree.insert(0, Hash::from(1))?;
+
+        let root = Root {
+            hash: Hash::from(100),
+            nonce: 1,
+        };
+        identity_tree.append_updates(
+            root,
+            LeafUpdates::Insert(HashMap::from([
+                (LeafIndex(1), Hash::from(2)),
+                (LeafIndex(2), Hash::from(3)),
+            ])),
+        )?;
+
+        let updates = identity_tree.tree_updates[&root].clone();
+
+        Ok((root, updates))
+    }
+
+    #[test]
+    fn test_delta_round_trip() -> eyre::Result<()> {
+        let (root, updates) = delta()?;
+
+        let bytes = encode_delta(&root, &updates);
+        assert_eq!(bytes.len(), HEADER_LEN + updates.len() * NODE_LEN);
+
+        let (decoded_root, decoded_updates) = decode_delta(&bytes)?;
+        assert_eq!(decoded_root, root);
+        assert_eq!(decoded_updates, updates);
+
+        // The encoding does not depend on the iteration order of the map
+        assert_eq!(encode_delta(&decoded_root, &decoded_updates), bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_delta_rejects_malformed() -> eyre::Result<()> {
+        let (root, updates) = delta()?;
+        let bytes = encode_delta(&root, &updates);
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = DELTA_FORMAT_VERSION + 1;
+        assert!(matches!(
+            decode_delta(&wrong_version),
+            Err(DeltaDecodeError::UnsupportedVersion { found, .. })
+                if found == DELTA_FORMAT_VERSION + 1
+        ));
+
+        assert!(matches!(
+            decode_delta(&[]),
+            Err(DeltaDecodeError::LengthMismatch { found: 0, .. })
+        ));
+        assert!(matches!(
+            decode_delta(&bytes[..HEADER_LEN - 1]),
+            Err(DeltaDecodeError::LengthMismatch { .. })
+        ));
+
+        // A truncated node or trailing bytes do not match the encoded node count
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            decode_delta(truncated),
+            Err(DeltaDecodeError::LengthMismatch { expected, found })
+                if expected == bytes.len() && found == bytes.len() - 1
+        ));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            decode_delta(&trailing),
+            Err(DeltaDecodeError::LengthMismatch { .. })
+        ));
+
+        // Repeat the first node in place of the second
+        let mut duplicate = bytes.clone();
+        duplicate.copy_within(
+            HEADER_LEN..HEADER_LEN + NODE_LEN,
+            HEADER_LEN + NODE_LEN,
+        );
+        assert!(matches!(
+            decode_delta(&duplicate),
+            Err(DeltaDecodeError::DuplicateNode { .. })
+        ));
+
+        Ok(())
+    }
+}